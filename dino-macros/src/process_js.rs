@@ -27,7 +27,13 @@ pub(crate) fn process_from_js(input: DeriveInput) -> TokenStream {
         let name = field.ident.as_ref().expect("Field must have a name");
         let ty = &field.ty;
         quote! {
-            let #name: #ty = obj.get(stringify!(#name))?;
+            let #name: #ty = obj.get(stringify!(#name)).map_err(|e| {
+                rquickjs::Error::new_from_js_message(
+                    "object",
+                    stringify!(#ident),
+                    format!("invalid field `{}`: {}", stringify!(#name), e),
+                )
+            })?;
         }
     });
 
@@ -45,7 +51,10 @@ pub(crate) fn process_from_js(input: DeriveInput) -> TokenStream {
         impl #impl_generics rquickjs::FromJs<'js> for #ident #ty_generics #where_clause
         {
             fn from_js(_ctx: &rquickjs::Ctx<'js>, value: rquickjs::Value<'js>) -> rquickjs::Result<Self> {
-                let obj = value.into_object().unwrap();
+                let type_name = value.type_name();
+                let obj = value.into_object().ok_or_else(|| {
+                    rquickjs::Error::new_from_js_message(type_name, stringify!(#ident), "expected an object")
+                })?;
 
                 #(#code)*
 
@@ -167,13 +176,18 @@ mod tests {
         //         _ctx: &rquickjs::Ctx<'js>,
         //         value: rquickjs::Value<'js>,
         //     ) -> rquickjs::Result<Self> {
-        //         let obj = value.into_object().unwrap();
-        //         let query: HashMap<String, String> = obj.get(stringify!(query))?;
-        //         let params: HashMap<String, String> = obj.get(stringify!(params))?;
-        //         let headers: HashMap<String, String> = obj.get(stringify!(headers))?;
-        //         let method: String = obj.get(stringify!(method))?;
-        //         let url: String = obj.get(stringify!(url))?;
-        //         let body: Option<T> = obj.get(stringify!(body))?;
+        //         let type_name = value.type_name();
+        //         let obj = value.into_object().ok_or_else(|| {
+        //             rquickjs::Error::new_from_js_message(type_name, "Request", "expected an object")
+        //         })?;
+        //         let query: HashMap<String, String> = obj.get(stringify!(query)).map_err(|e| {
+        //             rquickjs::Error::new_from_js_message(
+        //                 "object",
+        //                 "Request",
+        //                 format!("invalid field `{}`: {}", "query", e),
+        //             )
+        //         })?;
+        //         ...
         //         Ok(Request {
         //             query,
         //             params,
@@ -220,13 +234,18 @@ mod tests {
         //         _ctx: &rquickjs::Ctx<'js>,
         //         value: rquickjs::Value<'js>,
         //     ) -> rquickjs::Result<Self> {
-        //         let obj = value.into_object().unwrap();
-        //         let query: HashMap<String, String> = obj.get(stringify!(query))?;
-        //         let params: HashMap<String, String> = obj.get(stringify!(params))?;
-        //         let headers: HashMap<String, String> = obj.get(stringify!(headers))?;
-        //         let method: String = obj.get(stringify!(method))?;
-        //         let url: String = obj.get(stringify!(url))?;
-        //         let body: Option<String> = obj.get(stringify!(body))?;
+        //         let type_name = value.type_name();
+        //         let obj = value.into_object().ok_or_else(|| {
+        //             rquickjs::Error::new_from_js_message(type_name, "Request", "expected an object")
+        //         })?;
+        //         let query: HashMap<String, String> = obj.get(stringify!(query)).map_err(|e| {
+        //             rquickjs::Error::new_from_js_message(
+        //                 "object",
+        //                 "Request",
+        //                 format!("invalid field `{}`: {}", "query", e),
+        //             )
+        //         })?;
+        //         ...
         //         Ok(Request {
         //             query,
         //             params,
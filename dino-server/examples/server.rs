@@ -21,12 +21,17 @@ async fn main() -> Result<()> {
                     body: JSON.stringify(req)
                 };
             }
-            return{hello:hello};
+            const echo = {
+                onMessage(socket, data) {
+                    socket.send(data);
+                },
+            };
+            return{hello:hello, echo:echo};
         })()"#;
 
     let routers = vec![TennetRouter::new(
         "localhost".to_string(),
-        SwappableAppRouter::try_new(code, config.routes)?,
+        SwappableAppRouter::try_new(code, config)?,
     )];
     start_server(8888, routers).await?;
 
@@ -0,0 +1,724 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{DefaultBodyLimit, Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{from_fn_with_state, Next},
+    response::Response,
+    routing::{get, post, put},
+    Json, Router,
+};
+use dashmap::{mapref::entry::Entry, DashMap};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tracing::{info, warn};
+
+use crate::{
+    metrics::metrics_handler, run_schedules, AppError, AppState, DeploymentRecord, DeploymentStore,
+    JsWorker, KvConfig, ProjectConfig, RouteInfo, Sticky, SwappableAppRouter,
+};
+
+// a bundle is a single js file, but it may contain inlined dependencies
+const MAX_BUNDLE_SIZE: usize = 32 * 1024 * 1024;
+
+#[derive(Clone)]
+struct AdminState {
+    app: AppState,
+    token: Arc<str>,
+    store: Option<Arc<DeploymentStore>>,
+    // deploys, rollbacks and removals of the same host run one at a time
+    locks: Arc<DashMap<String, Arc<Mutex<()>>>>,
+}
+
+/// 部署一个 tenant 的内容：build 出来的 js bundle 和 config.yml
+#[derive(Debug, Deserialize)]
+pub struct Deployment {
+    pub code: String,
+    // the content of config.yml
+    pub config: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TenantInfo {
+    pub host: String,
+    pub name: String,
+    pub hash: String,
+    // the version in the deployment store, if the store is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canary: Option<CanaryInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CanaryInfo {
+    pub hash: String,
+    pub weight: u8,
+    pub sticky: Option<Sticky>,
+}
+
+/// 在当前版本旁边运行一个 canary 版本，weight% 的请求由它处理
+#[derive(Debug, Deserialize)]
+pub struct CanaryDeployment {
+    #[serde(flatten)]
+    pub deployment: Deployment,
+    pub weight: u8,
+    #[serde(default)]
+    pub sticky: Option<Sticky>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Rollback {
+    // defaults to the version before the current one
+    pub version: Option<u64>,
+}
+
+/// 管理 tenant 的 http api，和业务请求使用不同的端口，
+/// 所有接口都需要 `Authorization: Bearer <token>`
+///
+/// - `GET /tenants`: list all tenants with the hash of their code
+/// - `PUT /tenants/:host`: deploy a bundle for the host, an existing tenant is swapped.
+///   only the bundle and config.yml are deployed, so `static`, `secrets`, `secrets_file`,
+///   a sled `kv` and route `auth`, which read files or env of the server, are rejected
+/// - `DELETE /tenants/:host`: remove the tenant, in-flight requests are not affected
+/// - `GET /tenants/:host/routes`: the route table with the middleware of each route
+/// - `PUT /tenants/:host/canary`: run a canary version next to the current one
+/// - `DELETE /tenants/:host/canary`: stop the canary
+/// - `GET /metrics`: the prometheus metrics of all tenants
+///
+/// 配置了 DeploymentStore 时，每次部署都会持久化，并额外提供：
+///
+/// - `GET /tenants/:host/deployments`: the kept versions of the host, the newest first
+/// - `POST /tenants/:host/rollback`: switch to `{"version": n}` or the previous version
+pub fn admin_router(
+    state: AppState,
+    token: impl Into<String>,
+    store: Option<Arc<DeploymentStore>>,
+) -> Router {
+    let has_store = store.is_some();
+    let state = AdminState {
+        app: state,
+        token: Arc::from(token.into()),
+        store,
+        locks: Arc::default(),
+    };
+    let mut router = Router::new()
+        .route("/metrics", get(admin_metrics))
+        .route("/tenants", get(list_tenants))
+        .route("/tenants/:host", put(deploy_tenant).delete(remove_tenant))
+        .route("/tenants/:host/routes", get(list_routes))
+        .route(
+            "/tenants/:host/canary",
+            put(deploy_canary).delete(remove_canary),
+        );
+    if has_store {
+        router = router
+            .route("/tenants/:host/deployments", get(list_deployments))
+            .route("/tenants/:host/rollback", post(rollback_tenant));
+    }
+    router
+        .route_layer(from_fn_with_state(state.clone(), check_token))
+        .layer(DefaultBodyLimit::max(MAX_BUNDLE_SIZE))
+        .with_state(state)
+}
+
+async fn check_token(
+    State(state): State<AdminState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), state.token.as_bytes()) => {
+            Ok(next.run(req).await)
+        }
+        _ => Err(AppError::Unauthorized("invalid admin token".to_string())),
+    }
+}
+
+async fn admin_metrics(State(state): State<AdminState>) -> Result<Response, AppError> {
+    metrics_handler(State(state.app)).await
+}
+
+async fn list_tenants(State(state): State<AdminState>) -> Json<Vec<TenantInfo>> {
+    let mut tenants: Vec<TenantInfo> = state
+        .app
+        .routers
+        .iter()
+        .map(|entry| {
+            let version = state.current_version(entry.key());
+            tenant_info(entry.key().clone(), entry.value(), version)
+        })
+        .collect();
+    tenants.sort_by(|a, b| a.host.cmp(&b.host));
+    Json(tenants)
+}
+
+async fn list_routes(
+    State(state): State<AdminState>,
+    Path(host): Path<String>,
+) -> Result<Json<Vec<RouteInfo>>, AppError> {
+    let router = state.get(&host)?;
+    Ok(Json(router.load().routes.clone()))
+}
+
+async fn deploy_tenant(
+    State(state): State<AdminState>,
+    Path(host): Path<String>,
+    Json(deployment): Json<Deployment>,
+) -> Result<Json<TenantInfo>, AppError> {
+    let Deployment {
+        code,
+        config: content,
+    } = deployment;
+    let (code, config) = validate(code, &content).await?;
+
+    let _guard = state.lock(&host).await;
+    let name = config.name.clone();
+    // saved before the swap, a version that is served is never lost on restart
+    let previous = state.current_version(&host);
+    let version = match &state.store {
+        Some(store) => Some(store.save(&host, &code, &content)?.version),
+        None => None,
+    };
+    let router = state
+        .deploy(&host, code, config)
+        .await
+        .inspect_err(|_| state.restore(&host, previous))?;
+    let info = tenant_info(host, &router, version);
+    info!(host = %info.host, name = %name, hash = %info.hash, ?version, "tenant deployed");
+    Ok(Json(info))
+}
+
+async fn deploy_canary(
+    State(state): State<AdminState>,
+    Path(host): Path<String>,
+    Json(canary): Json<CanaryDeployment>,
+) -> Result<Json<TenantInfo>, AppError> {
+    let CanaryDeployment {
+        deployment,
+        weight,
+        sticky,
+    } = canary;
+    let (code, config) = validate(deployment.code, &deployment.config).await?;
+    // ordered with the deploys and rollbacks of the host
+    let _guard = state.lock(&host).await;
+    let router = state.get(&host)?;
+    let canary = router.clone();
+    blocking(move || canary.set_canary(code, config, weight, sticky)).await?;
+
+    let version = state.current_version(&host);
+    let info = tenant_info(host, &router, version);
+    let hash = info
+        .canary
+        .as_ref()
+        .map(|c| c.hash.as_str())
+        .unwrap_or_default();
+    info!(host = %info.host, hash = %hash, weight, "canary deployed");
+    Ok(Json(info))
+}
+
+async fn remove_canary(
+    State(state): State<AdminState>,
+    Path(host): Path<String>,
+) -> Result<Json<TenantInfo>, AppError> {
+    let _guard = state.lock(&host).await;
+    let router = state.get(&host)?;
+    if router.clear_canary().is_some() {
+        info!(host = %host, "canary removed");
+    }
+    let version = state.current_version(&host);
+    Ok(Json(tenant_info(host, &router, version)))
+}
+
+async fn list_deployments(
+    State(state): State<AdminState>,
+    Path(host): Path<String>,
+) -> Result<Json<Vec<DeploymentRecord>>, AppError> {
+    let store = state.store()?;
+    let history = store.history(&host)?;
+    if history.is_empty() {
+        return Err(AppError::HostNotFound(host));
+    }
+    Ok(Json(history))
+}
+
+async fn rollback_tenant(
+    State(state): State<AdminState>,
+    Path(host): Path<String>,
+    rollback: Option<Json<Rollback>>,
+) -> Result<Json<TenantInfo>, AppError> {
+    let store = state.store()?;
+    let _guard = state.lock(&host).await;
+    let version = match rollback.and_then(|Json(r)| r.version) {
+        Some(version) => version,
+        None => store.previous(&host)?.ok_or_else(|| {
+            AppError::InvalidDeployment(format!("{host} has no previous version"))
+        })?,
+    };
+    let deployment = store.load(&host, version).map_err(invalid_deployment)?;
+
+    let name = deployment.config.name.clone();
+    let previous = state.current_version(&host);
+    store.set_current(&host, version)?;
+    let router = state
+        .deploy(&host, deployment.code, deployment.config)
+        .await
+        .inspect_err(|_| state.restore(&host, previous))?;
+    let info = tenant_info(host, &router, Some(version));
+    info!(host = %info.host, name = %name, hash = %info.hash, version, "tenant rolled back");
+    Ok(Json(info))
+}
+
+async fn remove_tenant(
+    State(state): State<AdminState>,
+    Path(host): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let _guard = state.lock(&host).await;
+    let Some((_, router)) = state.app.routers.remove(&host) else {
+        return Err(AppError::HostNotFound(host));
+    };
+    // stops the schedules, the workers exit once the in-flight requests are done
+    router.close();
+    if let Some(store) = &state.store {
+        store.remove(&host)?;
+    }
+    info!(host = %host, "tenant removed");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+impl AdminState {
+    // swap the running tenant, or create it if the host is new. the map entry decides which
+    // of two concurrent deploys creates the tenant, so only one scheduler runs per host
+    async fn deploy(
+        &self,
+        host: &str,
+        code: String,
+        config: ProjectConfig,
+    ) -> Result<SwappableAppRouter, AppError> {
+        if let Ok(router) = self.get(host) {
+            let tenant = router.clone();
+            blocking(move || tenant.swap(code, config)).await?;
+            return Ok(router);
+        }
+        // built outside of the map lock, loading the code takes a while
+        let router = {
+            let (code, config) = (code.clone(), config.clone());
+            blocking(move || SwappableAppRouter::try_new(code, config)).await?
+        };
+        let existing = match self.app.routers.entry(host.to_string()) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => {
+                entry.insert(router.clone());
+                let executions = self.app.executions.clone();
+                tokio::spawn(run_schedules(router.clone(), executions));
+                return Ok(router);
+            }
+        };
+        // the host was created in the meantime, this deploy becomes its next version
+        router.close();
+        let tenant = existing.clone();
+        blocking(move || tenant.swap(code, config)).await?;
+        Ok(existing)
+    }
+
+    // points the store back to the version that is still served after a failed swap
+    fn restore(&self, host: &str, version: Option<u64>) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let ret = match version {
+            Some(version) => store.set_current(host, version),
+            None => store.remove(host),
+        };
+        if let Err(e) = ret {
+            warn!(host = %host, "failed to restore the deployment store: {}", e);
+        }
+    }
+
+    async fn lock(&self, host: &str) -> OwnedMutexGuard<()> {
+        let lock = self.locks.entry(host.to_string()).or_default().clone();
+        lock.lock_owned().await
+    }
+
+    fn get(&self, host: &str) -> Result<SwappableAppRouter, AppError> {
+        let router = self.app.routers.get(host).map(|r| r.value().clone());
+        router.ok_or_else(|| AppError::HostNotFound(host.to_string()))
+    }
+
+    fn store(&self) -> Result<&DeploymentStore, AppError> {
+        let store = self.store.as_deref();
+        store.ok_or_else(|| anyhow::anyhow!("deployment store is not enabled").into())
+    }
+
+    fn current_version(&self, host: &str) -> Option<u64> {
+        let store = self.store.as_ref()?;
+        store.current(host).ok().flatten()
+    }
+}
+
+// evaluate the bundle once so that a broken one is rejected
+// here instead of failing the requests after the swap
+async fn validate(code: String, content: &str) -> Result<(String, ProjectConfig), AppError> {
+    let config: ProjectConfig = serde_yml::from_str(content)
+        .map_err(|e| AppError::InvalidDeployment(format!("invalid config: {e}")))?;
+    check_server_resources(&config)?;
+    blocking(move || {
+        JsWorker::try_new_with_config(&code, &config.name, &config.worker)?;
+        Ok((code, config))
+    })
+    .await
+}
+
+// only the bundle and config.yml are deployed and stored. these fields would be read from the
+// server itself: a path relative to its working directory or anywhere with an absolute path,
+// a secret from any environment variable of the process
+fn check_server_resources(config: &ProjectConfig) -> Result<(), AppError> {
+    let mut fields = Vec::new();
+    if !config.static_files.is_empty() {
+        fields.push("static");
+    }
+    if !config.secrets.is_empty() {
+        fields.push("secrets");
+    }
+    if config.secrets_file.is_some() {
+        fields.push("secrets_file");
+    }
+    if matches!(config.kv, KvConfig::Sled { .. }) {
+        fields.push("kv.path");
+    }
+    // every auth reads its keys from a file
+    if config.routes.values().flatten().any(|r| r.auth.is_some()) {
+        fields.push("auth");
+    }
+    if fields.is_empty() {
+        return Ok(());
+    }
+    Err(AppError::InvalidDeployment(format!(
+        "{} can't be deployed by the admin api, they are read from the server",
+        fields.join(", ")
+    )))
+}
+
+// loading a bundle blocks until every worker has evaluated it, which may take up to the
+// worker timeout, so it runs off the async workers
+async fn blocking<T, F>(f: F) -> Result<T, AppError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+{
+    let ret = tokio::task::spawn_blocking(f)
+        .await
+        .map_err(anyhow::Error::from)?;
+    ret.map_err(invalid_deployment)
+}
+
+fn tenant_info(host: String, router: &SwappableAppRouter, version: Option<u64>) -> TenantInfo {
+    let current = router.load();
+    let canary = router.canary().map(|c| CanaryInfo {
+        hash: c.router.hash.clone(),
+        weight: c.weight,
+        sticky: c.sticky.clone(),
+    });
+    TenantInfo {
+        host,
+        name: current.pool.tenant().to_string(),
+        hash: current.hash.clone(),
+        version,
+        canary,
+    }
+}
+
+fn invalid_deployment(e: anyhow::Error) -> AppError {
+    AppError::InvalidDeployment(format!("{e:#}"))
+}
+
+// compare without returning early so that the token can't be guessed by timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http,
+    };
+    use dashmap::DashMap;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const TOKEN: &str = "admin-token";
+
+    fn code(message: &str) -> String {
+        format!(
+            r#"(function(){{
+                async function hello1(req){{
+                    return {{ status: 200, headers: {{}}, body: "{message}" }};
+                }}
+                return{{hello1:hello1}};
+            }})()"#
+        )
+    }
+
+    fn request(method: &str, uri: &str, body: Option<serde_json::Value>) -> Request {
+        let builder = http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {TOKEN}"))
+            .header(header::CONTENT_TYPE, "application/json");
+        let body = body.map_or_else(Body::empty, |v| Body::from(v.to_string()));
+        builder.body(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn admin_router_should_manage_tenants() -> anyhow::Result<()> {
+        let state = AppState::new(DashMap::new());
+        let app = admin_router(state.clone(), TOKEN, None);
+        let config = include_str!("../fixtures/config.yml");
+
+        let deployment = json!({ "code": code("v1"), "config": config });
+        let res = app
+            .clone()
+            .oneshot(request("PUT", "/tenants/example.com", Some(deployment)))
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await?;
+        let v1: TenantInfo = serde_json::from_slice(&body)?;
+        assert_eq!(v1.name, "dino-test");
+
+        let deployment = json!({ "code": code("v2"), "config": config });
+        let res = app
+            .clone()
+            .oneshot(request("PUT", "/tenants/example.com", Some(deployment)))
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // a broken bundle is rejected and the running version is kept
+        let deployment = json!({ "code": "(function(){", "config": config });
+        let res = app
+            .clone()
+            .oneshot(request("PUT", "/tenants/example.com", Some(deployment)))
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // only the bundle and config.yml are deployed, nothing is read from the server
+        let auth = "handler: hello1\n      auth: { api_key: { keys_file: keys.yml } }";
+        let configs = [
+            format!("{config}static:\n  /: public\n"),
+            format!("{config}static:\n  /: /etc\n"),
+            format!("{config}secrets:\n  TOKEN: AWS_SECRET_ACCESS_KEY\n"),
+            format!("{config}secrets_file: /etc/secrets.yml\n"),
+            format!("{config}kv:\n  backend: sled\n  path: /tmp/kv\n"),
+            config.replace("handler: hello1", auth),
+        ];
+        for config in configs {
+            let deployment = json!({ "code": code("v3"), "config": config });
+            let res = app
+                .clone()
+                .oneshot(request("PUT", "/tenants/example.com", Some(deployment)))
+                .await?;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            let body = to_bytes(res.into_body(), usize::MAX).await?;
+            let body = String::from_utf8(body.to_vec())?;
+            assert!(
+                body.contains("can't be deployed by the admin api"),
+                "{body}"
+            );
+        }
+
+        let res = app
+            .clone()
+            .oneshot(request("GET", "/tenants", None))
+            .await?;
+        let body = to_bytes(res.into_body(), usize::MAX).await?;
+        let tenants: Vec<TenantInfo> = serde_json::from_slice(&body)?;
+        assert_eq!(tenants.len(), 1);
+        assert_eq!(tenants[0].host, "example.com");
+        assert_ne!(tenants[0].hash, v1.hash);
+        let router = state.routers.get("example.com").unwrap().load();
+        assert!(router.code.contains("v2"));
+
+        let res = app
+            .clone()
+            .oneshot(request("GET", "/tenants/example.com/routes", None))
+            .await?;
+        let body = to_bytes(res.into_body(), usize::MAX).await?;
+        let routes: Vec<RouteInfo> = serde_json::from_slice(&body)?;
+        assert_eq!(routes.len(), 5);
+        assert_eq!(routes[0].path, "/api/hello/:id");
+        assert_eq!(routes[0].method, "GET");
+        assert_eq!(routes[4].method, "WEBSOCKET");
+
+        let res = app
+            .clone()
+            .oneshot(request("GET", "/metrics", None))
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await?;
+        let metrics = String::from_utf8(body.to_vec())?;
+        assert!(metrics.contains(r#"dino_pool_workers{host="example.com"}"#));
+
+        let router = state.routers.get("example.com").unwrap().clone();
+        let res = app
+            .clone()
+            .oneshot(request("DELETE", "/tenants/example.com", None))
+            .await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(state.routers.is_empty());
+        // the scheduler of the removed tenant is stopped
+        assert!(router.closed.is_cancelled());
+        let res = app
+            .oneshot(request("DELETE", "/tenants/example.com", None))
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_router_should_create_tenant_once() -> anyhow::Result<()> {
+        let state = AppState::new(DashMap::new());
+        let app = admin_router(state.clone(), TOKEN, None);
+        let config = include_str!("../fixtures/config.yml");
+
+        let deploy = |message: &str| {
+            let deployment = json!({ "code": code(message), "config": config });
+            app.clone()
+                .oneshot(request("PUT", "/tenants/example.com", Some(deployment)))
+        };
+        let (r1, r2) = tokio::join!(deploy("v1"), deploy("v2"));
+        assert_eq!(r1?.status(), StatusCode::OK);
+        assert_eq!(r2?.status(), StatusCode::OK);
+        assert_eq!(state.routers.len(), 1);
+        let router = state.routers.get("example.com").unwrap().clone();
+        assert!(!router.closed.is_cancelled());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_router_should_rollback_deployments() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let store = Arc::new(DeploymentStore::open(dir.path(), 10)?);
+        let state = AppState::new(DashMap::new());
+        let app = admin_router(state.clone(), TOKEN, Some(store.clone()));
+        let config = include_str!("../fixtures/config.yml");
+
+        for message in ["v1", "v2", "v3"] {
+            let deployment = json!({ "code": code(message), "config": config });
+            let res = app
+                .clone()
+                .oneshot(request("PUT", "/tenants/example.com", Some(deployment)))
+                .await?;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let req = request("GET", "/tenants/example.com/deployments", None);
+        let res = app.clone().oneshot(req).await?;
+        let body = to_bytes(res.into_body(), usize::MAX).await?;
+        let history: Vec<DeploymentRecord> = serde_json::from_slice(&body)?;
+        let versions: Vec<u64> = history.iter().map(|r| r.version).collect();
+        assert_eq!(versions, vec![3, 2, 1]);
+
+        // without a version, roll back to the previous one
+        let req = request("POST", "/tenants/example.com/rollback", None);
+        let res = app.clone().oneshot(req).await?;
+        let body = to_bytes(res.into_body(), usize::MAX).await?;
+        let info: TenantInfo = serde_json::from_slice(&body)?;
+        assert_eq!(info.version, Some(2));
+        assert!(state
+            .routers
+            .get("example.com")
+            .unwrap()
+            .load()
+            .code
+            .contains("v2"));
+
+        let rollback = json!({ "version": 1 });
+        let req = request("POST", "/tenants/example.com/rollback", Some(rollback));
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(state
+            .routers
+            .get("example.com")
+            .unwrap()
+            .load()
+            .code
+            .contains("v1"));
+        assert_eq!(store.current("example.com")?, Some(1));
+        assert_eq!(
+            store.load_current("example.com")?.record.hash,
+            history[2].hash
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_router_should_manage_canary() -> anyhow::Result<()> {
+        let state = AppState::new(DashMap::new());
+        let app = admin_router(state.clone(), TOKEN, None);
+        let config = include_str!("../fixtures/config.yml");
+
+        let canary = json!({ "code": code("v2"), "config": config, "weight": 100 });
+        let req = request("PUT", "/tenants/example.com/canary", Some(canary.clone()));
+        let res = app.clone().oneshot(req).await?;
+        // the current version must be deployed first
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let deployment = json!({ "code": code("v1"), "config": config });
+        let req = request("PUT", "/tenants/example.com", Some(deployment));
+        app.clone().oneshot(req).await?;
+        let req = request("PUT", "/tenants/example.com/canary", Some(canary));
+        let res = app.clone().oneshot(req).await?;
+        let body = to_bytes(res.into_body(), usize::MAX).await?;
+        let info: TenantInfo = serde_json::from_slice(&body)?;
+        let canary = info.canary.expect("canary should be running");
+        assert_eq!(canary.weight, 100);
+        assert_ne!(canary.hash, info.hash);
+
+        let router = state.routers.get("example.com").unwrap().clone();
+        let selection = router.select(&Default::default());
+        assert!(selection.canary);
+        assert!(selection.router.code.contains("v2"));
+
+        let req = request("DELETE", "/tenants/example.com/canary", None);
+        let res = app.oneshot(req).await?;
+        let body = to_bytes(res.into_body(), usize::MAX).await?;
+        let info: TenantInfo = serde_json::from_slice(&body)?;
+        assert!(info.canary.is_none());
+        assert!(router
+            .select(&Default::default())
+            .router
+            .code
+            .contains("v1"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_router_should_require_token() -> anyhow::Result<()> {
+        let app = admin_router(AppState::new(DashMap::new()), TOKEN, None);
+        let req = http::Request::builder()
+            .uri("/tenants")
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = http::Request::builder()
+            .uri("/tenants")
+            .header(header::AUTHORIZATION, "Bearer wrong-token")
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = http::Request::builder()
+            .uri("/metrics")
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+}
@@ -0,0 +1,437 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs,
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Result};
+use axum::{
+    extract::{Host, Request, State},
+    http::{header, header::Entry, HeaderMap, HeaderName},
+    middleware::Next,
+    response::Response,
+};
+use jsonwebtoken::{
+    decode, decode_header,
+    errors::ErrorKind,
+    jwk::{AlgorithmParameters, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde_json::{json, Value};
+
+use crate::{ApiKeyConfig, AppError, AppState, AuthConfig, JwtAlgorithm, JwtConfig};
+
+/// 路由的认证器，由 config.yml 中路由的 `auth` 创建，key 文件在每次 swap 时重新读取。
+/// 校验通过后返回的 claims 作为 `req.auth` 传给 js
+pub struct Authenticator(Verifier);
+
+enum Verifier {
+    Jwt(JwtVerifier),
+    ApiKey(ApiKeyVerifier),
+}
+
+struct JwtVerifier {
+    // the key id from the jwks file, a key without id is used for tokens without `kid`
+    keys: Vec<(Option<String>, DecodingKey)>,
+    validation: Validation,
+    scopes: Vec<String>,
+}
+
+struct ApiKeyVerifier {
+    header: HeaderName,
+    // blake3 of the key -> name of the key, the keys are never compared byte by byte
+    keys: HashMap<blake3::Hash, String>,
+    allow: Vec<String>,
+}
+
+impl Authenticator {
+    pub fn try_new(config: &AuthConfig) -> Result<Self> {
+        let verifier = match config {
+            AuthConfig::Jwt(config) => Verifier::Jwt(JwtVerifier::try_new(config)?),
+            AuthConfig::ApiKey(config) => Verifier::ApiKey(ApiKeyVerifier::try_new(config)?),
+        };
+        Ok(Self(verifier))
+    }
+
+    /// the verified claims, Unauthorized without valid credentials and Forbidden when
+    /// the credentials are not allowed on the route
+    pub fn verify(&self, headers: &HeaderMap) -> Result<Value, AppError> {
+        match &self.0 {
+            Verifier::Jwt(jwt) => jwt.verify(headers),
+            Verifier::ApiKey(api_key) => api_key.verify(headers),
+        }
+    }
+}
+
+// keys are not printed
+impl fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.0 {
+            Verifier::Jwt(_) => "jwt",
+            Verifier::ApiKey(_) => "api_key",
+        };
+        f.debug_tuple("Authenticator").field(&kind).finish()
+    }
+}
+
+impl JwtVerifier {
+    fn try_new(config: &JwtConfig) -> Result<Self> {
+        let algorithm = match config.algorithm {
+            JwtAlgorithm::Hs256 => Algorithm::HS256,
+            JwtAlgorithm::Rs256 => Algorithm::RS256,
+            JwtAlgorithm::EdDsa => Algorithm::EdDSA,
+        };
+        let keys = match (&config.key_file, &config.jwks_file) {
+            (Some(file), None) => vec![(None, load_key(file, config.algorithm)?)],
+            (None, Some(file)) => load_jwks(file, config.algorithm)?,
+            _ => bail!("auth.jwt requires either key_file or jwks_file"),
+        };
+
+        // only the configured algorithm is accepted, `alg` of the token can't pick another one
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = config.leeway_secs;
+        validation.validate_nbf = true;
+        if config.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&config.audience);
+        }
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        Ok(Self {
+            keys,
+            validation,
+            scopes: config.scopes.clone(),
+        })
+    }
+
+    fn verify(&self, headers: &HeaderMap) -> Result<Value, AppError> {
+        let token = bearer_token(headers)
+            .ok_or_else(|| AppError::Unauthorized("missing bearer token".to_string()))?;
+        let header = decode_header(token).map_err(invalid_token)?;
+        let key = self
+            .keys
+            .iter()
+            .find(|(kid, _)| *kid == header.kid)
+            // a single key is used whatever the `kid` of the token is
+            .or_else(|| (self.keys.len() == 1).then(|| &self.keys[0]))
+            .map(|(_, key)| key)
+            .ok_or_else(|| AppError::Unauthorized("unknown key id".to_string()))?;
+        let claims = decode::<Value>(token, key, &self.validation)
+            .map_err(invalid_token)?
+            .claims;
+
+        let granted = granted_scopes(&claims);
+        if let Some(scope) = self.scopes.iter().find(|s| !granted.contains(s.as_str())) {
+            return Err(AppError::Forbidden(format!("missing scope {scope}")));
+        }
+        Ok(claims)
+    }
+}
+
+impl ApiKeyVerifier {
+    fn try_new(config: &ApiKeyConfig) -> Result<Self> {
+        let file = &config.keys_file;
+        let content = fs::read_to_string(file)
+            .map_err(|e| anyhow!("read keys file {} failed: {e}", file.display()))?;
+        // the parse error may quote the content of the file
+        let keys: BTreeMap<String, String> = serde_yml::from_str(&content)
+            .map_err(|_| anyhow!("invalid keys file {}", file.display()))?;
+        if keys.values().any(|key| key.is_empty()) {
+            bail!("empty api key in {}", file.display());
+        }
+        let keys = keys
+            .into_iter()
+            .map(|(name, key)| (blake3::hash(key.as_bytes()), name))
+            .collect();
+        Ok(Self {
+            header: HeaderName::from_bytes(config.header.to_ascii_lowercase().as_bytes())?,
+            keys,
+            allow: config.allow.clone(),
+        })
+    }
+
+    fn verify(&self, headers: &HeaderMap) -> Result<Value, AppError> {
+        let key = headers
+            .get(&self.header)
+            .ok_or_else(|| AppError::Unauthorized("missing api key".to_string()))?;
+        let name = self
+            .keys
+            .get(&blake3::hash(key.as_bytes()))
+            .ok_or_else(|| AppError::Unauthorized("invalid api key".to_string()))?;
+        if !self.allow.is_empty() && !self.allow.contains(name) {
+            return Err(AppError::Forbidden(format!(
+                "api key {name} is not allowed"
+            )));
+        }
+        Ok(json!({ "key": name }))
+    }
+}
+
+/// 把 tenant 配置的 api key headers 标记为 sensitive，和 authorization、cookie 一样，
+/// request span 中只会记录 `Sensitive`。必须在 TraceLayer 之前运行
+pub(crate) async fn mark_api_keys(
+    State(state): State<AppState>,
+    Host(host): Host,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if let Ok((_, tenant, _)) = state.lookup(&host, req.uri().path()) {
+        // either version may serve the request, it is not picked yet
+        let canary = tenant.canary().map(|c| c.router.clone());
+        for router in std::iter::once(tenant.load()).chain(canary) {
+            for name in &router.api_key_headers {
+                if let Entry::Occupied(mut entry) = req.headers_mut().entry(name) {
+                    entry.iter_mut().for_each(|v| v.set_sensitive(true));
+                }
+            }
+        }
+    }
+    next.run(req).await
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then_some(token)
+}
+
+// `scope` is a space separated string (RFC 8693), `scp` is used by some providers as an array
+fn granted_scopes(claims: &Value) -> HashSet<&str> {
+    let mut scopes = HashSet::new();
+    for name in ["scope", "scp"] {
+        match &claims[name] {
+            Value::String(s) => scopes.extend(s.split_whitespace()),
+            Value::Array(items) => scopes.extend(items.iter().filter_map(|v| v.as_str())),
+            _ => {}
+        }
+    }
+    scopes
+}
+
+fn invalid_token(e: jsonwebtoken::errors::Error) -> AppError {
+    let message = match e.kind() {
+        ErrorKind::ExpiredSignature => "token expired".to_string(),
+        ErrorKind::InvalidAudience => "invalid audience".to_string(),
+        ErrorKind::InvalidIssuer => "invalid issuer".to_string(),
+        _ => format!("invalid token: {e}"),
+    };
+    AppError::Unauthorized(message)
+}
+
+fn load_key(file: &Path, algorithm: JwtAlgorithm) -> Result<DecodingKey> {
+    let content =
+        fs::read(file).map_err(|e| anyhow!("read key file {} failed: {e}", file.display()))?;
+    let key = match algorithm {
+        JwtAlgorithm::Hs256 => {
+            // a trailing newline added by the editor is not part of the secret
+            let len = content
+                .iter()
+                .rposition(|b| !b.is_ascii_whitespace())
+                .map_or(0, |i| i + 1);
+            if len == 0 {
+                bail!("empty secret in {}", file.display());
+            }
+            DecodingKey::from_secret(&content[..len])
+        }
+        JwtAlgorithm::Rs256 => DecodingKey::from_rsa_pem(&content)?,
+        JwtAlgorithm::EdDsa => DecodingKey::from_ed_pem(&content)?,
+    };
+    Ok(key)
+}
+
+fn load_jwks(file: &Path, algorithm: JwtAlgorithm) -> Result<Vec<(Option<String>, DecodingKey)>> {
+    let content = fs::read_to_string(file)
+        .map_err(|e| anyhow!("read jwks file {} failed: {e}", file.display()))?;
+    let jwks: JwkSet = serde_json::from_str(&content)
+        .map_err(|e| anyhow!("invalid jwks file {}: {e}", file.display()))?;
+    let mut keys = Vec::new();
+    for jwk in &jwks.keys {
+        // keys of the other algorithms in the set are ignored
+        let usable = matches!(
+            (&jwk.algorithm, algorithm),
+            (AlgorithmParameters::OctetKey(_), JwtAlgorithm::Hs256)
+                | (AlgorithmParameters::RSA(_), JwtAlgorithm::Rs256)
+                | (AlgorithmParameters::OctetKeyPair(_), JwtAlgorithm::EdDsa)
+        );
+        if usable {
+            keys.push((jwk.common.key_id.clone(), DecodingKey::from_jwk(jwk)?));
+        }
+    }
+    if keys.is_empty() {
+        bail!("no {algorithm:?} key in jwks file {}", file.display());
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use axum::http::HeaderValue;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    use super::*;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = HeaderValue::from_str(&format!("Bearer {token}")).unwrap();
+        headers.insert(header::AUTHORIZATION, value);
+        headers
+    }
+
+    fn authenticator(dir: &Path, yaml: &str) -> Result<Authenticator> {
+        let mut config: AuthConfig = serde_yml::from_str(yaml)?;
+        config.rebase(dir);
+        Authenticator::try_new(&config)
+    }
+
+    #[test]
+    fn jwt_should_verify_hs256_claims() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("secret"), "secret\n")?;
+        let auth = authenticator(
+            dir.path(),
+            "jwt: { algorithm: HS256, key_file: secret, audience: [api], scopes: [read] }",
+        )?;
+        let key = EncodingKey::from_secret(b"secret");
+        let token = |claims: Value| encode(&Header::default(), &claims, &key).unwrap();
+
+        let claims = json!({ "sub": "alice", "aud": "api", "exp": now() + 60, "scope": "read" });
+        let ret = auth.verify(&bearer(&token(claims)))?;
+        assert_eq!(ret["sub"], "alice");
+
+        let claims = json!({ "sub": "alice", "aud": "api", "exp": now() + 60 });
+        let err = auth.verify(&bearer(&token(claims))).unwrap_err();
+        assert!(matches!(err, AppError::Forbidden(_)));
+
+        for claims in [
+            json!({ "aud": "api", "exp": now() - 120, "scope": "read" }),
+            json!({ "aud": "web", "exp": now() + 60, "scope": "read" }),
+            json!({ "aud": "api", "scope": "read" }),
+        ] {
+            let err = auth.verify(&bearer(&token(claims))).unwrap_err();
+            assert!(matches!(err, AppError::Unauthorized(_)), "{err:?}");
+        }
+        let other = EncodingKey::from_secret(b"other");
+        let claims = json!({ "aud": "api", "exp": now() + 60, "scope": "read" });
+        let forged = encode(&Header::default(), &claims, &other)?;
+        assert!(auth.verify(&bearer(&forged)).is_err());
+        assert!(auth.verify(&HeaderMap::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_should_pick_key_from_jwks() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // base64 of `secret` and `foobar`
+        let jwks = json!({ "keys": [
+            { "kty": "oct", "kid": "k1", "k": "c2VjcmV0" },
+            { "kty": "oct", "kid": "k2", "k": "Zm9vYmFy" },
+        ]});
+        fs::write(dir.path().join("jwks.json"), jwks.to_string())?;
+        let auth = authenticator(
+            dir.path(),
+            "jwt: { algorithm: HS256, jwks_file: jwks.json }",
+        )?;
+
+        let claims = json!({ "sub": "bob", "exp": now() + 60 });
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("k2".to_string());
+        let token = encode(&header, &claims, &EncodingKey::from_secret(b"foobar"))?;
+        assert_eq!(auth.verify(&bearer(&token))?["sub"], "bob");
+
+        header.kid = Some("k1".to_string());
+        let token = encode(&header, &claims, &EncodingKey::from_secret(b"foobar"))?;
+        assert!(auth.verify(&bearer(&token)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_should_verify_eddsa() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let key_pair = rcgen::KeyPair::generate_for(&rcgen::PKCS_ED25519)?;
+        fs::write(dir.path().join("public.pem"), key_pair.public_key_pem())?;
+        let auth = authenticator(
+            dir.path(),
+            "jwt: { algorithm: EdDSA, key_file: public.pem }",
+        )?;
+
+        let key = EncodingKey::from_ed_pem(key_pair.serialize_pem().as_bytes())?;
+        let claims = json!({ "sub": "carol", "exp": now() + 60 });
+        let token = encode(&Header::new(Algorithm::EdDSA), &claims, &key)?;
+        assert_eq!(auth.verify(&bearer(&token))?["sub"], "carol");
+
+        // a HS256 token signed with the public key must not pass
+        let hs = EncodingKey::from_secret(key_pair.public_key_pem().as_bytes());
+        let token = encode(&Header::default(), &claims, &hs)?;
+        assert!(auth.verify(&bearer(&token)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn api_key_should_check_allowed_names() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("keys.yml"), "ci: key-1\nadmin: key-2\n")?;
+        let auth = authenticator(
+            dir.path(),
+            "api_key: { keys_file: keys.yml, allow: [admin] }",
+        )?;
+        let headers = |key: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-api-key", HeaderValue::from_str(key).unwrap());
+            headers
+        };
+        assert_eq!(auth.verify(&headers("key-2"))?, json!({ "key": "admin" }));
+        let err = auth.verify(&headers("key-1")).unwrap_err();
+        assert!(matches!(err, AppError::Forbidden(_)));
+        let err = auth.verify(&headers("key-3")).unwrap_err();
+        assert!(matches!(err, AppError::Unauthorized(_)));
+        assert!(auth.verify(&HeaderMap::new()).is_err());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn mark_api_keys_should_mark_configured_headers() -> Result<()> {
+        use axum::{body::Body, middleware::from_fn_with_state, routing::get, Router};
+        use dashmap::DashMap;
+        use tower::ServiceExt;
+
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("keys.yml"), "ci: key-1\n")?;
+        let mut config: crate::ProjectConfig =
+            serde_yml::from_str(include_str!("../fixtures/config.yml"))?;
+        config.routes["/api/hello/:id"][0].auth = Some(serde_yml::from_str(
+            "api_key: { header: X-Token, keys_file: keys.yml }",
+        )?);
+        let config = config.with_base_dir(dir.path());
+        let routers = DashMap::new();
+        let router = crate::SwappableAppRouter::try_new("(function(){ return {}; })()", config)?;
+        routers.insert("example.com".to_string(), router);
+
+        let sensitive = |headers: HeaderMap| async move {
+            let sensitive = |name: &str| headers[name].is_sensitive();
+            format!("{} {}", sensitive("x-token"), sensitive("user-agent"))
+        };
+        let app = Router::new()
+            .route("/*path", get(sensitive))
+            .layer(from_fn_with_state(AppState::new(routers), mark_api_keys));
+        let req = Request::get("/api/hello/1")
+            .header(header::HOST, "example.com")
+            .header("x-token", "key-1")
+            .header(header::USER_AGENT, "curl")
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        assert_eq!(body, "true false");
+        Ok(())
+    }
+}
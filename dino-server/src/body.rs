@@ -0,0 +1,70 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    response::Response,
+};
+use http_body::{Body as HttpBody, Frame, SizeHint};
+
+/// keep `value` alive until the body of the response is sent or dropped, e.g. a
+/// concurrency slot held by a streaming response
+pub(crate) fn hold<T>(res: Response, value: T) -> Response
+where
+    T: Send + Unpin + 'static,
+{
+    res.map(|body| {
+        Body::new(HoldBody {
+            body,
+            _value: value,
+        })
+    })
+}
+
+struct HoldBody<T> {
+    body: Body,
+    _value: T,
+}
+
+impl<T: Unpin> HttpBody for HoldBody<T> {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    // the size is kept, so that a fixed length body is not sent chunked
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn hold_should_keep_value_until_body_is_consumed() -> anyhow::Result<()> {
+        let value = Arc::new(());
+        let res = hold(Response::new(Body::from("hello")), value.clone());
+        assert_eq!(res.body().size_hint().exact(), Some(5));
+        assert_eq!(Arc::strong_count(&value), 2);
+
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        assert_eq!(body, "hello");
+        assert_eq!(Arc::strong_count(&value), 1);
+        Ok(())
+    }
+}
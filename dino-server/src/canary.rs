@@ -0,0 +1,234 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use axum::{
+    extract::{Host, Request, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{AppRouter, AppState, ProjectConfig, SwappableAppRouter};
+
+// a month, the sticky id only needs to outlive the canary release
+const STICKY_COOKIE_MAX_AGE: u64 = 30 * 24 * 3600;
+
+/// 灰度发布：同一个 host 同时运行两个版本，按 weight 的比例把请求分给 canary 版本。
+/// canary 只保存在内存中，确认没有问题后把同样的代码正常部署即可
+pub struct Canary {
+    pub router: AppRouter,
+    // percentage of the requests served by the canary, 0..=100
+    pub weight: u8,
+    pub sticky: Option<Sticky>,
+}
+
+/// keep a client on the same version across requests
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sticky {
+    // a random id is assigned in this cookie on the first request of the client
+    Cookie(String),
+    // e.g. a user id set by the client or a gateway, random if the header is missing
+    Header(String),
+}
+
+/// the version picked for a request
+#[derive(Clone)]
+pub struct Selection {
+    pub router: AppRouter,
+    pub canary: bool,
+    // the set-cookie of a newly assigned sticky id
+    pub cookie: Option<HeaderValue>,
+}
+
+impl SwappableAppRouter {
+    /// run the code next to the current version, weight% of the requests go to it
+    pub fn set_canary(
+        &self,
+        code: impl Into<String>,
+        config: ProjectConfig,
+        weight: u8,
+        sticky: Option<Sticky>,
+    ) -> Result<()> {
+        if weight > 100 {
+            bail!("canary weight must be in 0..=100, got {weight}");
+        }
+        let router = AppRouter::from(Arc::new(self.build(code, config)?));
+        let canary = Canary {
+            router,
+            weight,
+            sticky,
+        };
+        self.canary.store(Some(Arc::new(canary)));
+        Ok(())
+    }
+
+    /// stop the canary, all requests go to the current version again
+    pub fn clear_canary(&self) -> Option<Arc<Canary>> {
+        self.canary.swap(None)
+    }
+
+    pub fn canary(&self) -> Option<Arc<Canary>> {
+        self.canary.load_full()
+    }
+
+    /// pick the version serving a request
+    pub fn select(&self, headers: &HeaderMap) -> Selection {
+        let stable = self.load();
+        let Some(canary) = self.canary.load_full() else {
+            return Selection {
+                router: stable,
+                canary: false,
+                cookie: None,
+            };
+        };
+
+        let (key, cookie) = match &canary.sticky {
+            Some(Sticky::Header(name)) => {
+                let key = headers.get(name).and_then(|v| v.to_str().ok());
+                (key.map(|v| v.to_string()), None)
+            }
+            Some(Sticky::Cookie(name)) => match get_cookie(headers, name) {
+                Some(id) => (Some(id.to_string()), None),
+                None => {
+                    let id = uuid::Uuid::now_v7().to_string();
+                    let attrs = "Path=/; HttpOnly; SameSite=Lax";
+                    let cookie = format!("{name}={id}; Max-Age={STICKY_COOKIE_MAX_AGE}; {attrs}");
+                    (Some(id), HeaderValue::from_str(&cookie).ok())
+                }
+            },
+            None => (None, None),
+        };
+        // the same key always falls into the same bucket, raising the weight only moves
+        // clients from the current version to the canary
+        let bucket = match key {
+            Some(key) => bucket(&key),
+            None => rand::thread_rng().gen_range(0..100),
+        };
+        let is_canary = bucket < canary.weight;
+        Selection {
+            router: if is_canary {
+                canary.router.clone()
+            } else {
+                stable
+            },
+            canary: is_canary,
+            cookie,
+        }
+    }
+}
+
+/// 每个请求只选择一次版本，放在 request extensions 中，
+/// 限流、跨域和 handler 都使用这个版本的配置和代码
+pub(crate) async fn select_version(
+    State(state): State<AppState>,
+    Host(host): Host,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if let Ok((_, tenant, _)) = state.lookup(&host, req.uri().path()) {
+        let selection = tenant.select(req.headers());
+        req.extensions_mut().insert(selection);
+    }
+    next.run(req).await
+}
+
+/// the version picked by `select_version`, the current one if the request has none
+pub(crate) fn selected(req: &Request, tenant: &SwappableAppRouter) -> AppRouter {
+    match req.extensions().get::<Selection>() {
+        Some(selection) => selection.router.clone(),
+        None => tenant.load(),
+    }
+}
+
+fn bucket(key: &str) -> u8 {
+    let hash = blake3::hash(key.as_bytes());
+    let bytes: [u8; 8] = hash.as_bytes()[..8].try_into().unwrap_or_default();
+    (u64::from_le_bytes(bytes) % 100) as u8
+}
+
+fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Result<SwappableAppRouter> {
+        let config = include_str!("../fixtures/config.yml");
+        let config: ProjectConfig = serde_yml::from_str(config)?;
+        SwappableAppRouter::try_new("stable", config)
+    }
+
+    fn set_canary(router: &SwappableAppRouter, weight: u8, sticky: Option<Sticky>) -> Result<()> {
+        let config = include_str!("../fixtures/config.yml");
+        router.set_canary("canary", serde_yml::from_str(config)?, weight, sticky)
+    }
+
+    #[test]
+    fn select_should_split_traffic_by_weight() -> Result<()> {
+        let router = router()?;
+        let headers = HeaderMap::new();
+        assert!(!router.select(&headers).canary);
+
+        set_canary(&router, 100, None)?;
+        let selection = router.select(&headers);
+        assert!(selection.canary);
+        assert_eq!(selection.router.code, "canary");
+
+        set_canary(&router, 30, None)?;
+        let count = (0..1000).filter(|_| router.select(&headers).canary).count();
+        assert!(
+            (200..400).contains(&count),
+            "{count} requests went to the canary"
+        );
+
+        assert!(set_canary(&router, 101, None).is_err());
+        router.clear_canary();
+        assert_eq!(router.select(&headers).router.code, "stable");
+        Ok(())
+    }
+
+    #[test]
+    fn select_should_be_sticky() -> Result<()> {
+        let router = router()?;
+        set_canary(&router, 50, Some(Sticky::Header("x-user-id".to_string())))?;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-user-id", HeaderValue::from_static("alice"));
+        let first = router.select(&headers).canary;
+        assert!((0..100).all(|_| router.select(&headers).canary == first));
+
+        set_canary(&router, 50, Some(Sticky::Cookie("dino-canary".to_string())))?;
+        let selection = router.select(&HeaderMap::new());
+        let cookie = selection.cookie.expect("sticky cookie should be assigned");
+        let cookie = cookie
+            .to_str()?
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        assert!(cookie.starts_with("dino-canary="));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_str(&format!("a=b; {cookie}"))?,
+        );
+        for _ in 0..100 {
+            let s = router.select(&headers);
+            assert_eq!(s.canary, selection.canary);
+            assert!(s.cookie.is_none());
+        }
+        Ok(())
+    }
+}
@@ -1,22 +1,282 @@
-use std::path::Path;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use axum::http::Method;
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::ProjectRoutes;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct ProjectConfig {
     pub name: String,
+    // the host pattern of the tenant when served by `dino serve`, e.g. `api.example.com`
+    #[serde(default)]
+    pub host: Option<String>,
     pub routes: ProjectRoutes,
+    // js middleware run before the handler of every http route, before the route's own
+    #[serde(default)]
+    pub middleware: Vec<String>,
+    #[serde(default)]
+    pub worker: WorkerConfig,
+    #[serde(default)]
+    pub schedules: Vec<ScheduleConfig>,
+    #[serde(default)]
+    pub kv: KvConfig,
+    // plain values exposed to handlers as `Dino.env`
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    // name in `Dino.env` -> name in the process env or the secrets file,
+    // the values never appear in config.yml and so never in the build output
+    #[serde(default)]
+    pub secrets: BTreeMap<String, String>,
+    // a local yaml file of `name: value`, relative to the project directory
+    #[serde(default)]
+    pub secrets_file: Option<PathBuf>,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    // cross-origin requests are rejected by the browser unless configured
+    #[serde(default)]
+    pub cors: Option<CorsConfig>,
+    // url prefix -> directory, served before the js routes
+    #[serde(default, rename = "static")]
+    pub static_files: BTreeMap<String, StaticDir>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct ProjectRoute {
-    #[serde(deserialize_with = "deserialize_method")]
+    #[serde(default)]
+    pub kind: RouteKind,
+    // websocket routes are always upgraded from a GET request, so the method can be omitted
+    #[serde(default = "default_method", deserialize_with = "deserialize_method")]
     pub method: Method,
     pub handler: String,
+    // js middleware run in order before the handler, each calls `next(req)` to go on
+    #[serde(default)]
+    pub middleware: Vec<String>,
+    // verified before the middleware and the handler run
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+}
+
+/// 路由的认证方式，在 rust 中校验，通过后 claims 作为 `req.auth` 传给 js。
+/// 没有或无效的凭证返回 401，凭证有效但权限不足返回 403
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "AuthConfigMap")]
+pub enum AuthConfig {
+    // `Authorization: Bearer <jwt>`
+    Jwt(JwtConfig),
+    ApiKey(ApiKeyConfig),
+}
+
+// serde_yml reads externally tagged enums from `!tag`, config.yml uses `jwt: {..}` instead
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuthConfigMap {
+    jwt: Option<JwtConfig>,
+    api_key: Option<ApiKeyConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct JwtConfig {
+    pub algorithm: JwtAlgorithm,
+    // HS256: the shared secret, RS256 / EdDSA: the PEM encoded public key
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+    // a JWKS json file, the key is picked by the `kid` of the token
+    #[serde(default)]
+    pub jwks_file: Option<PathBuf>,
+    // the `aud` claim must contain one of them, not checked when empty
+    #[serde(default)]
+    pub audience: Vec<String>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    // required in the `scope` (space separated) or `scp` claim, 403 when missing
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    // allowed clock skew for `exp` and `nbf`
+    #[serde(default = "default_jwt_leeway")]
+    pub leeway_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum JwtAlgorithm {
+    #[serde(rename = "HS256")]
+    Hs256,
+    #[serde(rename = "RS256")]
+    Rs256,
+    #[serde(rename = "EdDSA")]
+    EdDsa,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKeyConfig {
+    #[serde(default = "default_api_key_header")]
+    pub header: String,
+    // a yaml file of `name: key`, the keys never appear in config.yml
+    pub keys_file: PathBuf,
+    // names of the keys accepted by the route, 403 for the others. all keys when empty
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+/// 路由表中的一行，列出每个路由实际执行的 middleware，方便审计
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteInfo {
+    pub path: String,
+    // `WEBSOCKET` for websocket routes
+    pub method: String,
+    pub handler: String,
+    pub middleware: Vec<String>,
+    // `jwt` or `api_key`
+    #[serde(default)]
+    pub auth: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteKind {
+    #[default]
+    Http,
+    // the handler is an object with onOpen / onMessage / onClose callbacks
+    Websocket,
+}
+
+/// `Dino.kv` 的存储后端，在 tenant 创建时确定，swap 不会改变已有的数据
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum KvConfig {
+    #[default]
+    Memory,
+    // embedded on-disk store, the directory can only be used by one tenant
+    Sled {
+        path: PathBuf,
+    },
+}
+
+/// 定时执行的 handler，cron 支持 5 段（分钟级）和 6/7 段（秒级）两种写法
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduleConfig {
+    pub cron: String,
+    pub handler: String,
+    #[serde(default)]
+    pub overlap: OverlapPolicy,
+    // a random delay in [0, jitter_ms) before each run, to spread the load of many tenants
+    #[serde(default)]
+    pub jitter_ms: u64,
+}
+
+/// what to do when a schedule fires while its previous run is still in progress
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverlapPolicy {
+    #[default]
+    Skip,
+    // wait for the previous run to finish
+    Queue,
+    // run concurrently
+    Allow,
+}
+
+/// tenant 的限流和并发配额，超过时返回 429 和 Retry-After，默认不限制
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    // every limit must have a token left for the request to be accepted
+    pub rate: Vec<RateLimitConfig>,
+    // requests of the tenant handled at the same time
+    pub max_concurrent: Option<usize>,
+}
+
+/// token bucket: `requests` tokens are refilled every `period_ms`, at most `burst` are saved
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitConfig {
+    pub requests: u32,
+    #[serde(default = "default_rate_period")]
+    pub period_ms: u64,
+    // defaults to requests
+    #[serde(default)]
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub key: RateLimitKey,
+    // the header name when keyed by header, e.g. `x-api-key`
+    #[serde(default)]
+    pub header: Option<String>,
+}
+
+/// each key has its own bucket
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitKey {
+    // the address of the client connection
+    #[default]
+    Ip,
+    Header,
+    // the handler of the matched route
+    Route,
+    // a single bucket for the whole tenant
+    Tenant,
+}
+
+/// tenant 的跨域配置，OPTIONS 预检请求由 server 直接返回，不需要在 routes 中声明
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    // `https://app.example.com`, `https://*.example.com` or `*`
+    pub allow_origins: Vec<String>,
+    // `*` allows the method or headers the preflight asks for
+    pub allow_methods: Vec<String>,
+    pub allow_headers: Vec<String>,
+    // response headers readable by the page besides the safelisted ones
+    pub expose_headers: Vec<String>,
+    // can't be used with `*` origins
+    pub allow_credentials: bool,
+    // how long the browser caches a preflight result
+    pub max_age_secs: Option<u64>,
+}
+
+/// 静态文件目录，`/assets: public` 或者 `/assets: { dir: public, max_age_secs: 86400 }`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum StaticDir {
+    Dir(PathBuf),
+    Options {
+        dir: PathBuf,
+        // without it the browser revalidates the files with their ETag on every use
+        #[serde(default)]
+        max_age_secs: Option<u64>,
+    },
+}
+
+/// js worker 相关的配置，每个 tenant 独立一份
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WorkerConfig {
+    // number of dedicated threads holding a warm JsWorker
+    pub pool_size: usize,
+    // wall-clock timeout of a request (queueing + execution), in milliseconds
+    pub timeout_ms: u64,
+    // cpu time a handler can spend executing js, in milliseconds
+    pub cpu_time_ms: u64,
+    // memory limit of each quickjs runtime, in megabytes
+    pub memory_limit_mb: usize,
+    // max stack size of each quickjs runtime, in kilobytes
+    pub max_stack_size_kb: usize,
+    // each websocket session owns a dedicated thread and JsWorker
+    pub max_websocket_sessions: usize,
+    pub fetch: FetchConfig,
+}
+
+/// 限制 handler 中 fetch() 可以访问的外部服务
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct FetchConfig {
+    // exact host or `*.example.com`, an empty list means no outbound request is allowed
+    pub allowed_hosts: Vec<String>,
 }
 
 impl ProjectConfig {
@@ -25,6 +285,215 @@ impl ProjectConfig {
         let config = serde_yml::from_str(&content)?;
         Ok(config)
     }
+
+    /// the middleware chain of a route, the global middleware first.
+    /// websocket routes don't run middleware
+    pub fn middleware_of(&self, route: &ProjectRoute) -> Vec<String> {
+        if route.kind == RouteKind::Websocket {
+            return vec![];
+        }
+        let global = self.middleware.iter();
+        global.chain(&route.middleware).cloned().collect()
+    }
+
+    /// all routes in the order of config.yml
+    pub fn route_table(&self) -> Vec<RouteInfo> {
+        let mut table = Vec::new();
+        for (path, routes) in &self.routes {
+            for route in routes {
+                let method = match route.kind {
+                    RouteKind::Http => route.method.to_string(),
+                    RouteKind::Websocket => "WEBSOCKET".to_string(),
+                };
+                let auth = route.auth.as_ref().map(|auth| match auth {
+                    AuthConfig::Jwt(_) => "jwt".to_string(),
+                    AuthConfig::ApiKey(_) => "api_key".to_string(),
+                });
+                table.push(RouteInfo {
+                    path: path.clone(),
+                    method,
+                    handler: route.handler.clone(),
+                    middleware: self.middleware_of(route),
+                    auth,
+                });
+            }
+        }
+        table
+    }
+
+    /// relative paths in config.yml are relative to the project, not the working directory
+    pub fn with_base_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref();
+        if let Some(path) = self.secrets_file.as_mut().filter(|p| p.is_relative()) {
+            *path = dir.join(&*path);
+        }
+        for static_dir in self.static_files.values_mut() {
+            static_dir.rebase(dir);
+        }
+        let routes = self.routes.values_mut().flatten();
+        for auth in routes.filter_map(|route| route.auth.as_mut()) {
+            auth.rebase(dir);
+        }
+        if let KvConfig::Sled { path } = &mut self.kv {
+            if path.is_relative() {
+                *path = dir.join(&*path);
+            }
+        }
+        self
+    }
+
+    /// read the static dirs from a copy of the project, e.g. the build output,
+    /// relative to the project like the other paths
+    pub fn with_static_root(mut self, root: impl AsRef<Path>) -> Self {
+        for static_dir in self.static_files.values_mut() {
+            static_dir.rebase(root.as_ref());
+        }
+        self
+    }
+
+    /// 在加载代码时解析出 `Dino.env` 的值，secret 优先从进程的环境变量中读取，其次是 secrets_file。
+    /// 返回的错误中只包含名字，不包含任何 secret 的值
+    pub fn resolve_env(&self) -> Result<BTreeMap<String, String>> {
+        let file: BTreeMap<String, String> = match &self.secrets_file {
+            Some(path) if !self.secrets.is_empty() => {
+                let content = fs::read_to_string(path)
+                    .map_err(|e| anyhow!("read secrets file {} failed: {e}", path.display()))?;
+                // the parse error may quote the content of the file
+                serde_yml::from_str(&content)
+                    .map_err(|_| anyhow!("invalid secrets file {}", path.display()))?
+            }
+            _ => BTreeMap::new(),
+        };
+
+        let mut env = self.env.clone();
+        for (name, source) in &self.secrets {
+            if env.contains_key(name) {
+                bail!("{name} is defined in both env and secrets");
+            }
+            let value = std::env::var(source)
+                .ok()
+                .or_else(|| file.get(source).cloned())
+                .ok_or_else(|| anyhow!("secret {name} is not set, {source} is not found"))?;
+            env.insert(name.clone(), value);
+        }
+        Ok(env)
+    }
+}
+
+fn default_rate_period() -> u64 {
+    1000
+}
+
+fn default_jwt_leeway() -> u64 {
+    60
+}
+
+fn default_api_key_header() -> String {
+    "x-api-key".to_string()
+}
+
+impl WorkerConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn cpu_time(&self) -> Duration {
+        Duration::from_millis(self.cpu_time_ms)
+    }
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            pool_size: 4,
+            timeout_ms: 30_000,
+            cpu_time_ms: 5_000,
+            memory_limit_mb: 64,
+            max_stack_size_kb: 1024,
+            max_websocket_sessions: 128,
+            fetch: FetchConfig::default(),
+        }
+    }
+}
+
+impl StaticDir {
+    pub fn dir(&self) -> &Path {
+        match self {
+            Self::Dir(dir) | Self::Options { dir, .. } => dir,
+        }
+    }
+
+    pub fn max_age(&self) -> Option<Duration> {
+        match self {
+            Self::Dir(_) => None,
+            Self::Options { max_age_secs, .. } => max_age_secs.map(Duration::from_secs),
+        }
+    }
+
+    fn rebase(&mut self, base: &Path) {
+        let (Self::Dir(dir) | Self::Options { dir, .. }) = self;
+        if dir.is_relative() {
+            *dir = base.join(&*dir);
+        }
+    }
+}
+
+impl TryFrom<AuthConfigMap> for AuthConfig {
+    type Error = String;
+
+    fn try_from(map: AuthConfigMap) -> Result<Self, Self::Error> {
+        match (map.jwt, map.api_key) {
+            (Some(jwt), None) => Ok(Self::Jwt(jwt)),
+            (None, Some(api_key)) => Ok(Self::ApiKey(api_key)),
+            _ => Err("auth requires exactly one of jwt and api_key".to_string()),
+        }
+    }
+}
+
+impl AuthConfig {
+    pub(crate) fn rebase(&mut self, base: &Path) {
+        let paths = match self {
+            Self::Jwt(jwt) => vec![jwt.key_file.as_mut(), jwt.jwks_file.as_mut()],
+            Self::ApiKey(api_key) => vec![Some(&mut api_key.keys_file)],
+        };
+        for path in paths.into_iter().flatten() {
+            if path.is_relative() {
+                *path = base.join(&*path);
+            }
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allow_origins: vec![],
+            allow_methods: vec!["*".to_string()],
+            allow_headers: vec!["*".to_string()],
+            expose_headers: vec![],
+            allow_credentials: false,
+            max_age_secs: None,
+        }
+    }
+}
+
+impl FetchConfig {
+    pub fn is_allowed(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.allowed_hosts.iter().any(|pattern| {
+            let pattern = pattern.to_lowercase();
+            match pattern.strip_prefix("*.") {
+                Some(suffix) => host
+                    .strip_suffix(suffix)
+                    .is_some_and(|prefix| prefix.ends_with('.')),
+                None => host == pattern,
+            }
+        })
+    }
+}
+
+fn default_method() -> Method {
+    Method::GET
 }
 
 fn deserialize_method<'de, D>(deserializer: D) -> Result<Method, D::Error>
@@ -45,3 +514,75 @@ where
         _ => Err(serde::de::Error::custom("invalid method")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_route_kind_should_default_to_http() {
+        let routes: Vec<ProjectRoute> = serde_yml::from_str(
+            r#"
+            - method: POST
+              handler: hello
+            - kind: websocket
+              handler: chat
+            "#,
+        )
+        .unwrap();
+        assert_eq!(routes[0].kind, RouteKind::Http);
+        assert_eq!(routes[0].method, Method::POST);
+        assert_eq!(routes[1].kind, RouteKind::Websocket);
+        assert_eq!(routes[1].method, Method::GET);
+    }
+
+    #[test]
+    fn fetch_config_is_allowed_should_work() {
+        let config = FetchConfig {
+            allowed_hosts: vec!["api.example.com".to_string(), "*.github.com".to_string()],
+        };
+        assert!(config.is_allowed("api.example.com"));
+        assert!(config.is_allowed("API.Example.com"));
+        assert!(config.is_allowed("api.github.com"));
+        assert!(config.is_allowed("a.b.github.com"));
+        assert!(!config.is_allowed("github.com"));
+        assert!(!config.is_allowed("evilgithub.com"));
+        assert!(!config.is_allowed("example.com"));
+        assert!(!FetchConfig::default().is_allowed("api.example.com"));
+    }
+
+    #[test]
+    fn project_config_resolve_env_should_work() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let secrets_file = dir.path().join(".secrets.yml");
+        fs::write(
+            &secrets_file,
+            "DB_PASSWORD: from-file\nAPI_KEY: from-file\n",
+        )?;
+        std::env::set_var("DINO_TEST_RESOLVE_ENV_API_KEY", "from-env");
+
+        let mut config: ProjectConfig = serde_yml::from_str(
+            r#"
+            name: test
+            routes: {}
+            env:
+              API_BASE: https://api.example.com
+            secrets:
+              API_KEY: DINO_TEST_RESOLVE_ENV_API_KEY
+              DB_PASSWORD: DB_PASSWORD
+            "#,
+        )?;
+        config.secrets_file = Some(secrets_file);
+        let env = config.resolve_env()?;
+        assert_eq!(env["API_BASE"], "https://api.example.com");
+        assert_eq!(env["API_KEY"], "from-env");
+        assert_eq!(env["DB_PASSWORD"], "from-file");
+
+        config
+            .secrets
+            .insert("TOKEN".to_string(), "MISSING".to_string());
+        let err = config.resolve_env().unwrap_err().to_string();
+        assert_eq!(err, "secret TOKEN is not set, MISSING is not found");
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+use std::{convert::Infallible, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use axum::{
+    extract::{Host, Request, State},
+    http::{header, HeaderName, Method},
+    middleware::Next,
+    response::Response,
+};
+use tower::{Layer, ServiceExt};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer, ExposeHeaders};
+
+use crate::{canary::selected, AppState, CorsConfig};
+
+const WILDCARD: &str = "*";
+
+/// 根据 config.yml 中的 `cors` 创建 tower-http 的 CorsLayer，和代码一起 swap。
+/// `*` 的 methods 和 headers 按请求原样返回，这样也可以和 allow_credentials 一起使用
+pub(crate) fn cors_layer(config: &CorsConfig) -> Result<CorsLayer> {
+    if config.allow_origins.is_empty() {
+        bail!("cors.allow_origins must not be empty");
+    }
+    let any_origin = config.allow_origins.iter().any(|o| o == WILDCARD);
+    let any_expose = config.expose_headers.iter().any(|h| h == WILDCARD);
+    if config.allow_credentials && (any_origin || any_expose) {
+        bail!("cors.allow_credentials can't be used with `*` origins or expose_headers");
+    }
+
+    let origin = if any_origin {
+        AllowOrigin::any()
+    } else {
+        let patterns = config.allow_origins.clone();
+        for pattern in &patterns {
+            if pattern.matches(WILDCARD).count() > 1 {
+                bail!("invalid cors origin {pattern:?}, only one `*` is allowed");
+            }
+        }
+        AllowOrigin::predicate(move |origin, _| {
+            let origin = origin.to_str().unwrap_or_default();
+            patterns.iter().any(|p| origin_matches(p, origin))
+        })
+    };
+
+    let methods = if config.allow_methods.iter().any(|m| m == WILDCARD) {
+        AllowMethods::mirror_request()
+    } else {
+        let methods = config
+            .allow_methods
+            .iter()
+            .map(|m| Method::from_bytes(m.to_ascii_uppercase().as_bytes()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("invalid cors method: {e}"))?;
+        AllowMethods::list(methods)
+    };
+
+    let headers = if config.allow_headers.iter().any(|h| h == WILDCARD) {
+        AllowHeaders::mirror_request()
+    } else {
+        AllowHeaders::list(header_names(&config.allow_headers)?)
+    };
+
+    let expose = if any_expose {
+        ExposeHeaders::any()
+    } else {
+        ExposeHeaders::list(header_names(&config.expose_headers)?)
+    };
+
+    let mut layer = CorsLayer::new()
+        .allow_origin(origin)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .expose_headers(expose)
+        .allow_credentials(config.allow_credentials);
+    if let Some(secs) = config.max_age_secs {
+        layer = layer.max_age(Duration::from_secs(secs));
+    }
+    Ok(layer)
+}
+
+/// 按 tenant 的配置处理跨域请求，OPTIONS 预检请求直接返回，不需要在 routes 中声明
+pub(crate) async fn cors(
+    State(state): State<AppState>,
+    Host(host): Host,
+    req: Request,
+    next: Next,
+) -> Result<Response, Infallible> {
+    let Ok((_, tenant, _)) = state.lookup(&host, req.uri().path()) else {
+        return Ok(next.run(req).await);
+    };
+    // the cors of the version serving the request, a canary may have changed it
+    let layer = selected(&req, &tenant).cors.clone();
+    // every OPTIONS request is a preflight for the layer, leave the others to the routes
+    let preflight = req
+        .headers()
+        .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    match layer {
+        Some(layer) if preflight || req.method() != Method::OPTIONS => {
+            layer.layer(next).oneshot(req).await
+        }
+        _ => Ok(next.run(req).await),
+    }
+}
+
+// `https://*.example.com` matches any subdomain of example.com, not example.com itself
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    match pattern.split_once(WILDCARD) {
+        Some((prefix, suffix)) => {
+            let Some(rest) = origin.strip_prefix(prefix) else {
+                return false;
+            };
+            let Some(sub) = rest.strip_suffix(suffix) else {
+                return false;
+            };
+            !sub.is_empty()
+                && sub
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        }
+        None => pattern.eq_ignore_ascii_case(origin),
+    }
+}
+
+fn header_names(names: &[String]) -> Result<Vec<HeaderName>> {
+    names
+        .iter()
+        .map(|name| HeaderName::from_bytes(name.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("invalid cors header: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use dashmap::DashMap;
+
+    use super::*;
+    use crate::{tenant_router, ProjectConfig, SwappableAppRouter};
+
+    fn config(yaml: &str) -> Result<CorsConfig> {
+        Ok(serde_yml::from_str(yaml)?)
+    }
+
+    #[test]
+    fn origin_matches_should_support_wildcard() {
+        assert!(origin_matches(
+            "https://app.example.com",
+            "https://APP.example.com"
+        ));
+        assert!(origin_matches(
+            "https://*.example.com",
+            "https://a.b.example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "http://a.example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://a.example.com.evil.io"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://a/b.example.com"
+        ));
+    }
+
+    #[test]
+    fn cors_layer_should_reject_invalid_config() -> Result<()> {
+        assert!(cors_layer(&config("allow_origins: []")?).is_err());
+        assert!(cors_layer(&config(
+            "{ allow_origins: ['*'], allow_credentials: true }"
+        )?)
+        .is_err());
+        assert!(cors_layer(&config("allow_origins: ['https://*.*.example.com']")?).is_err());
+        assert!(cors_layer(&config("{ allow_origins: ['*'], allow_headers: ['a b'] }")?).is_err());
+        let config =
+            config("{ allow_origins: ['https://*.example.com'], allow_credentials: true }")?;
+        assert!(cors_layer(&config).is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tenant_router_should_answer_preflight() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function hello1(req){
+                return { status: 200, headers: {}, body: "hello" };
+            }
+            return{hello1:hello1};
+        })()"#;
+        let mut config: ProjectConfig =
+            serde_yml::from_str(include_str!("../fixtures/config.yml"))?;
+        config.cors = Some(serde_yml::from_str(
+            "{ allow_origins: ['https://*.example.com'], max_age_secs: 600 }",
+        )?);
+        let routers = DashMap::new();
+        routers.insert(
+            "example.com".to_string(),
+            SwappableAppRouter::try_new(code, config)?,
+        );
+        let app = tenant_router(AppState::new(routers));
+
+        // no OPTIONS route is declared for /api/hello/:id
+        let req = Request::options("/api/hello/1")
+            .header(header::HOST, "example.com")
+            .header(header::ORIGIN, "https://app.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let headers = res.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "PUT");
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        let req = Request::get("/api/hello/1")
+            .header(header::HOST, "example.com")
+            .header(header::ORIGIN, "https://other.org")
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tenant_router_should_use_cors_of_canary() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function hello1(req){
+                return { status: 200, headers: {}, body: "hello" };
+            }
+            return{hello1:hello1};
+        })()"#;
+        let config: ProjectConfig = serde_yml::from_str(include_str!("../fixtures/config.yml"))?;
+        let router = SwappableAppRouter::try_new(code, config.clone())?;
+        let mut canary = config;
+        canary.cors = Some(serde_yml::from_str(
+            "allow_origins: ['https://app.example.com']",
+        )?);
+        router.set_canary(code, canary, 100, None)?;
+        let routers = DashMap::new();
+        routers.insert("example.com".to_string(), router);
+        let app = tenant_router(AppState::new(routers));
+
+        let req = Request::get("/api/hello/1")
+            .header(header::HOST, "example.com")
+            .header(header::ORIGIN, "https://app.example.com")
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        Ok(())
+    }
+}
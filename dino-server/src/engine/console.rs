@@ -0,0 +1,67 @@
+use std::{cell::RefCell, rc::Rc};
+
+use rquickjs::{Ctx, Function};
+use tracing::{event, Level};
+
+const CONSOLE_JS: &str = include_str!("js/console.js");
+
+/// 把 handler 中的 console.* 输出到 tracing，并带上 tenant / handler / request id
+pub(crate) struct Console {
+    tenant: String,
+    // (handler, request id) of the current execution
+    scope: RefCell<(String, String)>,
+}
+
+macro_rules! console_event {
+    ($level:expr, $console:expr, $msg:expr) => {{
+        let scope = $console.scope.borrow();
+        let (handler, request_id) = &*scope;
+        event!(
+            target: "dino::console",
+            $level,
+            tenant = %$console.tenant,
+            handler = %handler,
+            request_id = %request_id,
+            "{}",
+            $msg
+        )
+    }};
+}
+
+impl Console {
+    pub(crate) fn new(tenant: impl Into<String>) -> Rc<Self> {
+        Rc::new(Self {
+            tenant: tenant.into(),
+            scope: RefCell::new(Default::default()),
+        })
+    }
+
+    /// install `console` into the globals of the context
+    pub(crate) fn install(self: &Rc<Self>, ctx: &Ctx<'_>) -> rquickjs::Result<()> {
+        let console = self.clone();
+        let log = Function::new(ctx.clone(), move |level: String, msg: String| {
+            console.log(&level, &msg)
+        })?
+        .with_name("log")?;
+        let install: Function = ctx.eval(CONSOLE_JS)?;
+        install.call((log,))
+    }
+
+    pub(crate) fn enter(&self, handler: impl Into<String>, request_id: impl Into<String>) {
+        *self.scope.borrow_mut() = (handler.into(), request_id.into());
+    }
+
+    pub(crate) fn exit(&self) {
+        *self.scope.borrow_mut() = Default::default();
+    }
+
+    fn log(&self, level: &str, msg: &str) {
+        match level {
+            "error" => console_event!(Level::ERROR, self, msg),
+            "warn" => console_event!(Level::WARN, self, msg),
+            "debug" => console_event!(Level::DEBUG, self, msg),
+            "trace" => console_event!(Level::TRACE, self, msg),
+            _ => console_event!(Level::INFO, self, msg),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use rquickjs::{Ctx, Exception, Function, Object};
+
+use crate::{KvStore, MemoryKv};
+
+const DINO_JS: &str = include_str!("js/dino.js");
+
+/// 通过 `Dino` 全局对象暴露给 handler 的宿主能力，同一个 tenant 的所有 worker 共享
+#[derive(Clone)]
+pub struct Bindings {
+    pub kv: Arc<dyn KvStore>,
+    // resolved `env` and `secrets` of the config, read-only in js
+    pub env: Arc<BTreeMap<String, String>>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self {
+            kv: Arc::new(MemoryKv::default()),
+            env: Arc::default(),
+        }
+    }
+}
+
+/// install `Dino` into the globals of the context
+pub(crate) fn install<'js>(ctx: &Ctx<'js>, bindings: &Bindings) -> rquickjs::Result<()> {
+    let kv = Object::new(ctx.clone())?;
+
+    let store = bindings.kv.clone();
+    let get = Function::new(ctx.clone(), move |ctx: Ctx<'js>, key: String| {
+        store.get(&key).map_err(|e| kv_error(&ctx, e))
+    })?;
+    kv.set("get", get.with_name("get")?)?;
+
+    let store = bindings.kv.clone();
+    let put = Function::new(
+        ctx.clone(),
+        move |ctx: Ctx<'js>, key: String, value: String, ttl_ms: Option<f64>| {
+            let ttl = ttl_ms.map(|ms| Duration::from_millis(ms.max(0.0) as u64));
+            store.put(&key, value, ttl).map_err(|e| kv_error(&ctx, e))
+        },
+    )?;
+    kv.set("put", put.with_name("put")?)?;
+
+    let store = bindings.kv.clone();
+    let delete = Function::new(ctx.clone(), move |ctx: Ctx<'js>, key: String| {
+        store.delete(&key).map_err(|e| kv_error(&ctx, e))
+    })?;
+    kv.set("delete", delete.with_name("delete")?)?;
+
+    let store = bindings.kv.clone();
+    let list = Function::new(
+        ctx.clone(),
+        move |ctx: Ctx<'js>, prefix: String, limit: u32| {
+            store
+                .list(&prefix, limit as usize)
+                .map_err(|e| kv_error(&ctx, e))
+        },
+    )?;
+    kv.set("list", list.with_name("list")?)?;
+
+    let env = Object::new(ctx.clone())?;
+    for (name, value) in bindings.env.iter() {
+        env.set(name.as_str(), value.as_str())?;
+    }
+
+    let install: Function = ctx.eval(DINO_JS)?;
+    install.call((kv, env))
+}
+
+fn kv_error(ctx: &Ctx<'_>, e: anyhow::Error) -> rquickjs::Error {
+    Exception::throw_internal(ctx, &format!("kv error: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{JsBody, JsWorker, Req, WorkerConfig};
+
+    #[test]
+    fn dino_kv_should_be_shared_by_workers() -> anyhow::Result<()> {
+        let code = r#"
+        (function(){
+            async function incr(req){
+                const count = (await Dino.kv.get("count", "json")) ?? 0;
+                await Dino.kv.put("count", count + 1);
+                await Dino.kv.put(`visit:${count}`, { at: count }, { ttl: 60 });
+                const keys = await Dino.kv.list("visit:", { limit: 10 });
+                return { status: 200, headers: {}, body: `${count + 1} ${keys.join(",")}` };
+            }
+            return{incr:incr};
+        })()"#;
+        let bindings = Bindings::default();
+        let config = WorkerConfig::default();
+        let w1 = JsWorker::try_new_with_bindings(code, "test", &config, &bindings)?;
+        let w2 = JsWorker::try_new_with_bindings(code, "test", &config, &bindings)?;
+
+        let req = || Req::<JsBody>::builder().method("GET").url("/").build();
+        let ret = w1.run("incr", req())?;
+        assert_eq!(ret.body, Some("1 visit:0".into()));
+        let ret = w2.run("incr", req())?;
+        assert_eq!(ret.body, Some("2 visit:0,visit:1".into()));
+        assert_eq!(bindings.kv.get("visit:1")?.as_deref(), Some(r#"{"at":1}"#));
+        Ok(())
+    }
+
+    #[test]
+    fn dino_env_should_be_read_only() -> anyhow::Result<()> {
+        let code = r#"
+        (function(){
+            async function env(req){
+                "use strict";
+                let error = "";
+                try { Dino.env.API_KEY = "changed"; } catch (e) { error = e.name; }
+                return { status: 200, headers: {}, body: `${Dino.env.API_KEY} ${error}` };
+            }
+            return{env:env};
+        })()"#;
+        let bindings = Bindings {
+            env: Arc::new(BTreeMap::from([(
+                "API_KEY".to_string(),
+                "s3cr3t".to_string(),
+            )])),
+            ..Default::default()
+        };
+        let config = WorkerConfig::default();
+        let worker = JsWorker::try_new_with_bindings(code, "test", &config, &bindings)?;
+
+        let req = Req::<JsBody>::builder().method("GET").url("/").build();
+        let ret = worker.run("env", req)?;
+        assert_eq!(ret.body, Some("s3cr3t TypeError".into()));
+        Ok(())
+    }
+}
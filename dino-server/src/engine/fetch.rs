@@ -0,0 +1,205 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+    time::Instant,
+};
+
+use anyhow::Result;
+use dino_macros::IntoJs;
+use reqwest::redirect;
+use rquickjs::{Ctx, Exception, Function, Object, Persistent, Promise};
+use tokio::{runtime::Runtime, sync::mpsc};
+
+use super::JsBody;
+use crate::FetchConfig;
+
+const FETCH_JS: &str = include_str!("js/fetch.js");
+// same as the default policy of reqwest
+const MAX_REDIRECTS: usize = 10;
+
+/// fetch() 的 rust 实现：请求在 worker 自己的 tokio runtime 中执行，
+/// 完成后由 JsWorker 的事件循环 resolve 对应的 promise
+pub(crate) struct Fetcher {
+    config: FetchConfig,
+    client: reqwest::Client,
+    io: Runtime,
+    next_id: Cell<u64>,
+    pending: RefCell<HashMap<u64, Persistent<Function<'static>>>>,
+    sender: mpsc::UnboundedSender<(u64, RawResponse)>,
+    receiver: RefCell<mpsc::UnboundedReceiver<(u64, RawResponse)>>,
+}
+
+#[derive(Debug, Default, IntoJs)]
+struct RawResponse {
+    status: u16,
+    url: String,
+    headers: HashMap<String, String>,
+    body: Option<JsBody>,
+    error: Option<String>,
+}
+
+impl Fetcher {
+    pub(crate) fn try_new(config: FetchConfig) -> Result<Rc<Self>> {
+        let io = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        // every hop of a redirect must be allowed too, an allowed host could otherwise send
+        // the request to any address, e.g. an internal service
+        let allowed = config.clone();
+        let policy = redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+            let url = attempt.url();
+            let host = url.host_str().unwrap_or_default();
+            if !matches!(url.scheme(), "http" | "https") || !allowed.is_allowed(host) {
+                let msg = format!("redirect to {host} is not allowed");
+                return attempt.error(msg);
+            }
+            attempt.follow()
+        });
+        let client = reqwest::Client::builder().redirect(policy).build()?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Ok(Rc::new(Self {
+            config,
+            client,
+            io,
+            next_id: Cell::new(0),
+            pending: RefCell::new(HashMap::new()),
+            sender,
+            receiver: RefCell::new(receiver),
+        }))
+    }
+
+    /// install `fetch` into the globals of the context
+    pub(crate) fn install<'js>(self: &Rc<Self>, ctx: &Ctx<'js>) -> rquickjs::Result<()> {
+        let fetcher = self.clone();
+        let raw_fetch = Function::new(
+            ctx.clone(),
+            move |ctx: Ctx<'js>,
+                  url: String,
+                  method: String,
+                  headers: HashMap<String, String>,
+                  body: Option<JsBody>| {
+                fetcher.start(&ctx, url, method, headers, body)
+            },
+        )?
+        .with_name("rawFetch")?;
+        let install: Function = ctx.eval(FETCH_JS)?;
+        install.call((raw_fetch,))
+    }
+
+    pub(crate) fn has_pending(&self) -> bool {
+        !self.pending.borrow().is_empty()
+    }
+
+    /// block until one of the outstanding requests finishes and resolve its promise,
+    /// return false if the deadline is reached first
+    pub(crate) fn wait<'js>(
+        &self,
+        ctx: &Ctx<'js>,
+        deadline: Option<Instant>,
+    ) -> rquickjs::Result<bool> {
+        let next = {
+            let mut receiver = self.receiver.borrow_mut();
+            self.io.block_on(async {
+                match deadline {
+                    Some(deadline) => tokio::time::timeout_at(deadline.into(), receiver.recv())
+                        .await
+                        .ok()
+                        .flatten(),
+                    None => receiver.recv().await,
+                }
+            })
+        };
+        let Some((id, res)) = next else {
+            return Ok(false);
+        };
+        // responses of requests started by an earlier run are ignored
+        let resolve = self.pending.borrow_mut().remove(&id);
+        if let Some(resolve) = resolve {
+            resolve.restore(ctx)?.call::<_, ()>((res,))?;
+        }
+        Ok(true)
+    }
+
+    /// drop the promises of requests which were not awaited by the handler
+    pub(crate) fn clear(&self) {
+        self.pending.borrow_mut().clear();
+    }
+
+    fn start<'js>(
+        &self,
+        ctx: &Ctx<'js>,
+        url: String,
+        method: String,
+        headers: HashMap<String, String>,
+        body: Option<JsBody>,
+    ) -> rquickjs::Result<Promise<'js>> {
+        let url = reqwest::Url::parse(&url)
+            .map_err(|e| Exception::throw_type(ctx, &format!("invalid url {url}: {e}")))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            let msg = format!("unsupported scheme: {}", url.scheme());
+            return Err(Exception::throw_type(ctx, &msg));
+        }
+        let host = url.host_str().unwrap_or_default();
+        if !self.config.is_allowed(host) {
+            let msg = format!("fetch to {host} is not allowed");
+            return Err(Exception::throw_type(ctx, &msg));
+        }
+        let method = reqwest::Method::from_bytes(method.as_bytes())
+            .map_err(|e| Exception::throw_type(ctx, &format!("invalid method: {e}")))?;
+
+        let (promise, resolve, _) = ctx.promise()?;
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.pending
+            .borrow_mut()
+            .insert(id, Persistent::save(ctx, resolve));
+
+        let mut builder = self.client.request(method, url);
+        for (k, v) in headers {
+            builder = builder.header(k, v);
+        }
+        if let Some(body) = body {
+            builder = builder.body(body.into_bytes());
+        }
+        let sender = self.sender.clone();
+        self.io.spawn(async move {
+            // with the causes, e.g. why a redirect was refused
+            let res = send_request(builder).await.unwrap_or_else(|e| RawResponse {
+                error: Some(format!("{e:#}")),
+                ..Default::default()
+            });
+            let _ = sender.send((id, res));
+        });
+        Ok(promise)
+    }
+}
+
+async fn send_request(builder: reqwest::RequestBuilder) -> Result<RawResponse> {
+    let res = builder.send().await?;
+    let status = res.status().as_u16();
+    let url = res.url().to_string();
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for (k, v) in res.headers() {
+        let v = v.to_str().unwrap_or_default();
+        headers
+            .entry(k.to_string())
+            .and_modify(|old| {
+                old.push_str(", ");
+                old.push_str(v);
+            })
+            .or_insert_with(|| v.to_string());
+    }
+    // always raw bytes, decoding is left to text() / json() on the js side
+    let body = res.bytes().await?;
+    Ok(RawResponse {
+        status,
+        url,
+        headers,
+        body: Some(JsBody::Binary(body.to_vec())),
+        error: None,
+    })
+}
@@ -0,0 +1,156 @@
+use axum::body::Body;
+use rquickjs::{Ctx, Error, Function, IntoJs, TypedArray, Value};
+
+const HTTP_JS: &str = include_str!("js/http.js");
+
+/// http body 在 js 中的表示：文本为 string，其它内容为 Uint8Array
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsBody {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl JsBody {
+    /// textual content types (or no content type at all) with valid utf-8 are passed as string
+    pub fn new(content_type: Option<&str>, bytes: impl Into<Vec<u8>>) -> Self {
+        let bytes = bytes.into();
+        if !content_type.map(is_text_content).unwrap_or(true) {
+            return Self::Binary(bytes);
+        }
+        match String::from_utf8(bytes) {
+            Ok(s) => Self::Text(s),
+            Err(e) => Self::Binary(e.into_bytes()),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Text(s) => s.as_bytes(),
+            Self::Binary(b) => b,
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Self::Text(s) => s.into_bytes(),
+            Self::Binary(b) => b,
+        }
+    }
+}
+
+fn is_text_content(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/xml"
+                | "application/javascript"
+                | "application/x-www-form-urlencoded"
+                | "application/graphql"
+        )
+}
+
+impl From<String> for JsBody {
+    fn from(s: String) -> Self {
+        Self::Text(s)
+    }
+}
+
+impl From<&str> for JsBody {
+    fn from(s: &str) -> Self {
+        Self::Text(s.to_string())
+    }
+}
+
+impl From<Vec<u8>> for JsBody {
+    fn from(b: Vec<u8>) -> Self {
+        Self::Binary(b)
+    }
+}
+
+impl From<JsBody> for Body {
+    fn from(body: JsBody) -> Self {
+        match body {
+            JsBody::Text(s) => s.into(),
+            JsBody::Binary(b) => b.into(),
+        }
+    }
+}
+
+impl<'js> IntoJs<'js> for JsBody {
+    fn into_js(self, ctx: &Ctx<'js>) -> rquickjs::Result<Value<'js>> {
+        match self {
+            JsBody::Text(s) => s.into_js(ctx),
+            JsBody::Binary(b) => TypedArray::<u8>::new(ctx.clone(), b)?.into_js(ctx),
+        }
+    }
+}
+
+impl<'js> rquickjs::FromJs<'js> for JsBody {
+    fn from_js(_ctx: &Ctx<'js>, value: Value<'js>) -> rquickjs::Result<Self> {
+        if let Some(s) = value.as_string() {
+            return Ok(Self::Text(s.to_string()?));
+        }
+        if let Some(obj) = value.as_object() {
+            if let Some(arr) = obj.as_typed_array::<u8>() {
+                let bytes = arr.as_bytes().unwrap_or_default();
+                return Ok(Self::Binary(bytes.to_vec()));
+            }
+            if let Some(buf) = obj.as_array_buffer() {
+                let bytes = buf.as_bytes().unwrap_or_default();
+                return Ok(Self::Binary(bytes.to_vec()));
+            }
+        }
+        Err(Error::new_from_js_message(
+            value.type_name(),
+            "JsBody",
+            "body must be a string, ArrayBuffer or Uint8Array",
+        ))
+    }
+}
+
+/// install TextEncoder / TextDecoder and the hidden `__dino` body helpers
+pub(crate) fn install(ctx: &Ctx<'_>) -> rquickjs::Result<()> {
+    let encode = Function::new(ctx.clone(), |s: String| JsBody::Binary(s.into_bytes()))?
+        .with_name("encodeUtf8")?;
+    let decode = Function::new(ctx.clone(), |body: JsBody| match body {
+        JsBody::Text(s) => s,
+        JsBody::Binary(b) => String::from_utf8_lossy(&b).into_owned(),
+    })?
+    .with_name("decodeUtf8")?;
+    let install: Function = ctx.eval(HTTP_JS)?;
+    install.call((encode, decode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn js_body_new_should_detect_text() {
+        assert_eq!(
+            JsBody::new(Some("application/json; charset=utf-8"), "{}"),
+            JsBody::Text("{}".to_string())
+        );
+        assert_eq!(
+            JsBody::new(None, "hello"),
+            JsBody::Text("hello".to_string())
+        );
+        assert_eq!(
+            JsBody::new(Some("image/png"), "png"),
+            JsBody::Binary(b"png".to_vec())
+        );
+        assert_eq!(
+            JsBody::new(Some("text/plain"), vec![0xff, 0xfe]),
+            JsBody::Binary(vec![0xff, 0xfe])
+        );
+    }
+}
@@ -0,0 +1,100 @@
+// browser style `console` built on top of the `log(level, message)` host function.
+(function (log) {
+  const MAX_DEPTH = 2;
+  const IDENT = /^[A-Za-z_$][A-Za-z0-9_$]*$/;
+
+  function formatKey(key) {
+    if (typeof key === "symbol") return `[${key.toString()}]`;
+    return IDENT.test(key) ? key : `'${key}'`;
+  }
+
+  function formatList(open, items, close) {
+    return items.length === 0 ? `${open}${close}` : `${open} ${items.join(", ")} ${close}`;
+  }
+
+  function formatValue(value, depth, seen) {
+    switch (typeof value) {
+      case "string":
+        return depth === 0 ? value : `'${value}'`;
+      case "bigint":
+        return `${value}n`;
+      case "symbol":
+        return value.toString();
+      case "function":
+        return value.name ? `[Function: ${value.name}]` : "[Function (anonymous)]";
+      case "object":
+        break;
+      default:
+        return String(value);
+    }
+
+    if (value === null) return "null";
+    if (value instanceof Error) return value.stack ? `${value}\n${value.stack}` : String(value);
+    if (value instanceof Date) return value.toISOString();
+    if (value instanceof RegExp) return value.toString();
+    if (seen.includes(value)) return "[Circular]";
+
+    const name = value.constructor && value.constructor.name;
+    if (depth > MAX_DEPTH) return Array.isArray(value) ? "[Array]" : `[${name || "Object"}]`;
+
+    seen = seen.concat([value]);
+    const format = (v) => formatValue(v, depth + 1, seen);
+    if (Array.isArray(value)) return formatList("[", value.map(format), "]");
+    if (value instanceof Map) {
+      const items = Array.from(value, ([k, v]) => `${format(k)} => ${format(v)}`);
+      return formatList(`Map(${value.size}) {`, items, "}");
+    }
+    if (value instanceof Set) {
+      return formatList(`Set(${value.size}) {`, Array.from(value, format), "}");
+    }
+    if (ArrayBuffer.isView(value)) {
+      return formatList(`${name}(${value.length}) [`, Array.from(value, format), "]");
+    }
+
+    const items = Reflect.ownKeys(value)
+      .filter((k) => Object.prototype.propertyIsEnumerable.call(value, k))
+      .map((k) => `${formatKey(k)}: ${format(value[k])}`);
+    const prefix = name && name !== "Object" ? `${name} ` : "";
+    return prefix + formatList("{", items, "}");
+  }
+
+  // supports the %s %d %i %f %o %O %j %c substitutions of the first argument
+  function format(args) {
+    let rest = args;
+    let head = "";
+    if (typeof args[0] === "string" && args[0].includes("%")) {
+      let i = 1;
+      head = args[0].replace(/%([sdifoOjc%])/g, (m, c) => {
+        if (c === "%") return "%";
+        if (i >= args.length) return m;
+        const v = args[i++];
+        switch (c) {
+          case "s":
+            return typeof v === "string" ? v : formatValue(v, 1, []);
+          case "d":
+          case "i":
+            return String(c === "i" ? parseInt(v) : Number(v));
+          case "f":
+            return String(parseFloat(v));
+          case "j":
+            return JSON.stringify(v);
+          case "c":
+            return "";
+          default:
+            return formatValue(v, 1, []);
+        }
+      });
+      rest = args.slice(i);
+      if (rest.length === 0) return head;
+      head += " ";
+    }
+    return head + rest.map((v) => formatValue(v, 0, [])).join(" ");
+  }
+
+  const console = {};
+  for (const level of ["debug", "info", "warn", "error", "trace"]) {
+    console[level] = (...args) => log(level, format(args));
+  }
+  console.log = console.info;
+  globalThis.console = console;
+})
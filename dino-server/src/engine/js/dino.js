@@ -0,0 +1,32 @@
+// the `Dino` namespace with the host apis of dino-server, e.g. `await Dino.kv.get("counter")`.
+(function (kv, env) {
+  const kvNamespace = Object.freeze({
+    // options may be "json" or {type: "json"} to parse the stored value
+    async get(key, options = {}) {
+      // a missing key comes back from rust as undefined, handlers get null like workers kv
+      const value = kv.get(String(key)) ?? null;
+      const type = typeof options === "string" ? options : options.type;
+      return value !== null && type === "json" ? JSON.parse(value) : value;
+    },
+
+    // options.ttl is in seconds, non-string values are stored as json
+    async put(key, value, options = {}) {
+      const ttl = options.ttl === undefined ? undefined : Number(options.ttl) * 1000;
+      kv.put(String(key), typeof value === "string" ? value : JSON.stringify(value), ttl);
+    },
+
+    async delete(key) {
+      kv.delete(String(key));
+    },
+
+    async list(prefix = "", options = {}) {
+      return kv.list(String(prefix), options.limit ?? 1000);
+    },
+  });
+
+  Object.defineProperty(globalThis, "Dino", {
+    // env holds resolved secrets, it can be read but not changed by handlers
+    value: Object.freeze({ kv: kvNamespace, env: Object.freeze(env) }),
+    enumerable: false,
+  });
+})
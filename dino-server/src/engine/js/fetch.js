@@ -0,0 +1,12 @@
+// WHATWG style `fetch` built on top of the `rawFetch` host function provided by dino-server.
+// rawFetch(url, method, headers, body) resolves to {status, url, headers, body} or {error}.
+(function (rawFetch) {
+  globalThis.fetch = async function fetch(input, init = {}) {
+    const req = new Request(input instanceof Request ? input : String(input.url ?? input), init);
+    const raw = await rawFetch(req.url, req.method, Object.fromEntries(req.headers), req.body);
+    if (raw.error) throw new TypeError(`fetch failed: ${raw.error}`);
+    const res = new Response(raw.body, { status: raw.status, headers: raw.headers });
+    res.url = raw.url;
+    return res;
+  };
+})
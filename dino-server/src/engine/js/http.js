@@ -0,0 +1,444 @@
+// Fetch API style `Headers` / `Request` / `Response` / `ReadableStream` and
+// TextEncoder / TextDecoder, built on top of the utf-8 host functions.
+(function (encodeUtf8, decodeUtf8) {
+  function toBytes(body) {
+    if (body instanceof Uint8Array) return body;
+    if (body instanceof ArrayBuffer) return new Uint8Array(body);
+    if (ArrayBuffer.isView(body)) {
+      return new Uint8Array(body.buffer, body.byteOffset, body.byteLength);
+    }
+    return encodeUtf8(String(body));
+  }
+
+  function isBinary(body) {
+    return body instanceof ArrayBuffer || ArrayBuffer.isView(body);
+  }
+
+  function isStream(body) {
+    if (body instanceof ReadableStream) return true;
+    return body !== null && typeof body === "object" && Symbol.asyncIterator in body;
+  }
+
+  // bodies are kept as string or Uint8Array, which is what rust understands, streams are
+  // consumed chunk by chunk by rust after the handler returns
+  function normalizeBody(body) {
+    if (body === undefined || body === null) return null;
+    if (typeof body === "string" || isStream(body)) return body;
+    return isBinary(body) ? toBytes(body) : String(body);
+  }
+
+  // a minimal ReadableStream: enough for `new ReadableStream({start, pull, cancel})`,
+  // `getReader()` and `for await (const chunk of stream)`
+  class ReadableStream {
+    constructor(source = {}) {
+      this._source = source;
+      this._queue = [];
+      this._waiting = null;
+      this._done = false;
+      this._error = null;
+      const controller = {
+        enqueue: (chunk) => this._push({ value: chunk, done: false }),
+        close: () => this._push({ value: undefined, done: true }),
+        error: (e) => this._push({ error: e ?? new TypeError("stream errored") }),
+      };
+      this._controller = controller;
+      this._started = Promise.resolve(source.start && source.start(controller));
+    }
+
+    _push(item) {
+      if (this._done) return;
+      if (item.error !== undefined) {
+        this._done = true;
+        this._error = item.error;
+      } else if (item.done) {
+        this._done = true;
+      } else {
+        this._queue.push(item);
+      }
+      if (this._waiting) {
+        const resolve = this._waiting;
+        this._waiting = null;
+        resolve();
+      }
+    }
+
+    async _read() {
+      await this._started;
+      while (this._queue.length === 0 && !this._done) {
+        const wait = new Promise((resolve) => (this._waiting = resolve));
+        if (this._source.pull) await this._source.pull(this._controller);
+        if (this._queue.length === 0 && !this._done) await wait;
+      }
+      if (this._queue.length > 0) return this._queue.shift();
+      if (this._error !== null) throw this._error;
+      return { value: undefined, done: true };
+    }
+
+    async cancel(reason) {
+      this._done = true;
+      this._queue = [];
+      if (this._source.cancel) await this._source.cancel(reason);
+    }
+
+    getReader() {
+      return {
+        read: () => this._read(),
+        cancel: (reason) => this.cancel(reason),
+        releaseLock() {},
+      };
+    }
+
+    [Symbol.asyncIterator]() {
+      return {
+        next: () => this._read(),
+        return: async () => {
+          await this.cancel();
+          return { value: undefined, done: true };
+        },
+        [Symbol.asyncIterator]() {
+          return this;
+        },
+      };
+    }
+  }
+
+  // `data` may be any json value, strings are sent as is
+  function formatEvent(ev) {
+    if (typeof ev !== "object" || ev === null) ev = { data: ev };
+    let out = "";
+    if (ev.event !== undefined) out += `event: ${ev.event}\n`;
+    if (ev.id !== undefined) out += `id: ${ev.id}\n`;
+    if (ev.retry !== undefined) out += `retry: ${ev.retry}\n`;
+    const data = typeof ev.data === "string" ? ev.data : JSON.stringify(ev.data ?? "");
+    for (const line of data.split("\n")) out += `data: ${line}\n`;
+    return out + "\n";
+  }
+
+  // read a streamed body into a single Uint8Array
+  async function consume(body) {
+    if (!isStream(body)) return body;
+    const chunks = [];
+    for await (const chunk of body) chunks.push(toBytes(chunk));
+    const ret = new Uint8Array(chunks.reduce((n, c) => n + c.length, 0));
+    let offset = 0;
+    for (const chunk of chunks) {
+      ret.set(chunk, offset);
+      offset += chunk.length;
+    }
+    return ret;
+  }
+
+  async function* eventStream(events) {
+    for await (const ev of events) yield formatEvent(ev);
+  }
+
+  class TextEncoder {
+    get encoding() {
+      return "utf-8";
+    }
+
+    encode(input = "") {
+      return encodeUtf8(String(input));
+    }
+  }
+
+  class TextDecoder {
+    get encoding() {
+      return "utf-8";
+    }
+
+    decode(input) {
+      return input === undefined ? "" : decodeUtf8(toBytes(input));
+    }
+  }
+
+  // header values live in a Map outside the instance, so that client controlled names like
+  // `get` or `delete` can't replace the methods. a Proxy keeps `headers["content-type"]`,
+  // assignment and `{ ...headers }` of the old plain `{name: value}` contract working
+  const headerMaps = new WeakMap();
+
+  function headerName(key) {
+    return typeof key === "string" && !(key in Headers.prototype) ? key.toLowerCase() : null;
+  }
+
+  const headersProxy = {
+    get(target, key, receiver) {
+      const name = headerName(key);
+      if (name === null) return Reflect.get(target, key, receiver);
+      return headerMaps.get(target).get(name);
+    },
+    set(target, key, value, receiver) {
+      const name = headerName(key);
+      if (name === null) return Reflect.set(target, key, value, receiver);
+      headerMaps.get(target).set(name, String(value));
+      return true;
+    },
+    has(target, key) {
+      const name = headerName(key);
+      return name === null ? key in target : headerMaps.get(target).has(name);
+    },
+    deleteProperty(target, key) {
+      const name = headerName(key);
+      if (name === null) return Reflect.deleteProperty(target, key);
+      headerMaps.get(target).delete(name);
+      return true;
+    },
+    // names taken by the methods are only reachable through get() and entries()
+    ownKeys(target) {
+      return [...headerMaps.get(target).keys()].filter((k) => headerName(k) !== null);
+    },
+    getOwnPropertyDescriptor(target, key) {
+      const map = headerMaps.get(target);
+      if (headerName(key) === null || !map.has(key)) return undefined;
+      return { value: map.get(key), writable: true, enumerable: true, configurable: true };
+    },
+  };
+
+  class Headers {
+    constructor(init) {
+      const map = new Map();
+      const proxy = new Proxy(this, headersProxy);
+      headerMaps.set(this, map);
+      headerMaps.set(proxy, map);
+      if (init) {
+        const entries = Array.isArray(init)
+          ? init
+          : init instanceof Headers
+            ? Array.from(init.entries())
+            : Object.entries(init);
+        for (const [k, v] of entries) proxy.append(k, v);
+      }
+      return proxy;
+    }
+
+    get(name) {
+      return headerMaps.get(this).get(String(name).toLowerCase()) ?? null;
+    }
+
+    has(name) {
+      return headerMaps.get(this).has(String(name).toLowerCase());
+    }
+
+    set(name, value) {
+      headerMaps.get(this).set(String(name).toLowerCase(), String(value));
+    }
+
+    append(name, value) {
+      const old = this.get(name);
+      this.set(name, old === null ? value : `${old}, ${value}`);
+    }
+
+    delete(name) {
+      headerMaps.get(this).delete(String(name).toLowerCase());
+    }
+
+    keys() {
+      return headerMaps.get(this).keys();
+    }
+
+    values() {
+      return headerMaps.get(this).values();
+    }
+
+    entries() {
+      return headerMaps.get(this).entries();
+    }
+
+    forEach(callback, thisArg) {
+      for (const [k, v] of this.entries()) callback.call(thisArg, v, k, this);
+    }
+
+    [Symbol.iterator]() {
+      return this.entries();
+    }
+  }
+
+  const bodyUsed = Symbol("bodyUsed");
+
+  class Body {
+    get bodyUsed() {
+      return !!this[bodyUsed];
+    }
+
+    async text() {
+      this[bodyUsed] = true;
+      const body = await consume(this.body);
+      if (body === null || body === undefined) return "";
+      return typeof body === "string" ? body : decodeUtf8(toBytes(body));
+    }
+
+    async json() {
+      return JSON.parse(await this.text());
+    }
+
+    async arrayBuffer() {
+      this[bodyUsed] = true;
+      const body = await consume(this.body);
+      if (body === null || body === undefined) return new ArrayBuffer(0);
+      const bytes = toBytes(body);
+      return bytes.buffer.slice(bytes.byteOffset, bytes.byteOffset + bytes.byteLength);
+    }
+  }
+
+  class Request extends Body {
+    constructor(input, init = {}) {
+      super();
+      const base = input instanceof Request ? input : null;
+      this.method = String(init.method ?? base?.method ?? "GET").toUpperCase();
+      this.url = base ? base.url : String(input);
+      this.headers = new Headers(init.headers ?? base?.headers);
+      this.body = normalizeBody(init.body ?? base?.body);
+    }
+  }
+
+  class Response extends Body {
+    constructor(body = null, init = {}) {
+      super();
+      this.status = init.status ?? 200;
+      this.statusText = init.statusText ?? "";
+      this.headers = new Headers(init.headers);
+      this.body = normalizeBody(body);
+      this.url = "";
+    }
+
+    get ok() {
+      return this.status >= 200 && this.status < 300;
+    }
+
+    static json(data, init = {}) {
+      const res = new Response(JSON.stringify(data), init);
+      if (!res.headers.has("content-type")) res.headers.set("content-type", "application/json");
+      return res;
+    }
+
+    // Server-Sent Events: each item of the (async) iterable is a string or {event, data, id, retry}
+    static sse(events, init = {}) {
+      const res = new Response(eventStream(events), init);
+      res.headers.set("content-type", "text/event-stream");
+      res.headers.set("cache-control", "no-cache");
+      return res;
+    }
+
+    static redirect(url, status = 302) {
+      if (![301, 302, 303, 307, 308].includes(status)) {
+        throw new RangeError(`invalid redirect status: ${status}`);
+      }
+      return new Response(null, { status, headers: { location: String(url) } });
+    }
+  }
+
+  // the request built by rust becomes a Request, `query`, `params` and the verified `auth`
+  // claims are kept as extra fields
+  function request(raw) {
+    const req = new Request(raw.url, raw);
+    req.query = raw.query;
+    req.params = raw.params;
+    req.auth = raw.auth == null ? null : JSON.parse(raw.auth);
+    return req;
+  }
+
+  // the stream of the last response, taken by rust right after the handler returns
+  let pendingStream = null;
+
+  // handlers may return a Response or the plain `{status, headers, body}` object
+  function response(res) {
+    if (res instanceof Response) {
+      res = { status: res.status, headers: Object.fromEntries(res.headers), body: res.body };
+    }
+    if (res && typeof res === "object" && isStream(res.body)) {
+      pendingStream = res.body[Symbol.asyncIterator]();
+      return { ...res, body: null };
+    }
+    if (res && typeof res === "object" && isBinary(res.body)) {
+      return { ...res, body: toBytes(res.body) };
+    }
+    return res;
+  }
+
+  // a Response for the middleware, whatever the handler returned
+  function toResponse(res) {
+    if (res instanceof Response || res === null || typeof res !== "object") return res;
+    return new Response(res.body, { status: res.status, headers: res.headers });
+  }
+
+  // run the middleware in order, `next(req)` runs the rest of the chain and resolves to
+  // a Response, a middleware may also return its own response without calling next
+  function chain(handlers, middleware, handler, req) {
+    const run = (i, req) => {
+      if (i === middleware.length) return Promise.resolve(handler(req));
+      let called = false;
+      const next = (nextReq) => {
+        if (called) return Promise.reject(new Error("next() called multiple times"));
+        called = true;
+        // a new Request doesn't carry `query`, `params` and `auth` over
+        if (nextReq instanceof Request && nextReq !== req) {
+          nextReq.query ??= req.query;
+          nextReq.params ??= req.params;
+          nextReq.auth ??= req.auth;
+        }
+        return run(i + 1, nextReq ?? req).then(toResponse);
+      };
+      return Promise.resolve().then(() => handlers[middleware[i]](req, next));
+    };
+    return run(0, req);
+  }
+
+  function takeStream() {
+    const stream = pendingStream;
+    pendingStream = null;
+    return stream;
+  }
+
+  // resolve to the next chunk as string or Uint8Array, or null when the stream is done
+  async function pull(stream) {
+    const { value, done } = await stream.next();
+    if (done) return null;
+    return typeof value === "string" ? value : toBytes(value);
+  }
+
+  async function cancel(stream) {
+    if (typeof stream.return === "function") await stream.return();
+  }
+
+  // the `socket` passed to the callbacks of a websocket handler
+  function socket(send, close, req) {
+    return Object.freeze({
+      request: request(req),
+      send(data) {
+        send(typeof data === "string" || isBinary(data) ? normalizeBody(data) : String(data));
+      },
+      close(code = 1000, reason = "") {
+        close(code, String(reason));
+      },
+    });
+  }
+
+  // call an optional (async) callback, always resolves to a promise
+  function dispatch(target, name, ...args) {
+    return Promise.resolve().then(() =>
+      typeof target[name] === "function" ? target[name](...args) : undefined,
+    );
+  }
+
+  globalThis.TextEncoder = TextEncoder;
+  globalThis.TextDecoder = TextDecoder;
+  globalThis.Headers = Headers;
+  globalThis.Request = Request;
+  globalThis.Response = Response;
+  globalThis.ReadableStream = ReadableStream;
+  Object.defineProperty(globalThis, "__dino", {
+    value: {
+      request,
+      response,
+      chain,
+      takeStream,
+      pull,
+      cancel,
+      socket,
+      dispatch,
+      toBytes,
+      isBinary,
+    },
+    enumerable: false,
+  });
+})
@@ -0,0 +1,99 @@
+use std::{
+    alloc::{alloc, dealloc, realloc, Layout},
+    ptr::null_mut,
+};
+
+use rquickjs::allocator::{Allocator, RawMemPtr};
+
+use super::ExecutionBudget;
+use crate::ExecutionLimit;
+
+// same as libc malloc, quickjs expects its allocations to be aligned like that.
+// the size of the allocation is kept in front of it
+const ALIGN: usize = 16;
+
+/// 在分配时检查内存限制，超出时在 budget 上记录下来。quickjs 只会抛出一个普通的
+/// InternalError("out of memory")，用户代码也能抛出同样的错误，不能靠 message 来判断
+pub(super) struct LimitedAllocator {
+    limit: usize,
+    used: usize,
+    budget: ExecutionBudget,
+}
+
+impl LimitedAllocator {
+    pub fn new(limit: usize, budget: ExecutionBudget) -> Self {
+        Self {
+            limit,
+            used: 0,
+            budget,
+        }
+    }
+
+    fn exceeds(&self, size: usize) -> bool {
+        if self.used + size <= self.limit {
+            return false;
+        }
+        self.budget.exceed(ExecutionLimit::Memory);
+        true
+    }
+}
+
+fn layout(size: usize) -> Option<Layout> {
+    let size = size.checked_next_multiple_of(ALIGN)?.checked_add(ALIGN)?;
+    Layout::from_size_align(size, ALIGN).ok()
+}
+
+unsafe fn header(ptr: RawMemPtr) -> *mut usize {
+    ptr.sub(ALIGN) as *mut usize
+}
+
+unsafe impl Allocator for LimitedAllocator {
+    fn alloc(&mut self, size: usize) -> RawMemPtr {
+        let Some(layout) = layout(size) else {
+            return null_mut();
+        };
+        if self.exceeds(layout.size()) {
+            return null_mut();
+        }
+        unsafe {
+            let ptr = alloc(layout);
+            if ptr.is_null() {
+                return null_mut();
+            }
+            *(ptr as *mut usize) = layout.size();
+            self.used += layout.size();
+            ptr.add(ALIGN)
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: RawMemPtr) {
+        let size = *header(ptr);
+        self.used -= size;
+        dealloc(
+            ptr.sub(ALIGN),
+            Layout::from_size_align_unchecked(size, ALIGN),
+        );
+    }
+
+    unsafe fn realloc(&mut self, ptr: RawMemPtr, new_size: usize) -> RawMemPtr {
+        let size = *header(ptr);
+        let Some(layout) = layout(new_size) else {
+            return null_mut();
+        };
+        if self.exceeds(layout.size().saturating_sub(size)) {
+            return null_mut();
+        }
+        let old = Layout::from_size_align_unchecked(size, ALIGN);
+        let ptr = realloc(ptr.sub(ALIGN), old, layout.size());
+        if ptr.is_null() {
+            return null_mut();
+        }
+        *(ptr as *mut usize) = layout.size();
+        self.used = self.used - size + layout.size();
+        ptr.add(ALIGN)
+    }
+
+    unsafe fn usable_size(ptr: RawMemPtr) -> usize {
+        *header(ptr) - ALIGN
+    }
+}
@@ -0,0 +1,1005 @@
+mod console;
+mod dino;
+mod fetch;
+mod http;
+mod memory;
+mod socket;
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+use axum::{body::Body, response::Response};
+use console::Console;
+pub use dino::Bindings;
+use dino_macros::{FromJs, IntoJs};
+use fetch::Fetcher;
+pub use http::JsBody;
+use memory::LimitedAllocator;
+use rquickjs::{
+    prelude::Coerced, Context, Ctx, Exception, Function, Object, Persistent, Promise, Runtime,
+    Value,
+};
+use socket::SocketSession;
+use tracing::warn;
+use typed_builder::TypedBuilder;
+
+use crate::{middleware::REQUEST_ID_HEADER, AppError, ExecutionLimit, WorkerConfig};
+
+#[allow(unused)]
+pub struct JsWorker {
+    // dropped first: they may hold js values which must be freed before the runtime
+    // the async iterator of a streaming response which is not finished yet
+    stream: RefCell<Option<Persistent<Object<'static>>>>,
+    // the websocket connection this worker is pinned to
+    session: RefCell<Option<SocketSession>>,
+    // the `InternalError` of this context, thrown by quickjs on a stack overflow
+    internal_error: Persistent<Function<'static>>,
+    fetcher: Rc<Fetcher>,
+    console: Rc<Console>,
+    rt: Runtime,
+    ctx: Context,
+    budget: ExecutionBudget,
+    timeout: Duration,
+    cpu_time: Duration,
+    // set when a limit was hit, the runtime may be left in a bad state and should be recreated
+    poisoned: Cell<bool>,
+}
+
+/// 通过 quickjs 的 interrupt handler 限制一次执行可以使用的 cpu 时间和 wall-clock 时间
+#[derive(Debug, Clone, Default)]
+struct ExecutionBudget(Rc<BudgetState>);
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    cpu_time: Cell<Duration>,
+    deadline: Cell<Option<Instant>>,
+    spent: Cell<Duration>,
+    resumed_at: Cell<Option<Instant>>,
+    exceeded: Cell<Option<ExecutionLimit>>,
+}
+
+#[derive(Debug, TypedBuilder, IntoJs)]
+pub struct Req<T> {
+    #[builder(default)]
+    pub query: HashMap<String, String>,
+    #[builder(default)]
+    pub params: HashMap<String, String>,
+    #[builder(default)]
+    pub headers: HashMap<String, String>,
+    #[builder(setter(into))]
+    pub method: String,
+    #[builder(setter(into))]
+    pub url: String,
+    #[builder(default)]
+    pub body: Option<T>,
+    // the verified claims of the route's `auth` as json, `req.auth` in js
+    #[builder(default)]
+    pub auth: Option<String>,
+}
+
+#[derive(Debug, FromJs)]
+pub struct Res<T> {
+    pub status: u16,
+    pub headers: HashMap<String, String>,
+    pub body: Option<T>,
+}
+
+impl JsWorker {
+    pub fn try_new(module: &str) -> Result<Self> {
+        Self::try_new_with_config(module, "default", &WorkerConfig::default())
+    }
+
+    /// tenant is the project name, it is attached to the logs emitted by console.*
+    pub fn try_new_with_config(module: &str, tenant: &str, config: &WorkerConfig) -> Result<Self> {
+        Self::try_new_with_bindings(module, tenant, config, &Bindings::default())
+    }
+
+    pub fn try_new_with_bindings(
+        module: &str,
+        tenant: &str,
+        config: &WorkerConfig,
+        bindings: &Bindings,
+    ) -> Result<Self> {
+        let budget = ExecutionBudget::default();
+        let limit = config.memory_limit_mb * 1024 * 1024;
+        let rt = Runtime::new_with_alloc(LimitedAllocator::new(limit, budget.clone()))?;
+        rt.set_max_stack_size(config.max_stack_size_kb * 1024);
+        let checker = budget.clone();
+        rt.set_interrupt_handler(Some(Box::new(move || checker.is_exceeded())));
+        let ctx = Context::full(&rt)?;
+        let fetcher = Fetcher::try_new(config.fetch.clone())?;
+        let console = Console::new(tenant);
+
+        // evaluating the module is also limited, a top level `while(true){}` should not hang
+        budget.start(config.cpu_time(), config.timeout());
+        let ret = ctx.with(|ctx| {
+            let global = ctx.globals();
+            let internal_error: Function = global.get("InternalError")?;
+            let internal_error = Persistent::save(&ctx, internal_error);
+            console.install(&ctx)?;
+            http::install(&ctx)?;
+            fetcher.install(&ctx)?;
+            dino::install(&ctx, bindings)?;
+            let ret: Object = ctx.eval(module)?;
+            fetcher.clear();
+            global.set("handlers", ret)?;
+            Ok::<_, anyhow::Error>(internal_error)
+        });
+        budget.stop();
+        if let Some(limit) = budget.exceeded() {
+            return Err(anyhow!("evaluate module failed: {limit} exceeded"));
+        }
+        let internal_error = ret?;
+
+        Ok(Self {
+            stream: RefCell::new(None),
+            session: RefCell::new(None),
+            internal_error,
+            fetcher,
+            console,
+            rt,
+            ctx,
+            budget,
+            timeout: config.timeout(),
+            cpu_time: config.cpu_time(),
+            poisoned: Cell::new(false),
+        })
+    }
+
+    pub fn run<T>(&self, name: &str, req: Req<T>) -> Result<Res<T>, AppError>
+    where
+        T: for<'js> rquickjs::IntoJs<'js>,
+        T: for<'js> rquickjs::FromJs<'js>,
+    {
+        self.run_with_middleware(&[], name, req)
+    }
+
+    /// 依次执行 middleware，每个 middleware 通过 `next(req)` 执行后面的 middleware 和 handler，
+    /// 可以修改 request 和 response，也可以不调用 next 直接返回
+    pub fn run_with_middleware<T>(
+        &self,
+        middleware: &[String],
+        name: &str,
+        req: Req<T>,
+    ) -> Result<Res<T>, AppError>
+    where
+        T: for<'js> rquickjs::IntoJs<'js>,
+        T: for<'js> rquickjs::FromJs<'js>,
+    {
+        let request_id = req.headers.get(REQUEST_ID_HEADER).cloned();
+        self.console.enter(name, request_id.unwrap_or_default());
+        self.budget.start(self.cpu_time, self.timeout);
+        let ret = self.ctx.with(|ctx| {
+            let ret = self.call_handler(&ctx, middleware, name, req);
+            self.fetcher.clear();
+            ret
+        });
+        self.budget.stop();
+        if ret.is_err() {
+            self.stream.take();
+        }
+        // a streaming response keeps the scope until the stream ends
+        if !self.is_streaming() {
+            self.console.exit();
+        }
+        ret
+    }
+
+    /// handler 返回了 ReadableStream / async iterator，body 需要通过 next_chunk 逐块读取
+    pub fn is_streaming(&self) -> bool {
+        self.stream.borrow().is_some()
+    }
+
+    /// pull the next chunk of the streaming response, None when the stream is finished.
+    /// every chunk gets a fresh execution budget, so a long stream is not killed by the timeout
+    pub fn next_chunk(&self) -> Result<Option<JsBody>, AppError> {
+        let Some(stream) = self.stream.borrow().clone() else {
+            return Ok(None);
+        };
+        self.budget.start(self.cpu_time, self.timeout);
+        let ret = self.ctx.with(|ctx| {
+            let handler = "stream";
+            let ret = self
+                .pull(&ctx, stream)
+                .map_err(|e| self.handle_error(&ctx, handler, e));
+            self.fetcher.clear();
+            ret
+        });
+        self.budget.stop();
+        if !matches!(ret, Ok(Some(_))) {
+            self.finish_stream();
+        }
+        ret
+    }
+
+    /// the client went away: give the iterator a chance to clean up, e.g. `finally` blocks
+    pub fn cancel_stream(&self) {
+        let Some(stream) = self.stream.borrow().clone() else {
+            return;
+        };
+        self.budget.start(self.cpu_time, self.timeout);
+        self.ctx.with(|ctx| {
+            let ret = (|| {
+                let stream = stream.restore(&ctx)?;
+                let glue: Object = ctx.globals().get("__dino")?;
+                let v: Promise = glue.get::<_, Function>("cancel")?.call((stream,))?;
+                self.await_promise::<Value>(&ctx, v)
+            })();
+            if let Err(e) = ret {
+                let e = self.handle_error(&ctx, "stream", e);
+                warn!("cancel streaming response failed: {}", e);
+            }
+            self.fetcher.clear();
+        });
+        self.budget.stop();
+        self.finish_stream();
+    }
+
+    fn finish_stream(&self) {
+        self.stream.take();
+        self.console.exit();
+    }
+
+    fn pull<'js>(
+        &self,
+        ctx: &Ctx<'js>,
+        stream: Persistent<Object<'static>>,
+    ) -> rquickjs::Result<Option<JsBody>> {
+        let stream = stream.restore(ctx)?;
+        let glue: Object = ctx.globals().get("__dino")?;
+        let v: Promise = glue.get::<_, Function>("pull")?.call((stream,))?;
+        self.await_promise(ctx, v)
+    }
+
+    /// 执行时超出限制后 runtime 可能处于不一致的状态，需要丢弃并重新创建
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.get()
+    }
+
+    fn handle_error(&self, ctx: &Ctx, handler: &str, e: rquickjs::Error) -> AppError {
+        // always take the pending exception out of the context
+        let exception = matches!(e, rquickjs::Error::Exception).then(|| {
+            let value = ctx.catch();
+            match value.as_exception() {
+                Some(ex) => {
+                    let internal = self.internal_error.clone().restore(ctx).ok();
+                    let internal = internal.is_some_and(|f| ex.is_instance_of(f));
+                    (ex.message().unwrap_or_default(), ex.stack(), internal)
+                }
+                // e.g. `throw "boom"` or `Promise.reject(42)`
+                None => {
+                    let message = value.get::<Coerced<String>>().map(|v| v.0);
+                    (message.unwrap_or_default(), None, false)
+                }
+            }
+        });
+        // set by the interrupt handler or by the allocator when the memory limit trips
+        if let Some(limit) = self.budget.exceeded() {
+            self.poisoned.set(true);
+            return AppError::LimitExceeded(limit);
+        }
+        let Some((message, stack, internal)) = exception else {
+            return anyhow::Error::from(e).into();
+        };
+
+        // quickjs unwinds a stack overflow like any other exception, the runtime is still usable
+        if internal && message == "stack overflow" {
+            return AppError::LimitExceeded(ExecutionLimit::StackSize);
+        }
+        AppError::JsException {
+            message,
+            stack,
+            handler: handler.to_string(),
+        }
+    }
+
+    fn call_handler<'js, T>(
+        &self,
+        ctx: &Ctx<'js>,
+        middleware: &[String],
+        name: &str,
+        req: Req<T>,
+    ) -> Result<Res<T>, AppError>
+    where
+        T: rquickjs::IntoJs<'js> + rquickjs::FromJs<'js>,
+    {
+        let value = self
+            .invoke(ctx, middleware, name, req)
+            .map_err(|e| self.handle_error(ctx, name, e))?;
+        // the handler must return a Response or {status, headers, body}
+        <Res<T> as rquickjs::FromJs>::from_js(ctx, value).map_err(|e| AppError::InvalidResponse {
+            handler: name.to_string(),
+            message: e.to_string(),
+        })
+    }
+
+    fn invoke<'js, T>(
+        &self,
+        ctx: &Ctx<'js>,
+        middleware: &[String],
+        name: &str,
+        req: Req<T>,
+    ) -> rquickjs::Result<Value<'js>>
+    where
+        T: rquickjs::IntoJs<'js>,
+    {
+        let global = ctx.globals();
+        let handlers: Object = global.get("handlers")?;
+        let Some(fun) = handlers.get::<_, Option<Function>>(name)? else {
+            let msg = format!("handler {name} is not defined");
+            return Err(Exception::throw_reference(ctx, &msg));
+        };
+        for m in middleware {
+            if handlers.get::<_, Option<Function>>(m.as_str())?.is_none() {
+                let msg = format!("middleware {m} is not defined");
+                return Err(Exception::throw_reference(ctx, &msg));
+            }
+        }
+        // req is passed as a Request, a returned Response becomes {status, headers, body}
+        let glue: Object = global.get("__dino")?;
+        let req: Value = glue.get::<_, Function>("request")?.call((req,))?;
+        let v: Promise = if middleware.is_empty() {
+            fun.call((req,))?
+        } else {
+            let chain = glue.get::<_, Function>("chain")?;
+            chain.call((handlers, middleware.to_vec(), fun, req))?
+        };
+        let res: Value = self.await_promise(ctx, v)?;
+        let res: Value = glue.get::<_, Function>("response")?.call((res,))?;
+        let stream: Option<Object> = glue.get::<_, Function>("takeStream")?.call(())?;
+        *self.stream.borrow_mut() = stream.map(|s| Persistent::save(ctx, s));
+        Ok(res)
+    }
+
+    // a tiny event loop: run quickjs jobs, and when all of them are blocked on io,
+    // wait for the next outstanding fetch to finish
+    fn await_promise<'js, V>(&self, ctx: &Ctx<'js>, promise: Promise<'js>) -> rquickjs::Result<V>
+    where
+        V: rquickjs::FromJs<'js>,
+    {
+        loop {
+            if let Some(ret) = promise.result() {
+                return ret;
+            }
+            if ctx.execute_pending_job() {
+                continue;
+            }
+            if !self.fetcher.has_pending() {
+                return Err(Exception::throw_internal(
+                    ctx,
+                    "handler returned a promise which never settles",
+                ));
+            }
+
+            // waiting for io does not count as cpu time
+            self.budget.pause();
+            let ret = self.fetcher.wait(ctx, self.budget.deadline());
+            self.budget.resume();
+            if !ret? {
+                self.budget.exceed(ExecutionLimit::WallClock);
+                return Err(Exception::throw_internal(ctx, "handler timed out"));
+            }
+        }
+    }
+}
+
+impl ExecutionBudget {
+    fn start(&self, cpu_time: Duration, timeout: Duration) {
+        let now = Instant::now();
+        self.0.cpu_time.set(cpu_time);
+        self.0.deadline.set(Some(now + timeout));
+        self.0.spent.set(Duration::ZERO);
+        self.0.resumed_at.set(Some(now));
+        self.0.exceeded.set(None);
+    }
+
+    fn stop(&self) {
+        self.pause();
+        self.0.deadline.set(None);
+    }
+
+    fn pause(&self) {
+        if let Some(t) = self.0.resumed_at.take() {
+            self.0.spent.set(self.0.spent.get() + t.elapsed());
+        }
+    }
+
+    fn resume(&self) {
+        self.0.resumed_at.set(Some(Instant::now()));
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.0.deadline.get()
+    }
+
+    fn exceed(&self, limit: ExecutionLimit) {
+        self.0.exceeded.set(Some(limit));
+    }
+
+    fn exceeded(&self) -> Option<ExecutionLimit> {
+        self.0.exceeded.get()
+    }
+
+    // called by quickjs regularly while executing, return true to interrupt the execution
+    fn is_exceeded(&self) -> bool {
+        if self.0.exceeded.get().is_some() {
+            return true;
+        }
+        let now = Instant::now();
+        let running = self.0.resumed_at.get().map(|t| now - t).unwrap_or_default();
+        if self.0.spent.get() + running > self.0.cpu_time.get() {
+            self.0.exceeded.set(Some(ExecutionLimit::CpuTime));
+        } else if matches!(self.0.deadline.get(), Some(deadline) if now > deadline) {
+            self.0.exceeded.set(Some(ExecutionLimit::WallClock));
+        }
+        self.0.exceeded.get().is_some()
+    }
+}
+
+impl<T> Res<T> {
+    /// build the response with the given body instead of the buffered one, e.g. a stream.
+    /// the status and the headers come from js, so this fails on e.g. `status: 1000`
+    pub fn try_into_response_with_body(self, body: Body) -> Result<Response, axum::http::Error> {
+        let mut builder = Response::builder().status(self.status);
+        for (k, v) in self.headers {
+            builder = builder.header(k, v);
+        }
+        builder.body(body)
+    }
+}
+
+impl<T: Into<Body>> Res<T> {
+    pub fn try_into_response(mut self) -> Result<Response, axum::http::Error> {
+        let body = self.body.take().map(Into::into).unwrap_or_else(Body::empty);
+        self.try_into_response_with_body(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{http::StatusCode, response::IntoResponse};
+
+    use super::*;
+
+    #[test]
+    fn js_worker_should_work() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function hello(req){
+                return {
+                    status:200,
+                    headers:{
+                        "content-type":"application/json"
+                    },
+                    body: JSON.stringify(req)
+                };
+            }
+            return{hello:hello};
+        })()"#;
+
+        let req: Req<String> = Req::builder()
+            .method("GET")
+            .url("https://example.com")
+            .headers(HashMap::new())
+            .build();
+        let worker = JsWorker::try_new(code)?;
+        let ret = worker.run("hello", req)?;
+        assert_eq!(ret.status, 200);
+        Ok(())
+    }
+
+    #[test]
+    fn js_worker_should_handle_binary_body() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function reverse(req){
+                const bytes = new Uint8Array(await req.arrayBuffer());
+                return {
+                    status: 200,
+                    headers: { "content-type": "application/octet-stream" },
+                    body: bytes.reverse().buffer,
+                };
+            }
+            async function echo(req){
+                const data = await req.json();
+                return {
+                    status: 200,
+                    headers: {},
+                    body: new TextEncoder().encode(data.name),
+                };
+            }
+            return{reverse:reverse, echo:echo};
+        })()"#;
+        let worker = JsWorker::try_new(code)?;
+
+        let req = Req::builder()
+            .method("POST")
+            .url("/")
+            .body(Some(JsBody::Binary(vec![0, 159, 146, 150])))
+            .build();
+        let ret = worker.run("reverse", req)?;
+        assert_eq!(ret.body, Some(JsBody::Binary(vec![150, 146, 159, 0])));
+
+        let req = Req::builder()
+            .method("POST")
+            .url("/")
+            .body(Some(r#"{"name":"dino"}"#.into()))
+            .build();
+        let ret = worker.run("echo", req)?;
+        assert_eq!(ret.body, Some(JsBody::Binary(b"dino".to_vec())));
+        Ok(())
+    }
+
+    #[test]
+    fn js_worker_should_support_fetch_api_response() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function hello(req){
+                const data = await req.json();
+                return Response.json(
+                    { name: data.name, agent: req.headers.get("User-Agent"), id: req.params.id },
+                    { status: 201, headers: { "x-dino": "1" } },
+                );
+            }
+            async function redirect(req){
+                return Response.redirect("/login", 307);
+            }
+            async function plain(req){
+                return { status: 200, headers: {}, body: req.headers["user-agent"] };
+            }
+            async function shadow(req){
+                const body = `${req.headers.get("get")} ${req.headers.has("delete")}`;
+                return new Response(body, { headers: { ...req.headers, set: "1" } });
+            }
+            return{hello:hello, redirect:redirect, plain:plain, shadow:shadow};
+        })()"#;
+        let worker = JsWorker::try_new(code)?;
+        let req = || {
+            Req::builder()
+                .method("POST")
+                .url("/api/1")
+                .params(HashMap::from([("id".to_string(), "1".to_string())]))
+                .headers(HashMap::from([
+                    ("user-agent".to_string(), "curl".to_string()),
+                    // client controlled names, they must not replace the methods of Headers
+                    ("get".to_string(), "x".to_string()),
+                    ("delete".to_string(), "y".to_string()),
+                ]))
+                .body(Some(JsBody::from(r#"{"name":"dino"}"#)))
+                .build()
+        };
+
+        let ret = worker.run("hello", req())?;
+        assert_eq!(ret.status, 201);
+        assert_eq!(
+            ret.headers.get("content-type").map(|v| v.as_str()),
+            Some("application/json")
+        );
+        assert_eq!(ret.headers.get("x-dino").map(|v| v.as_str()), Some("1"));
+        assert_eq!(
+            ret.body,
+            Some(r#"{"name":"dino","agent":"curl","id":"1"}"#.into())
+        );
+
+        let ret = worker.run("redirect", req())?;
+        assert_eq!(ret.status, 307);
+        assert_eq!(
+            ret.headers.get("location").map(|v| v.as_str()),
+            Some("/login")
+        );
+        assert_eq!(ret.body, None);
+
+        let ret = worker.run("plain", req())?;
+        assert_eq!(ret.body, Some("curl".into()));
+
+        let ret = worker.run("shadow", req())?;
+        assert_eq!(ret.body, Some("x true".into()));
+        assert_eq!(
+            ret.headers.get("user-agent").map(|v| v.as_str()),
+            Some("curl")
+        );
+        assert_eq!(ret.headers.get("set").map(|v| v.as_str()), Some("1"));
+        // not copied by the spread, the name belongs to a method
+        assert!(!ret.headers.contains_key("get"));
+        Ok(())
+    }
+
+    #[test]
+    fn js_worker_should_run_middleware_chain() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function auth(req, next){
+                if (!req.headers.get("authorization")) {
+                    return new Response("unauthorized", { status: 401 });
+                }
+                const headers = new Headers(req.headers);
+                headers.set("x-user", "alice");
+                return next(new Request(req, { headers }));
+            }
+            async function tag(req, next){
+                const res = await next();
+                res.headers.set("x-chain", `${res.headers.get("x-chain")},tag`);
+                return res;
+            }
+            async function hello(req){
+                const body = `${req.headers.get("x-user")} ${req.params.id}`;
+                return { status: 200, headers: { "x-chain": "hello" }, body };
+            }
+            return{auth:auth, tag:tag, hello:hello};
+        })()"#;
+        let worker = JsWorker::try_new(code)?;
+        let req = |auth: bool| {
+            let headers = auth.then(|| ("authorization".to_string(), "token".to_string()));
+            Req::<String>::builder()
+                .method("GET")
+                .url("/api/1")
+                .params(HashMap::from([("id".to_string(), "1".to_string())]))
+                .headers(headers.into_iter().collect())
+                .build()
+        };
+        let chain = ["tag".to_string(), "auth".to_string()];
+
+        let ret = worker.run_with_middleware(&chain, "hello", req(true))?;
+        assert_eq!(ret.status, 200);
+        assert_eq!(ret.body, Some("alice 1".into()));
+        assert_eq!(
+            ret.headers.get("x-chain").map(|v| v.as_str()),
+            Some("hello,tag")
+        );
+
+        // auth returns without calling next, tag still sees the response
+        let ret = worker.run_with_middleware(&chain, "hello", req(false))?;
+        assert_eq!(ret.status, 401);
+        assert_eq!(
+            ret.headers.get("x-chain").map(|v| v.as_str()),
+            Some("null,tag")
+        );
+
+        let chain = ["missing".to_string()];
+        match worker.run_with_middleware(&chain, "hello", req(true)) {
+            Err(AppError::JsException { message, .. }) => {
+                assert_eq!(message, "middleware missing is not defined")
+            }
+            v => panic!("expect js exception, got {:?}", v),
+        }
+        Ok(())
+    }
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn js_worker_console_should_log_to_tracing() -> Result<()> {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .with_max_level(tracing::Level::DEBUG)
+            .finish();
+        let code = r#"
+        (function(){
+            async function hello(req){
+                console.log("hello", { a: 1, b: "x", c: [1, 2] });
+                console.warn("%s is %d", "dino", 42);
+                return { status: 200, headers: {} };
+            }
+            return{hello:hello};
+        })()"#;
+
+        tracing::subscriber::with_default(subscriber, || -> Result<()> {
+            let worker = JsWorker::try_new_with_config(code, "test", &WorkerConfig::default())?;
+            let headers = HashMap::from([(REQUEST_ID_HEADER.to_string(), "req-1".to_string())]);
+            let req: Req<String> = Req::builder()
+                .method("GET")
+                .url("/")
+                .headers(headers)
+                .build();
+            worker.run("hello", req)?;
+            Ok(())
+        })?;
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone())?;
+        assert!(logs.contains("INFO"));
+        assert!(logs.contains("hello { a: 1, b: 'x', c: [ 1, 2 ] }"));
+        assert!(logs.contains("WARN"));
+        assert!(logs.contains("dino is 42"));
+        assert!(logs.contains("tenant=test"));
+        assert!(logs.contains("handler=hello"));
+        assert!(logs.contains("request_id=req-1"));
+        Ok(())
+    }
+
+    #[test]
+    fn js_worker_fetch_should_work() -> Result<()> {
+        // a local stand-in for the remote service
+        let rt = tokio::runtime::Runtime::new()?;
+        let listener = rt.block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))?;
+        let port = listener.local_addr()?.port();
+        let app = axum::Router::new().route(
+            "/echo",
+            axum::routing::post(|body: String| async move {
+                axum::Json(serde_json::json!({ "message": format!("hello {body}") }))
+            }),
+        );
+        rt.spawn(async move { axum::serve(listener, app).await });
+
+        let code = format!(
+            r#"
+        (function(){{
+            async function proxy(req){{
+                const res = await fetch("http://127.0.0.1:{port}/echo", {{
+                    method: "POST",
+                    body: "dino",
+                }});
+                const data = await res.json();
+                return {{
+                    status: res.status,
+                    headers: {{ "content-type": res.headers.get("content-type") }},
+                    body: data.message,
+                }};
+            }}
+            async function denied(req){{
+                try {{
+                    await fetch("https://example.com/");
+                    return {{ status: 200, headers: {{}} }};
+                }} catch (e) {{
+                    return {{ status: 403, headers: {{}}, body: e.message }};
+                }}
+            }}
+            return{{proxy:proxy, denied:denied}};
+        }})()"#
+        );
+        let config = WorkerConfig {
+            fetch: crate::FetchConfig {
+                allowed_hosts: vec!["127.0.0.1".to_string()],
+            },
+            ..Default::default()
+        };
+        let worker = JsWorker::try_new_with_config(&code, "test", &config)?;
+
+        let req: Req<String> = Req::builder().method("GET").url("/").build();
+        let ret = worker.run("proxy", req)?;
+        assert_eq!(ret.status, 200);
+        assert_eq!(ret.body.as_deref(), Some("hello dino"));
+        assert_eq!(
+            ret.headers.get("content-type").map(|v| v.as_str()),
+            Some("application/json")
+        );
+
+        let req: Req<String> = Req::builder().method("GET").url("/").build();
+        let ret = worker.run("denied", req)?;
+        assert_eq!(ret.status, 403);
+        assert_eq!(
+            ret.body.as_deref(),
+            Some("fetch to example.com is not allowed")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn js_worker_fetch_should_check_redirects() -> Result<()> {
+        let rt = tokio::runtime::Runtime::new()?;
+        let listener = rt.block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))?;
+        let port = listener.local_addr()?.port();
+        let redirect = |to: String| move || async move { axum::response::Redirect::to(&to) };
+        let app = axum::Router::new()
+            .route("/hello", axum::routing::get(|| async { "hello" }))
+            .route(
+                "/moved",
+                axum::routing::get(redirect(format!("http://127.0.0.1:{port}/hello"))),
+            )
+            // the same server, but under a host which is not allowed
+            .route(
+                "/escape",
+                axum::routing::get(redirect(format!("http://localhost:{port}/hello"))),
+            );
+        rt.spawn(async move { axum::serve(listener, app).await });
+
+        let code = format!(
+            r#"
+        (function(){{
+            async function get(req){{
+                try {{
+                    const res = await fetch(`http://127.0.0.1:{port}${{req.url}}`);
+                    return {{ status: res.status, headers: {{}}, body: await res.text() }};
+                }} catch (e) {{
+                    return {{ status: 502, headers: {{}}, body: e.message }};
+                }}
+            }}
+            return{{get:get}};
+        }})()"#
+        );
+        let config = WorkerConfig {
+            fetch: crate::FetchConfig {
+                allowed_hosts: vec!["127.0.0.1".to_string()],
+            },
+            ..Default::default()
+        };
+        let worker = JsWorker::try_new_with_config(&code, "test", &config)?;
+
+        let req: Req<String> = Req::builder().method("GET").url("/moved").build();
+        let ret = worker.run("get", req)?;
+        assert_eq!(ret.status, 200);
+        assert_eq!(ret.body.as_deref(), Some("hello"));
+
+        let req: Req<String> = Req::builder().method("GET").url("/escape").build();
+        let ret = worker.run("get", req)?;
+        assert_eq!(ret.status, 502);
+        let body = ret.body.unwrap_or_default();
+        assert!(
+            body.contains("redirect to localhost is not allowed"),
+            "{body}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn js_worker_should_surface_exception_with_stack() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function boom(req){
+                throw new Error("boom");
+            }
+            async function reject(req){
+                return Promise.reject("nope");
+            }
+            async function invalid(req){
+                return 42;
+            }
+            return{boom:boom, reject:reject, invalid:invalid};
+        })()"#;
+        let worker = JsWorker::try_new(code)?;
+        let req = || Req::<String>::builder().method("GET").url("/").build();
+
+        match worker.run("boom", req()) {
+            Err(AppError::JsException {
+                message,
+                stack,
+                handler,
+            }) => {
+                assert_eq!(message, "boom");
+                assert_eq!(handler, "boom");
+                assert!(stack.unwrap_or_default().contains("boom"));
+            }
+            v => panic!("expect js exception, got {:?}", v),
+        }
+        match worker.run("reject", req()) {
+            Err(AppError::JsException { message, .. }) => assert_eq!(message, "nope"),
+            v => panic!("expect js exception, got {:?}", v),
+        }
+        match worker.run("missing", req()) {
+            Err(AppError::JsException { message, .. }) => {
+                assert_eq!(message, "handler missing is not defined")
+            }
+            v => panic!("expect js exception, got {:?}", v),
+        }
+        assert!(matches!(
+            worker.run("invalid", req()),
+            Err(AppError::InvalidResponse { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn js_worker_should_interrupt_infinite_loop() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function hello(req){
+                while(true){}
+            }
+            return{hello:hello};
+        })()"#;
+        let config = WorkerConfig {
+            cpu_time_ms: 100,
+            ..Default::default()
+        };
+        let worker = JsWorker::try_new_with_config(code, "test", &config)?;
+        let req: Req<String> = Req::builder().method("GET").url("/").build();
+        let ret = worker.run("hello", req);
+        assert!(matches!(
+            ret,
+            Err(AppError::LimitExceeded(ExecutionLimit::CpuTime))
+        ));
+        assert!(worker.is_poisoned());
+        Ok(())
+    }
+
+    #[test]
+    fn js_worker_should_limit_memory() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function hello(req){
+                let arr = [];
+                while(true){ arr.push("dino".repeat(1024)); }
+            }
+            return{hello:hello};
+        })()"#;
+        let config = WorkerConfig {
+            memory_limit_mb: 8,
+            ..Default::default()
+        };
+        let worker = JsWorker::try_new_with_config(code, "test", &config)?;
+        let req: Req<String> = Req::builder().method("GET").url("/").build();
+        let ret = worker.run("hello", req);
+        assert!(matches!(
+            ret,
+            Err(AppError::LimitExceeded(ExecutionLimit::Memory))
+        ));
+        assert!(worker.is_poisoned());
+        Ok(())
+    }
+
+    #[test]
+    fn js_worker_should_limit_stack_size() -> Result<()> {
+        let code = r#"
+        (function(){
+            function deep(n){ return deep(n + 1) + 1; }
+            async function hello(req){
+                return deep(0);
+            }
+            return{hello:hello};
+        })()"#;
+        let worker = JsWorker::try_new(code)?;
+        let req: Req<String> = Req::builder().method("GET").url("/").build();
+        let ret = worker.run("hello", req);
+        assert!(matches!(
+            ret,
+            Err(AppError::LimitExceeded(ExecutionLimit::StackSize))
+        ));
+        assert!(!worker.is_poisoned());
+        Ok(())
+    }
+
+    #[test]
+    fn js_worker_should_not_trust_limit_messages_thrown_by_user() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function memory(req){
+                throw new Error("out of memory");
+            }
+            async function internal(req){
+                throw new InternalError("out of memory");
+            }
+            async function stack(req){
+                throw new Error("stack overflow");
+            }
+            return{memory:memory,internal:internal,stack:stack};
+        })()"#;
+        let config = WorkerConfig {
+            memory_limit_mb: 8,
+            ..Default::default()
+        };
+        let worker = JsWorker::try_new_with_config(code, "test", &config)?;
+        for name in ["memory", "internal", "stack"] {
+            let req: Req<String> = Req::builder().method("GET").url("/").build();
+            let e = worker.run(name, req).unwrap_err();
+            assert!(matches!(e, AppError::JsException { .. }), "{name}: {e:?}");
+            assert_eq!(
+                e.into_response().status(),
+                StatusCode::INTERNAL_SERVER_ERROR
+            );
+            assert!(!worker.is_poisoned());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+use std::borrow::Cow;
+
+use axum::extract::ws::{CloseFrame, Message};
+use rquickjs::{Ctx, Exception, Function, Object, Persistent, Promise, Value};
+use tokio::sync::mpsc;
+
+use super::{JsBody, JsWorker, Req};
+use crate::{middleware::REQUEST_ID_HEADER, AppError};
+
+/// 一个 websocket 连接在 JsWorker 中的状态，整个连接期间都绑定在同一个 worker 上
+pub(super) struct SocketSession {
+    handler: String,
+    request_id: String,
+    // the handler object with onOpen / onMessage / onClose
+    callbacks: Persistent<Object<'static>>,
+    socket: Persistent<Object<'static>>,
+}
+
+impl JsWorker {
+    /// create the `socket` object of the connection and call `onOpen(socket)`,
+    /// messages sent by js are written into `outbound`
+    pub fn open_socket(
+        &self,
+        name: &str,
+        req: Req<JsBody>,
+        outbound: mpsc::Sender<Message>,
+    ) -> Result<(), AppError> {
+        let request_id = req.headers.get(REQUEST_ID_HEADER).cloned();
+        let request_id = request_id.unwrap_or_default();
+        self.console.enter(name, &request_id);
+        self.budget.start(self.cpu_time, self.timeout);
+        let ret = self.ctx.with(|ctx| {
+            let (callbacks, socket) = create_socket(&ctx, name, req, outbound)
+                .map_err(|e| self.handle_error(&ctx, name, e))?;
+            *self.session.borrow_mut() = Some(SocketSession {
+                handler: name.to_string(),
+                request_id,
+                callbacks: Persistent::save(&ctx, callbacks.clone()),
+                socket: Persistent::save(&ctx, socket.clone()),
+            });
+            let data = Value::new_undefined(ctx.clone());
+            self.dispatch(&ctx, name, callbacks, "onOpen", socket, data)
+        });
+        self.budget.stop();
+        self.console.exit();
+        ret
+    }
+
+    /// a text or binary message from the client, calls `onMessage(socket, data)`
+    pub fn socket_message(&self, data: JsBody) -> Result<(), AppError> {
+        self.socket_event("onMessage", |ctx| rquickjs::IntoJs::into_js(data, ctx))
+    }
+
+    /// the connection is closed, calls `onClose(socket, {code, reason})` and ends the session
+    pub fn close_socket(&self, code: u16, reason: String) -> Result<(), AppError> {
+        let ret = self.socket_event("onClose", |ctx| {
+            let event = Object::new(ctx.clone())?;
+            event.set("code", code)?;
+            event.set("reason", reason)?;
+            Ok(event.into_value())
+        });
+        self.session.take();
+        ret
+    }
+
+    fn socket_event<F>(&self, event: &str, data: F) -> Result<(), AppError>
+    where
+        F: for<'js> FnOnce(&Ctx<'js>) -> rquickjs::Result<Value<'js>>,
+    {
+        let session = self.session.borrow().as_ref().map(|s| {
+            let (handler, request_id) = (s.handler.clone(), s.request_id.clone());
+            (handler, request_id, s.callbacks.clone(), s.socket.clone())
+        });
+        let Some((handler, request_id, callbacks, socket)) = session else {
+            return Ok(());
+        };
+        self.console.enter(&handler, request_id);
+
+        self.budget.start(self.cpu_time, self.timeout);
+        let ret = self.ctx.with(|ctx| {
+            let args: rquickjs::Result<_> =
+                (|| Ok((callbacks.restore(&ctx)?, socket.restore(&ctx)?, data(&ctx)?)))();
+            let (callbacks, socket, data) =
+                args.map_err(|e| self.handle_error(&ctx, &handler, e))?;
+            self.dispatch(&ctx, &handler, callbacks, event, socket, data)
+        });
+        self.budget.stop();
+        self.console.exit();
+        ret
+    }
+
+    fn dispatch<'js>(
+        &self,
+        ctx: &Ctx<'js>,
+        handler: &str,
+        callbacks: Object<'js>,
+        event: &str,
+        socket: Object<'js>,
+        data: Value<'js>,
+    ) -> Result<(), AppError> {
+        let ret = (|| {
+            let glue: Object = ctx.globals().get("__dino")?;
+            let dispatch: Function = glue.get("dispatch")?;
+            let v: Promise = dispatch.call((callbacks, event, socket, data))?;
+            self.await_promise::<Value>(ctx, v)
+        })();
+        self.fetcher.clear();
+        ret.map(|_| ())
+            .map_err(|e| self.handle_error(ctx, handler, e))
+    }
+}
+
+// a websocket handler is an object of callbacks instead of a function
+fn create_socket<'js>(
+    ctx: &Ctx<'js>,
+    name: &str,
+    req: Req<JsBody>,
+    outbound: mpsc::Sender<Message>,
+) -> rquickjs::Result<(Object<'js>, Object<'js>)> {
+    let handlers: Object = ctx.globals().get("handlers")?;
+    let Some(callbacks) = handlers.get::<_, Option<Object>>(name)? else {
+        let msg = format!("handler {name} is not defined");
+        return Err(Exception::throw_reference(ctx, &msg));
+    };
+
+    let sender = outbound.clone();
+    // the client may already be gone, the message is dropped in that case
+    let send = Function::new(ctx.clone(), move |data: JsBody| {
+        let msg = match data {
+            JsBody::Text(s) => Message::Text(s),
+            JsBody::Binary(b) => Message::Binary(b),
+        };
+        let _ = sender.blocking_send(msg);
+    })?
+    .with_name("send")?;
+    let close = Function::new(ctx.clone(), move |code: u16, reason: String| {
+        let frame = CloseFrame {
+            code,
+            reason: Cow::Owned(reason),
+        };
+        let _ = outbound.blocking_send(Message::Close(Some(frame)));
+    })?
+    .with_name("close")?;
+
+    let glue: Object = ctx.globals().get("__dino")?;
+    let socket: Object = glue
+        .get::<_, Function>("socket")?
+        .call((send, close, req))?;
+    Ok((callbacks, socket))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn js_worker_socket_should_work() -> anyhow::Result<()> {
+        let code = r#"
+        (function(){
+            let count = 0;
+            const echo = {
+                onOpen(socket) {
+                    socket.send(`welcome to ${socket.request.params.room}`);
+                },
+                async onMessage(socket, data) {
+                    count += 1;
+                    if (typeof data === "string") socket.send(`${count}: ${data}`);
+                    else socket.send(data.reverse());
+                },
+                onClose(socket, event) {
+                    console.log("closed", event.code);
+                },
+            };
+            return{echo:echo};
+        })()"#;
+        let worker = JsWorker::try_new(code)?;
+        let (tx, mut rx) = mpsc::channel(8);
+        let req = Req::builder()
+            .method("GET")
+            .url("/ws/lobby")
+            .params(HashMap::from([("room".to_string(), "lobby".to_string())]))
+            .build();
+
+        worker.open_socket("echo", req, tx)?;
+        assert!(matches!(rx.try_recv()?, Message::Text(s) if s == "welcome to lobby"));
+        worker.socket_message("hi".into())?;
+        assert!(matches!(rx.try_recv()?, Message::Text(s) if s == "1: hi"));
+        worker.socket_message(JsBody::Binary(vec![1, 2, 3]))?;
+        assert!(matches!(rx.try_recv()?, Message::Binary(b) if b == vec![3, 2, 1]));
+
+        worker.close_socket(1000, String::new())?;
+        // callbacks are not called any more once the session is closed
+        worker.socket_message("late".into())?;
+        assert!(rx.try_recv().is_err());
+        Ok(())
+    }
+}
@@ -1,20 +1,60 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
 use axum::{
-    http::{Method, StatusCode},
+    http::{
+        header::{CONTENT_TYPE, RETRY_AFTER},
+        Method, StatusCode,
+    },
     response::IntoResponse,
 };
+use serde_json::json;
 use thiserror::Error;
+use tracing::error;
+
+// in dev mode the message and the stack of js exceptions are returned to the client
+static DEV_MODE: AtomicBool = AtomicBool::new(false);
+// the detail of a handler failure outside dev mode, the message is only logged
+const GENERIC_DETAIL: &str = "the handler failed to produce a response, see the server logs";
 
 #[derive(Debug, Error)]
 pub enum AppError {
     #[error("Host not found: {0}")]
     HostNotFound(String),
 
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Invalid deployment: {0}")]
+    InvalidDeployment(String),
+
     #[error("Path not found: {0}")]
     RoutePathNotFound(String),
 
     #[error("Method not found: {0}")]
     RouteMethodNotAllowed(Method),
 
+    #[error("Execution limit exceeded: {0}")]
+    LimitExceeded(ExecutionLimit),
+
+    #[error("Too many requests, retry after {retry_after:?}")]
+    TooManyRequests { retry_after: Duration },
+
+    #[error("Js exception in handler {handler}: {message}")]
+    JsException {
+        message: String,
+        stack: Option<String>,
+        handler: String,
+    },
+
+    #[error("Invalid response from handler {handler}: {message}")]
+    InvalidResponse { handler: String, message: String },
+
     #[error("Anyhow error: {0}")]
     Anyhow(#[from] anyhow::Error),
 
@@ -22,13 +62,139 @@ pub enum AppError {
     Serde(#[from] serde_json::Error),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum ExecutionLimit {
+    #[error("wall-clock timeout")]
+    WallClock,
+    #[error("cpu time budget")]
+    CpuTime,
+    #[error("memory limit")]
+    Memory,
+    #[error("max stack size")]
+    StackSize,
+    #[error("max websocket sessions")]
+    WebSocketSessions,
+}
+
+pub fn set_dev_mode(enabled: bool) {
+    DEV_MODE.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_dev_mode() -> bool {
+    DEV_MODE.load(Ordering::Relaxed)
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
         let code = match self {
             AppError::HostNotFound(_) | AppError::RoutePathNotFound(_) => StatusCode::NOT_FOUND,
             AppError::RouteMethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
-            AppError::Anyhow(_) | AppError::Serde(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::InvalidDeployment(_) => StatusCode::BAD_REQUEST,
+            AppError::LimitExceeded(ExecutionLimit::WallClock) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::LimitExceeded(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::JsException { .. }
+            | AppError::InvalidResponse { .. }
+            | AppError::Anyhow(_)
+            | AppError::Serde(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        match self {
+            AppError::JsException {
+                message,
+                stack,
+                handler,
+            } => {
+                error!(
+                    handler = %handler,
+                    stack = stack.as_deref().unwrap_or_default(),
+                    "js exception: {}",
+                    message
+                );
+                let mut problem = json!({
+                    "type": "about:blank",
+                    "title": "Uncaught exception in handler",
+                    "status": code.as_u16(),
+                    "detail": GENERIC_DETAIL,
+                    "handler": handler,
+                });
+                if is_dev_mode() {
+                    problem["detail"] = message.into();
+                    problem["stack"] = stack.into();
+                }
+                problem_response(code, problem)
+            }
+            AppError::InvalidResponse { handler, message } => {
+                error!(handler = %handler, "invalid response: {}", message);
+                let mut problem = json!({
+                    "type": "about:blank",
+                    "title": "Invalid response from handler",
+                    "status": code.as_u16(),
+                    "detail": GENERIC_DETAIL,
+                    "handler": handler,
+                });
+                if is_dev_mode() {
+                    problem["detail"] = message.into();
+                }
+                problem_response(code, problem)
+            }
+            AppError::TooManyRequests { retry_after } => {
+                // in whole seconds, rounded up so that the retry is not rejected again
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                let retry_after = secs.max(1).to_string();
+                (code, [(RETRY_AFTER, retry_after)], self.to_string()).into_response()
+            }
+            _ => (code, self.to_string()).into_response(),
+        }
+    }
+}
+
+// https://datatracker.ietf.org/doc/html/rfc7807
+fn problem_response(code: StatusCode, problem: serde_json::Value) -> axum::response::Response {
+    (
+        code,
+        [(CONTENT_TYPE, "application/problem+json")],
+        problem.to_string(),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+
+    #[tokio::test]
+    async fn js_exception_should_render_problem_json() -> anyhow::Result<()> {
+        let err = AppError::JsException {
+            message: "Error: boom".to_string(),
+            stack: Some("    at hello (eval_script:3)".to_string()),
+            handler: "hello".to_string(),
+        };
+        let res = err.into_response();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            res.headers().get(CONTENT_TYPE).unwrap(),
+            "application/problem+json"
+        );
+        let body = to_bytes(res.into_body(), usize::MAX).await?;
+        let problem: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(problem["status"], 500);
+        assert_eq!(problem["handler"], "hello");
+        // the message and the stack are only exposed in dev mode
+        assert_eq!(problem["detail"], GENERIC_DETAIL);
+        assert!(problem.get("stack").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn too_many_requests_should_set_retry_after() {
+        let err = AppError::TooManyRequests {
+            retry_after: Duration::from_millis(1500),
         };
-        (code, self.to_string()).into_response()
+        let res = err.into_response();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "2");
     }
 }
@@ -0,0 +1,110 @@
+use dashmap::DashMap;
+
+/// the catch-all tenant, serves the hosts that match no other pattern
+pub const DEFAULT_HOST: &str = "*";
+// `/t/{tenant}/...`, for environments where we can't point a domain at the server
+const TENANT_PATH_PREFIX: &str = "/t/";
+
+/// 按固定的优先级查找 host 对应的 tenant：完全匹配 > 最长的 `*.domain` 后缀 > `*`。
+/// `*.example.com` 匹配任意层级的子域名，但不匹配 `example.com` 本身。
+/// 同时返回匹配到的 pattern
+pub(crate) fn match_host<V: Clone>(
+    routers: &DashMap<String, V>,
+    host: &str,
+) -> Option<(String, V)> {
+    find_host(host, |pattern| {
+        let v = routers.get(pattern)?;
+        Some((pattern.to_string(), v.value().clone()))
+    })
+}
+
+// the precedence of match_host over any lookup, e.g. the certificates of the tls listener
+pub(crate) fn find_host<V>(host: &str, get: impl Fn(&str) -> Option<V>) -> Option<V> {
+    if let Some(v) = get(host) {
+        return Some(v);
+    }
+    let mut rest = host;
+    while let Some((_, parent)) = rest.split_once('.') {
+        if let Some(v) = get(&format!("*.{parent}")) {
+            return Some(v);
+        }
+        rest = parent;
+    }
+    get(DEFAULT_HOST)
+}
+
+/// split `/t/{tenant}/rest` into the tenant and the path seen by the tenant's routes
+pub(crate) fn split_tenant_path(path: &str) -> Option<(&str, &str)> {
+    let rest = path.strip_prefix(TENANT_PATH_PREFIX)?;
+    let (tenant, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    (!tenant.is_empty()).then_some((tenant, path))
+}
+
+// the port is not part of the tenant, and host names are case-insensitive
+pub(crate) fn normalize_host(host: &str) -> String {
+    let host = match host.rfind(':') {
+        // keep ipv6 literals like `[::1]` intact
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    host.to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_host_should_follow_precedence() {
+        let routers = DashMap::new();
+        for host in ["example.com", "*.example.com", "*.preview.example.com", "*"] {
+            routers.insert(host.to_string(), host);
+        }
+        // the values are the patterns themselves
+        let match_host = |routers: &DashMap<String, &'static str>, host: &str| {
+            let (pattern, v) = match_host(routers, host)?;
+            assert_eq!(pattern, v);
+            Some(v)
+        };
+        assert_eq!(match_host(&routers, "example.com"), Some("example.com"));
+        assert_eq!(
+            match_host(&routers, "api.example.com"),
+            Some("*.example.com")
+        );
+        assert_eq!(
+            match_host(&routers, "a.b.example.com"),
+            Some("*.example.com")
+        );
+        assert_eq!(
+            match_host(&routers, "pr-1.preview.example.com"),
+            Some("*.preview.example.com")
+        );
+        assert_eq!(match_host(&routers, "other.org"), Some("*"));
+
+        routers.remove(DEFAULT_HOST);
+        assert_eq!(match_host(&routers, "other.org"), None);
+        assert_eq!(match_host(&routers, "localhost"), None);
+    }
+
+    #[test]
+    fn split_tenant_path_should_work() {
+        assert_eq!(
+            split_tenant_path("/t/acme/api/hello"),
+            Some(("acme", "/api/hello"))
+        );
+        assert_eq!(split_tenant_path("/t/acme"), Some(("acme", "/")));
+        assert_eq!(split_tenant_path("/t//api"), None);
+        assert_eq!(split_tenant_path("/api/hello"), None);
+    }
+
+    #[test]
+    fn normalize_host_should_strip_port() {
+        assert_eq!(normalize_host("Example.com:8080"), "example.com");
+        assert_eq!(normalize_host("localhost"), "localhost");
+        assert_eq!(normalize_host("[::1]:3000"), "[::1]");
+        assert_eq!(normalize_host("[::1]"), "[::1]");
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::KvConfig;
+
+/// handler 中 `Dino.kv` 的存储后端，每个 host (tenant) 独立一个实例。
+/// 方法在 worker 线程中同步调用，实现需要是线程安全的
+pub trait KvStore: Send + Sync + 'static {
+    fn get(&self, key: &str) -> Result<Option<String>>;
+    // the key expires after ttl, None means never
+    fn put(&self, key: &str, value: String, ttl: Option<Duration>) -> Result<()>;
+    fn delete(&self, key: &str) -> Result<()>;
+    // keys starting with prefix in lexicographic order
+    fn list(&self, prefix: &str, limit: usize) -> Result<Vec<String>>;
+}
+
+pub fn new_kv_store(config: &KvConfig) -> Result<Arc<dyn KvStore>> {
+    let store: Arc<dyn KvStore> = match config {
+        KvConfig::Memory => Arc::new(MemoryKv::default()),
+        KvConfig::Sled { path } => Arc::new(SledKv::open(path)?),
+    };
+    Ok(store)
+}
+
+#[derive(Default)]
+pub struct MemoryKv {
+    data: RwLock<BTreeMap<String, (String, Option<Instant>)>>,
+}
+
+impl KvStore for MemoryKv {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        let data = self.data.read().map_err(|_| anyhow!("kv lock poisoned"))?;
+        Ok(match data.get(key) {
+            Some((_, Some(expires_at))) if *expires_at <= Instant::now() => None,
+            Some((value, _)) => Some(value.clone()),
+            None => None,
+        })
+    }
+
+    fn put(&self, key: &str, value: String, ttl: Option<Duration>) -> Result<()> {
+        let mut data = self.data.write().map_err(|_| anyhow!("kv lock poisoned"))?;
+        let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        data.insert(key.to_string(), (value, expires_at));
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let mut data = self.data.write().map_err(|_| anyhow!("kv lock poisoned"))?;
+        data.remove(key);
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str, limit: usize) -> Result<Vec<String>> {
+        let mut data = self.data.write().map_err(|_| anyhow!("kv lock poisoned"))?;
+        // expired keys are only removed lazily
+        let now = Instant::now();
+        data.retain(|_, (_, expires_at)| !matches!(expires_at, Some(t) if *t <= now));
+        let keys = data
+            .range(prefix.to_string()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .take(limit)
+            .map(|(k, _)| k.clone())
+            .collect();
+        Ok(keys)
+    }
+}
+
+/// 基于 sled 的本地持久化存储，value 前 8 个字节保存过期时间（unix 毫秒，0 表示不过期）
+pub struct SledKv {
+    db: sled::Db,
+}
+
+impl SledKv {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let db = sled::open(path)?;
+        Ok(Self { db })
+    }
+
+    fn decode(&self, key: &[u8], raw: &[u8]) -> Result<Option<String>> {
+        if raw.len() < 8 {
+            return Err(anyhow!("corrupted kv entry"));
+        }
+        let (expires_at, value) = raw.split_at(8);
+        let expires_at = u64::from_be_bytes(expires_at.try_into()?);
+        if expires_at != 0 && expires_at <= now_millis() {
+            self.db.remove(key)?;
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8(value.to_vec())?))
+    }
+}
+
+impl KvStore for SledKv {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.db.get(key)? {
+            Some(raw) => self.decode(key.as_bytes(), &raw),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, key: &str, value: String, ttl: Option<Duration>) -> Result<()> {
+        let expires_at = ttl.map_or(0, |ttl| now_millis() + ttl.as_millis() as u64);
+        let mut raw = expires_at.to_be_bytes().to_vec();
+        raw.extend_from_slice(value.as_bytes());
+        self.db.insert(key, raw)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.db.remove(key)?;
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str, limit: usize) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for item in self.db.scan_prefix(prefix) {
+            if keys.len() >= limit {
+                break;
+            }
+            let (key, raw) = item?;
+            if self.decode(&key, &raw)?.is_some() {
+                keys.push(String::from_utf8(key.to_vec())?);
+            }
+        }
+        Ok(keys)
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kv_store_should_work(store: &dyn KvStore) -> Result<()> {
+        store.put("user:1", "alice".to_string(), None)?;
+        store.put("user:2", "bob".to_string(), None)?;
+        store.put("flag:beta", "on".to_string(), None)?;
+        store.put("tmp", "x".to_string(), Some(Duration::from_millis(10)))?;
+
+        assert_eq!(store.get("user:1")?.as_deref(), Some("alice"));
+        assert_eq!(store.get("missing")?, None);
+        assert_eq!(store.list("user:", 10)?, vec!["user:1", "user:2"]);
+        assert_eq!(store.list("user:", 1)?, vec!["user:1"]);
+
+        store.delete("user:1")?;
+        assert_eq!(store.get("user:1")?, None);
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(store.get("tmp")?, None);
+        assert_eq!(store.list("", 10)?, vec!["flag:beta", "user:2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn memory_kv_should_work() -> Result<()> {
+        kv_store_should_work(&MemoryKv::default())
+    }
+
+    #[test]
+    fn sled_kv_should_work() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        kv_store_should_work(&SledKv::open(dir.path())?)
+    }
+}
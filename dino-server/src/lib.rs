@@ -1,39 +1,99 @@
+mod admin;
+mod auth;
+mod body;
+mod canary;
 mod config;
+mod cors;
 mod engine;
 mod error;
+mod host;
+mod kv;
+mod limit;
+mod metrics;
 mod middleware;
+mod pool;
 mod router;
+mod scheduler;
+mod shutdown;
+mod static_files;
+mod store;
+mod tls;
 
+pub use admin::*;
+pub use auth::Authenticator;
+pub use canary::*;
 pub use config::*;
 pub use engine::*;
 pub use error::*;
+pub use host::DEFAULT_HOST;
+pub use kv::*;
+pub use limit::{LimitKeys, Limiter};
+pub use metrics::*;
 pub use middleware::*;
+pub use pool::*;
 pub use router::*;
+pub use scheduler::*;
+pub use shutdown::Executions;
+pub use static_files::StaticFiles;
+pub use store::*;
+pub use tls::{TlsCert, TlsOptions};
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    future::{Future, IntoFuture},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::Result;
 use axum::{
     body::Bytes,
-    extract::{Host, Query, State},
-    http::{request::Parts, Response},
-    response::IntoResponse,
+    extract::{ws::WebSocketUpgrade, Host, Query, State},
+    http::{header, request::Parts, uri::PathAndQuery, HeaderValue, Uri},
+    middleware::from_fn_with_state,
+    response::{IntoResponse, Response},
     routing::any,
     Router,
 };
 use dashmap::DashMap;
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
 use indexmap::IndexMap;
 use matchit::Match;
 use tokio::net::TcpListener;
-use tracing::info;
+use tracing::{info, warn, Span};
+use typed_builder::TypedBuilder;
+
+use auth::mark_api_keys;
+use body::hold;
+use canary::select_version;
+use cors::cors;
+use host::{match_host, normalize_host, split_tenant_path};
+use limit::{limit, ConcurrencySlot};
+use metrics::{track_metrics, HandlerInfo};
+use middleware::DEPLOYMENT_HEADER;
+use shutdown::{drain, ExecutionGuard};
+use tls::tls_config;
+
+// how long a graceful shutdown waits for the in-flight requests by default
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
 
 // indexmap 保证路由的注册顺序不变
 pub type ProjectRoutes = IndexMap<String, Vec<ProjectRoute>>;
 
 #[derive(Clone)]
 pub struct AppState {
-    // router key is hostname
-    routers: DashMap<String, SwappableAppRouter>,
+    // router key is a host pattern: `example.com`, `*.example.com` or `*` for the rest,
+    // tenants can be added or removed at runtime by the admin api
+    routers: Arc<DashMap<String, SwappableAppRouter>>,
+    // also route `/t/{tenant}/...` to the tenant, without relying on the host header
+    path_prefix: bool,
+    metrics: Arc<Metrics>,
+    // the in-flight requests and schedule runs, waited for on shutdown
+    executions: Executions,
 }
 
 #[derive(Clone)]
@@ -42,48 +102,336 @@ pub struct TennetRouter {
     router: SwappableAppRouter,
 }
 
+#[derive(Clone, TypedBuilder)]
+pub struct ServerOptions {
+    #[builder(default = 3000)]
+    pub port: u16,
+    // the admin api is only served when it is configured
+    #[builder(default, setter(into))]
+    pub admin: Option<AdminOptions>,
+    // tenants deployed by the admin api are saved here and restored on startup
+    #[builder(default, setter(into))]
+    pub store: Option<Arc<DeploymentStore>>,
+    // enable `/t/{tenant}/...` routing
+    #[builder(default)]
+    pub path_prefix: bool,
+    // serve https instead of http on the port
+    #[builder(default, setter(into))]
+    pub tls: Option<TlsOptions>,
+    // on shutdown, in-flight requests still running after this are dropped
+    #[builder(default = DEFAULT_SHUTDOWN_TIMEOUT)]
+    pub shutdown_timeout: Duration,
+    // serve `/metrics` without authentication on this port, it is also on the admin api
+    #[builder(default, setter(into))]
+    pub metrics_port: Option<u16>,
+}
+
+#[derive(Clone)]
+pub struct AdminOptions {
+    pub port: u16,
+    // bearer token required by every admin request
+    pub token: String,
+}
+
 pub async fn start_server(port: u16, routers: Vec<TennetRouter>) -> Result<()> {
-    let addr = format!("0.0.0.0:{port}");
-    info!("listening on {addr}");
-    let listener = TcpListener::bind(addr).await?;
-    // /*path 表示匹配所有路由
+    let options = ServerOptions::builder().port(port).build();
+    start_server_with_options(options, routers).await
+}
+
+pub async fn start_server_with_options(
+    options: ServerOptions,
+    routers: Vec<TennetRouter>,
+) -> Result<()> {
+    start_server_with_shutdown(options, routers, std::future::pending()).await
+}
+
+/// 收到 shutdown 后停止接受新连接，等待进行中的请求（包括 js worker 上的执行）结束，
+/// 最多等待 `options.shutdown_timeout`
+pub async fn start_server_with_shutdown(
+    options: ServerOptions,
+    routers: Vec<TennetRouter>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<()> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], options.port));
     let map = DashMap::new();
     for TennetRouter { host, router } in routers {
         map.insert(host, router);
     }
-    let state = AppState::new(map);
+    if let Some(store) = &options.store {
+        restore_tenants(store, &map)?;
+    }
+    let state = AppState::new(map).with_path_prefix(options.path_prefix);
+    for router in state.routers.iter() {
+        let executions = state.executions.clone();
+        tokio::spawn(run_schedules(router.clone(), executions));
+    }
+    let app = tenant_router(state.clone());
+    // every server and the drain deadline wait on the same signal
+    let shutdown = shutdown.boxed().shared();
+    // no new schedule runs once shutdown starts, the running ones are drained with the requests
+    let (signal, routers) = (shutdown.clone(), state.routers.clone());
+    tokio::spawn(async move {
+        signal.await;
+        for router in routers.iter() {
+            router.close();
+        }
+    });
+    let timeout = options.shutdown_timeout;
+    let server = match options.tls {
+        Some(tls) => {
+            let config = tls_config(tls)?;
+            info!("listening on https://{addr}");
+            let handle = axum_server::Handle::new();
+            let signal = shutdown.clone();
+            let h = handle.clone();
+            tokio::spawn(async move {
+                signal.await;
+                h.graceful_shutdown(Some(timeout));
+            });
+            axum_server::bind_rustls(addr, config)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .boxed()
+        }
+        None => {
+            info!("listening on http://{addr}");
+            serve(addr, app, shutdown.clone()).await?
+        }
+    };
+    let mut servers = vec![server];
+    if let Some(admin) = options.admin {
+        let addr = SocketAddr::from(([0, 0, 0, 0], admin.port));
+        info!("admin api listening on {addr}");
+        let app = admin_router(state.clone(), admin.token, options.store);
+        servers.push(serve(addr, app, shutdown.clone()).await?);
+    }
+    if let Some(port) = options.metrics_port {
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        info!("metrics listening on {addr}");
+        servers.push(serve(addr, metrics_router(state.clone()), shutdown.clone()).await?);
+    }
+    let servers = futures::future::try_join_all(servers).map(|ret| ret.map(|_| ()));
+    drain(servers, shutdown, timeout, &state.executions).await?;
+    info!("server stopped");
+    Ok(())
+}
+
+// /*path 表示匹配所有路由，请求先选择版本，再经过这个版本的跨域处理和限流后交给 handler，
+// 预检请求不占用限流额度，429 的响应也带有跨域的 headers。
+// axum 的 /*path 不匹配 `/`，站点根目录需要单独注册，例如 `static: { /: public }` 的 index.html
+fn tenant_router(state: AppState) -> Router {
     let app = Router::new()
+        .route("/", any(handler))
         .route("/*path", any(handler))
-        .with_state(state);
-    let app = set_layer(app);
-    axum::serve(listener, app.into_make_service()).await?;
-    Ok(())
+        .route_layer(from_fn_with_state(state.clone(), limit))
+        .route_layer(from_fn_with_state(state.clone(), cors))
+        .route_layer(from_fn_with_state(state.clone(), select_version))
+        .route_layer(from_fn_with_state(state.clone(), track_metrics))
+        .with_state(state.clone());
+    // outside of the TraceLayer, which logs the headers in the request span
+    set_layer(app).layer(from_fn_with_state(state, mark_api_keys))
+}
+
+// bind a plain http listener, the server stops accepting connections on shutdown
+async fn serve(
+    addr: SocketAddr,
+    app: Router,
+    shutdown: Shared<BoxFuture<'static, ()>>,
+) -> Result<BoxFuture<'static, std::io::Result<()>>> {
+    let listener = TcpListener::bind(addr).await?;
+    // the client address is used by the rate limits keyed by ip
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    let server = axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown)
+        .into_future();
+    Ok(server.boxed())
 }
 
 // we only support JSON requests and return JSON responses
 async fn handler(
     State(state): State<AppState>,
+    ws: Option<WebSocketUpgrade>,
     parts: Parts,
     Host(host): Host,
     Query(query): Query<HashMap<String, String>>,
     body: Option<Bytes>,
-) -> Result<impl IntoResponse, AppError> {
-    let router = get_router_by_host(host, state)?;
-    let matched = router.match_it(parts.method.clone(), parts.uri.path())?;
-    let req = assemble_req(&parts, query, body, &matched)?;
-    let handler = matched.value;
+) -> Result<Response, AppError> {
+    let (_, tenant, mut parts) = state.resolve(&host, parts)?;
+    // the version picked by `select_version` when a canary is running
+    let Selection {
+        router,
+        canary,
+        cookie,
+    } = match parts.extensions.remove::<Selection>() {
+        Some(selection) => selection,
+        None => tenant.select(&parts.headers),
+    };
+    Span::current()
+        .record("deployment", router.hash.as_str())
+        .record("canary", canary);
 
-    // TODO: build a worker pool, and send req via mpsc channel and get res from oneshot channel
-    // but if code change we need to recreate the worker
-    let worker = JsWorker::try_new(&router.code)?;
-    let res = worker.run(handler, req)?;
+    let mut handler_name = None;
+    let execution = state.executions.start();
+    let ret = dispatch(
+        router.clone(),
+        ws,
+        parts,
+        query,
+        body,
+        execution,
+        &mut handler_name,
+    )
+    .await;
+    let exception = matches!(ret, Err(AppError::JsException { .. }));
+    let mut res = ret.into_response();
+    // recorded by `track_metrics`
+    res.extensions_mut().insert(HandlerInfo {
+        handler: handler_name,
+        exception,
+    });
+    // error responses are also tagged, so that the versions can be compared
+    if let Ok(v) = HeaderValue::from_str(&router.hash) {
+        res.headers_mut().insert(DEPLOYMENT_HEADER, v);
+    }
+    if let Some(cookie) = cookie {
+        res.headers_mut().append(header::SET_COOKIE, cookie);
+    }
+    Ok(res)
+}
 
-    Ok(Response::from(res))
+async fn dispatch(
+    router: AppRouter,
+    ws: Option<WebSocketUpgrade>,
+    parts: Parts,
+    query: HashMap<String, String>,
+    body: Option<Bytes>,
+    // released once the response is sent or the websocket session ends
+    execution: ExecutionGuard,
+    // set to the handler of the matched route, for the metrics
+    handler_name: &mut Option<String>,
+) -> Result<Response, AppError> {
+    if let Some(ws) = ws {
+        let matched = router.match_websocket(parts.uri.path())?;
+        let handler = matched.value.to_string();
+        *handler_name = Some(handler.clone());
+        let req = assemble_req(&parts, query, None, &matched)?;
+        let permit = router.pool.try_acquire_session()?;
+        // the tenant's concurrency limit also counts the session, not only the upgrade
+        let slot = parts.extensions.get::<ConcurrencySlot>().cloned();
+        // the router (and its code version) is kept alive until the connection closes
+        let res = ws.on_upgrade(move |socket| async move {
+            router.pool.websocket(handler, req, socket, permit).await;
+            drop((execution, slot));
+        });
+        return Ok(res);
+    }
+
+    // a file of the `static` dirs takes precedence over the routes
+    if let Some(res) = router.static_files.serve(&parts) {
+        return Ok(res);
+    }
+
+    let matched = router.match_route(parts.method.clone(), parts.uri.path())?;
+    let handler = matched.value.handler.clone();
+    let middleware = matched.value.middleware.clone();
+    *handler_name = Some(handler.clone());
+    // 认证失败直接返回 401/403，不会进入 js
+    let claims = match &matched.value.auth {
+        Some(auth) => Some(auth.verify(&parts.headers)?.to_string()),
+        None => None,
+    };
+    let mut req = assemble_req(&parts, query, body, &matched)?;
+    req.auth = claims;
+
+    // send req to a warm worker via mpsc channel and get res from oneshot channel
+    let res = router
+        .pool
+        .run_with_middleware(handler, middleware, req)
+        .await?;
+
+    // a streaming response is still running on the worker
+    Ok(hold(res, execution))
+}
+
+// the routers given to start_server take precedence over the stored ones,
+// a tenant that fails to load is skipped so that the others can still be served
+fn restore_tenants(
+    store: &DeploymentStore,
+    routers: &DashMap<String, SwappableAppRouter>,
+) -> Result<()> {
+    for host in store.hosts()? {
+        if routers.contains_key(&host) {
+            continue;
+        }
+        let router = store
+            .load_current(&host)
+            .and_then(|d| SwappableAppRouter::try_new(d.code, d.config));
+        match router {
+            Ok(router) => {
+                info!(host = %host, "tenant restored");
+                routers.insert(host, router);
+            }
+            Err(e) => warn!(host = %host, "restore tenant failed: {:#}", e),
+        }
+    }
+    Ok(())
 }
 
 impl AppState {
     pub fn new(routers: DashMap<String, SwappableAppRouter>) -> Self {
-        Self { routers }
+        Self {
+            routers: Arc::new(routers),
+            path_prefix: false,
+            metrics: Arc::new(Metrics::new()),
+            executions: Executions::default(),
+        }
+    }
+
+    pub fn with_path_prefix(mut self, enabled: bool) -> Self {
+        self.path_prefix = enabled;
+        self
+    }
+
+    // find the tenant of the request, in path-prefix mode the `/t/{tenant}` part is removed
+    // from the uri so that the tenant's routes and `req.url` don't see it
+    fn resolve(
+        &self,
+        host: &str,
+        mut parts: Parts,
+    ) -> Result<(String, SwappableAppRouter, Parts), AppError> {
+        let (pattern, router, path) = self.lookup(host, parts.uri.path())?;
+        if let Some(path) = path {
+            let path_and_query = match parts.uri.query() {
+                Some(query) => format!("{path}?{query}"),
+                None => path.to_string(),
+            };
+            let path_and_query = PathAndQuery::try_from(path_and_query)
+                .map_err(|e| anyhow::anyhow!("invalid path: {e}"))?;
+            parts.uri = Uri::from(path_and_query);
+        }
+        Ok((pattern, router, parts))
+    }
+
+    // the host pattern and the tenant serving the request, with the path seen by the tenant
+    // when it is addressed by `/t/{tenant}`
+    fn lookup<'a>(
+        &self,
+        host: &str,
+        path: &'a str,
+    ) -> Result<(String, SwappableAppRouter, Option<&'a str>), AppError> {
+        if self.path_prefix {
+            if let Some((tenant, path)) = split_tenant_path(path) {
+                let (pattern, router) = self.get_router_by_host(tenant)?;
+                return Ok((pattern, router, Some(path)));
+            }
+        }
+        let (pattern, router) = self.get_router_by_host(host)?;
+        Ok((pattern, router, None))
+    }
+
+    fn get_router_by_host(&self, host: &str) -> Result<(String, SwappableAppRouter), AppError> {
+        let host = normalize_host(host);
+        match_host(&self.routers, &host).ok_or(AppError::HostNotFound(host))
     }
 }
 
@@ -93,30 +441,24 @@ impl TennetRouter {
     }
 }
 
-fn get_router_by_host(mut host: String, state: AppState) -> Result<AppRouter, AppError> {
-    // get router from state
-    let _ = host.split_off(host.find(":").unwrap_or(host.len()));
-    let router = state
-        .routers
-        .get(&host)
-        .ok_or(AppError::HostNotFound(host))?
-        .load();
-    Ok(router)
-}
-
-fn assemble_req(
+fn assemble_req<V>(
     parts: &Parts,
     query: HashMap<String, String>,
     body: Option<Bytes>,
-    matched: &Match<&str>,
-) -> Result<Req<String>, AppError> {
+    matched: &Match<V>,
+) -> Result<Req<JsBody>, AppError> {
     let params: HashMap<String, String> = matched
         .params
         .iter()
         .map(|(k, v)| (k.to_string(), v.to_string()))
         .collect();
-    // convert request data into Req and call handler with a js runtime
-    let body = body.and_then(|v| String::from_utf8(v.into()).ok());
+    // convert request data into Req and call handler with a js runtime,
+    // non-textual bodies are passed to js as Uint8Array
+    let content_type = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    let body = body.map(|v| JsBody::new(content_type, v));
 
     let headers = parts
         .headers
@@ -133,3 +475,291 @@ fn assemble_req(
         .build();
     Ok(req)
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn parts(uri: &str) -> Parts {
+        Request::get(uri).body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn app_state_resolve_should_support_path_prefix() -> Result<()> {
+        let config = include_str!("../fixtures/config.yml");
+        let router = SwappableAppRouter::try_new("", serde_yml::from_str(config)?)?;
+        let routers = DashMap::new();
+        routers.insert("*.example.com".to_string(), router);
+        let state = AppState::new(routers);
+
+        let (pattern, _, p) =
+            state.resolve("API.example.com:8080", parts("/t/acme.example.com/a?b=1"))?;
+        assert_eq!(pattern, "*.example.com");
+        // the prefix is only recognized in path-prefix mode
+        assert_eq!(p.uri, "/t/acme.example.com/a?b=1");
+        assert!(state.resolve("example.com", parts("/")).is_err());
+
+        let state = state.with_path_prefix(true);
+        let (_, _, p) = state.resolve("localhost", parts("/t/acme.example.com/a?b=1"))?;
+        assert_eq!(p.uri, "/a?b=1");
+        let (_, _, p) = state.resolve("api.example.com", parts("/api/hello/1"))?;
+        assert_eq!(p.uri, "/api/hello/1");
+        assert!(matches!(
+            state.resolve("api.example.com", parts("/t/acme/a")),
+            Err(AppError::HostNotFound(host)) if host == "acme"
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tenant_router_should_enforce_limits() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function hello1(req){
+                return { status: 200, headers: {}, body: "hello" };
+            }
+            return{hello1:hello1};
+        })()"#;
+        let mut config: ProjectConfig =
+            serde_yml::from_str(include_str!("../fixtures/config.yml"))?;
+        config.limits = serde_yml::from_str("rate: [{ requests: 1, period_ms: 60000 }]")?;
+        let routers = DashMap::new();
+        routers.insert(
+            "example.com".to_string(),
+            SwappableAppRouter::try_new(code, config)?,
+        );
+        let app = tenant_router(AppState::new(routers));
+
+        let req = || {
+            Request::get("/api/hello/1")
+                .header(header::HOST, "example.com")
+                .body(Body::empty())
+        };
+        let res = app.clone().oneshot(req()?).await?;
+        assert_eq!(res.status(), axum::http::StatusCode::OK);
+        let res = app.clone().oneshot(req()?).await?;
+        assert_eq!(res.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().contains_key(header::RETRY_AFTER));
+
+        // unknown hosts are left to the handler
+        let req = Request::get("/").header(header::HOST, "other.org");
+        let res = app.oneshot(req.body(Body::empty())?).await?;
+        assert_eq!(res.status(), axum::http::StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tenant_router_should_count_rejected_requests() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function hello1(req){
+                return { status: 200, headers: {}, body: "hello" };
+            }
+            return{hello1:hello1};
+        })()"#;
+        let mut config: ProjectConfig =
+            serde_yml::from_str(include_str!("../fixtures/config.yml"))?;
+        config.limits = serde_yml::from_str("rate: [{ requests: 1, period_ms: 60000 }]")?;
+        config.cors = Some(serde_yml::from_str("allow_origins: ['*']")?);
+        let routers = DashMap::new();
+        routers.insert(
+            "example.com".to_string(),
+            SwappableAppRouter::try_new(code, config)?,
+        );
+        let state = AppState::new(routers);
+        let app = tenant_router(state.clone());
+
+        let req = || {
+            Request::get("/api/hello/1")
+                .header(header::HOST, "example.com")
+                .body(Body::empty())
+        };
+        app.clone().oneshot(req()?).await?;
+        let res = app.clone().oneshot(req()?).await?;
+        assert_eq!(res.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+        // answered by the cors layer, before the limits
+        let preflight = Request::options("/api/hello/1")
+            .header(header::HOST, "example.com")
+            .header(header::ORIGIN, "https://app.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .body(Body::empty())?;
+        app.oneshot(preflight).await?;
+
+        let text = state.metrics.render(&state.routers)?;
+        let expected = [
+            r#"dino_http_requests_total{handler="hello1",host="example.com",status="200"} 1"#,
+            r#"dino_http_requests_total{handler="",host="example.com",status="429"} 1"#,
+            r#"dino_http_requests_total{handler="",host="example.com",status="200"} 1"#,
+        ];
+        for line in expected {
+            assert!(text.contains(line), "{line} is not found in:\n{text}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tenant_router_should_serve_site_root() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("index.html"), "<h1>hello</h1>")?;
+        let mut config: ProjectConfig =
+            serde_yml::from_str(include_str!("../fixtures/config.yml"))?;
+        config.static_files = serde_yml::from_str(&format!("{{ /: {} }}", dir.path().display()))?;
+        config.limits = serde_yml::from_str("rate: [{ requests: 1, period_ms: 60000 }]")?;
+        let routers = DashMap::new();
+        routers.insert(
+            "example.com".to_string(),
+            SwappableAppRouter::try_new("(function(){ return {}; })()", config)?,
+        );
+        let app = tenant_router(AppState::new(routers));
+
+        let req = || {
+            Request::get("/")
+                .header(header::HOST, "example.com")
+                .body(Body::empty())
+        };
+        let res = app.clone().oneshot(req()?).await?;
+        assert_eq!(res.status(), axum::http::StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        assert_eq!(body, "<h1>hello</h1>");
+        // the layers also run for the root
+        let res = app.oneshot(req()?).await?;
+        assert_eq!(res.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tenant_router_should_limit_canary_by_its_config() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function hello1(req){
+                return { status: 200, headers: {}, body: "hello" };
+            }
+            return{hello1:hello1};
+        })()"#;
+        let config: ProjectConfig = serde_yml::from_str(include_str!("../fixtures/config.yml"))?;
+        let router = SwappableAppRouter::try_new(code, config.clone())?;
+        let mut canary = config;
+        canary.limits = serde_yml::from_str("rate: [{ requests: 1, period_ms: 60000 }]")?;
+        router.set_canary(code, canary, 100, None)?;
+        let routers = DashMap::new();
+        routers.insert("example.com".to_string(), router);
+        let app = tenant_router(AppState::new(routers));
+
+        let req = || {
+            Request::get("/api/hello/1")
+                .header(header::HOST, "example.com")
+                .body(Body::empty())
+        };
+        let res = app.clone().oneshot(req()?).await?;
+        assert_eq!(res.status(), axum::http::StatusCode::OK);
+        let res = app.oneshot(req()?).await?;
+        assert_eq!(res.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tenant_router_should_hold_concurrency_until_body_is_sent() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function hello1(req){
+                return { status: 200, headers: {}, body: "hello" };
+            }
+            return{hello1:hello1};
+        })()"#;
+        let mut config: ProjectConfig =
+            serde_yml::from_str(include_str!("../fixtures/config.yml"))?;
+        config.limits = serde_yml::from_str("max_concurrent: 1")?;
+        let routers = DashMap::new();
+        routers.insert(
+            "example.com".to_string(),
+            SwappableAppRouter::try_new(code, config)?,
+        );
+        let app = tenant_router(AppState::new(routers));
+
+        let req = || {
+            Request::get("/api/hello/1")
+                .header(header::HOST, "example.com")
+                .body(Body::empty())
+        };
+        let res = app.clone().oneshot(req()?).await?;
+        assert_eq!(res.status(), axum::http::StatusCode::OK);
+        // the first response is still being sent
+        let busy = app.clone().oneshot(req()?).await?;
+        assert_eq!(busy.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+        axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        let res = app.oneshot(req()?).await?;
+        assert_eq!(res.status(), axum::http::StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tenant_router_should_pass_auth_claims() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function hello1(req){
+                return { status: 200, headers: {}, body: req.auth.key };
+            }
+            return{hello1:hello1};
+        })()"#;
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("keys.yml"), "ci: key-1\nadmin: key-2\n")?;
+        let mut config: ProjectConfig =
+            serde_yml::from_str(include_str!("../fixtures/config.yml"))?;
+        config.routes["/api/hello/:id"][0].auth = Some(serde_yml::from_str(
+            "api_key: { keys_file: keys.yml, allow: [admin] }",
+        )?);
+        let config = config.with_base_dir(dir.path());
+        let routers = DashMap::new();
+        routers.insert(
+            "example.com".to_string(),
+            SwappableAppRouter::try_new(code, config)?,
+        );
+        let app = tenant_router(AppState::new(routers));
+
+        let req = |key: &str| {
+            Request::get("/api/hello/1")
+                .header(header::HOST, "example.com")
+                .header("x-api-key", key)
+                .body(Body::empty())
+        };
+        let res = app.clone().oneshot(req("key-2")?).await?;
+        assert_eq!(res.status(), axum::http::StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        assert_eq!(body, "admin");
+        let res = app.clone().oneshot(req("key-1")?).await?;
+        assert_eq!(res.status(), axum::http::StatusCode::FORBIDDEN);
+        let res = app.oneshot(req("key-3")?).await?;
+        assert_eq!(res.status(), axum::http::StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tenant_router_should_count_executions_per_server() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function hello1(req){
+                return { status: 200, headers: {}, body: "hello" };
+            }
+            return{hello1:hello1};
+        })()"#;
+        let config: ProjectConfig = serde_yml::from_str(include_str!("../fixtures/config.yml"))?;
+        let router = SwappableAppRouter::try_new(code, config)?;
+        let states = [
+            AppState::new(DashMap::from_iter([("example.com".into(), router.clone())])),
+            AppState::new(DashMap::from_iter([("example.com".into(), router)])),
+        ];
+        let app = tenant_router(states[0].clone());
+
+        let req = Request::get("/api/hello/1").header(header::HOST, "example.com");
+        let res = app.oneshot(req.body(Body::empty())?).await?;
+        // the execution is in flight until the body is sent
+        assert_eq!(states[0].executions.active(), 1);
+        assert_eq!(states[1].executions.active(), 0);
+        axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        assert_eq!(states[0].executions.active(), 0);
+        Ok(())
+    }
+}
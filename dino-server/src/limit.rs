@@ -0,0 +1,334 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use axum::{
+    extract::{ConnectInfo, Host, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use dashmap::DashMap;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    body::hold, canary::selected, AppError, AppState, LimitsConfig, RateLimitConfig, RateLimitKey,
+};
+
+// buckets that are full again are the same as no bucket, they are dropped by a sweep
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+// hard cap of the buckets of a rate limit, a flood of new keys must not exhaust the memory.
+// new keys are rejected until the next sweep, the known ones are still served
+const MAX_BUCKETS: usize = 100_000;
+// retry hint when the concurrency cap is reached, the running requests are usually fast
+const CONCURRENCY_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// 一个 tenant 的限流器，由 config.yml 中的 `limits` 创建，和代码一起 swap。
+/// swap 之后使用新的配置和新的计数，进行中的请求仍然占用旧版本的并发名额
+#[derive(Debug)]
+pub struct Limiter {
+    rates: Vec<Arc<RateLimiter>>,
+    concurrency: Option<Arc<Semaphore>>,
+}
+
+/// what a rate limit is keyed by, collected from the request
+pub struct LimitKeys<'a> {
+    pub ip: Option<String>,
+    pub headers: &'a HeaderMap,
+    // the handler of the matched route
+    pub route: Option<&'a str>,
+}
+
+// token bucket: `requests` tokens are refilled every `period`, up to `burst`
+#[derive(Debug)]
+struct RateLimiter {
+    key: RateLimitKey,
+    header: String,
+    burst: f64,
+    // tokens refilled per second
+    rate: f64,
+    buckets: DashMap<String, Bucket>,
+    max_buckets: usize,
+}
+
+/// the concurrency slot of a request, in the request extensions so that a websocket
+/// session can keep it after the upgrade response is sent
+#[derive(Debug, Clone)]
+pub(crate) struct ConcurrencySlot {
+    _permit: Arc<OwnedSemaphorePermit>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Limiter {
+    pub fn try_new(config: &LimitsConfig) -> Result<Self> {
+        let rates = config
+            .rate
+            .iter()
+            .map(|config| RateLimiter::try_new(config).map(Arc::new))
+            .collect::<Result<Vec<_>>>()?;
+        // limiters built outside of a runtime (e.g. by `dino run --trigger`) serve no requests
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            for rate in &rates {
+                handle.spawn(sweep(Arc::downgrade(rate)));
+            }
+        }
+        let concurrency = match config.max_concurrent {
+            Some(0) => bail!("limits.max_concurrent must be greater than 0"),
+            Some(n) => Some(Arc::new(Semaphore::new(n))),
+            None => None,
+        };
+        Ok(Self { rates, concurrency })
+    }
+
+    /// take a token from every rate limit and a concurrency slot, the slot is released
+    /// when the permit is dropped
+    pub fn check(&self, keys: &LimitKeys) -> Result<Option<OwnedSemaphorePermit>, AppError> {
+        let now = Instant::now();
+        for rate in &self.rates {
+            rate.acquire(keys, now)?;
+        }
+        match &self.concurrency {
+            Some(semaphore) => {
+                let permit = semaphore.clone().try_acquire_owned().map_err(|_| {
+                    AppError::TooManyRequests {
+                        retry_after: CONCURRENCY_RETRY_AFTER,
+                    }
+                })?;
+                Ok(Some(permit))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl RateLimiter {
+    fn try_new(config: &RateLimitConfig) -> Result<Self> {
+        if config.requests == 0 || config.period_ms == 0 {
+            bail!("limits.rate requires requests and period_ms greater than 0");
+        }
+        let header = match (config.key, &config.header) {
+            (RateLimitKey::Header, Some(name)) => name.to_ascii_lowercase(),
+            (RateLimitKey::Header, None) => bail!("limits.rate keyed by header requires header"),
+            _ => String::new(),
+        };
+        let burst = config.burst.unwrap_or(config.requests).max(1);
+        Ok(Self {
+            key: config.key,
+            header,
+            burst: burst as f64,
+            rate: config.requests as f64 * 1000.0 / config.period_ms as f64,
+            buckets: DashMap::new(),
+            max_buckets: MAX_BUCKETS,
+        })
+    }
+
+    fn acquire(&self, keys: &LimitKeys, now: Instant) -> Result<(), AppError> {
+        let key = match self.key {
+            RateLimitKey::Ip => keys.ip.clone().unwrap_or_default(),
+            RateLimitKey::Header => keys
+                .headers
+                .get(&self.header)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string(),
+            RateLimitKey::Route => keys.route.unwrap_or_default().to_string(),
+            RateLimitKey::Tenant => String::new(),
+        };
+        if self.buckets.len() >= self.max_buckets && !self.buckets.contains_key(&key) {
+            return Err(AppError::TooManyRequests {
+                retry_after: SWEEP_INTERVAL,
+            });
+        }
+
+        let mut bucket = self.buckets.entry(key).or_insert_with(|| Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refill(&bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait = (1.0 - bucket.tokens) / self.rate;
+        Err(AppError::TooManyRequests {
+            retry_after: Duration::from_secs_f64(wait),
+        })
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+
+    // drop the buckets that are full again, a full bucket is the same as no bucket
+    fn sweep(&self, now: Instant) {
+        self.buckets.retain(|_, b| self.refill(b, now) < self.burst);
+    }
+}
+
+// sweeps the buckets of a rate limit until its limiter is dropped by a swap
+async fn sweep(rate: Weak<RateLimiter>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(rate) = rate.upgrade() else {
+            break;
+        };
+        rate.sweep(Instant::now());
+    }
+}
+
+/// 在 handler 之前按 tenant 的配置限流，超过限制时返回 429 和 Retry-After。
+/// 找不到 tenant 的请求直接交给 handler 处理
+pub(crate) async fn limit(
+    State(state): State<AppState>,
+    Host(host): Host,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Ok((_, tenant, path)) = state.lookup(&host, req.uri().path()) else {
+        return Ok(next.run(req).await);
+    };
+    // a canary is limited by its own config
+    let router = selected(&req, &tenant);
+    let permit = {
+        let path = path.unwrap_or(req.uri().path());
+        let route = router.match_it(req.method().clone(), path).ok();
+        let keys = LimitKeys {
+            ip: peer.map(|ConnectInfo(addr)| addr.ip().to_string()),
+            headers: req.headers(),
+            route: route.as_ref().map(|m| m.value),
+        };
+        router.limiter.check(&keys)?
+    };
+    let slot = permit.map(|permit| ConcurrencySlot {
+        _permit: Arc::new(permit),
+    });
+    if let Some(slot) = &slot {
+        req.extensions_mut().insert(slot.clone());
+    }
+    let res = next.run(req).await;
+    // the concurrency slot is held until the body is sent, streaming responses included
+    Ok(hold(res, slot))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn limiter(yaml: &str) -> Result<Limiter> {
+        let config: LimitsConfig = serde_yml::from_str(yaml)?;
+        Limiter::try_new(&config)
+    }
+
+    fn keys<'a>(ip: &str, headers: &'a HeaderMap, route: Option<&'a str>) -> LimitKeys<'a> {
+        LimitKeys {
+            ip: Some(ip.to_string()),
+            headers,
+            route,
+        }
+    }
+
+    #[test]
+    fn limiter_should_limit_rate_by_key() -> Result<()> {
+        let l = limiter(
+            r#"
+            rate:
+              - requests: 2
+                period_ms: 60000
+                key: ip
+              - requests: 3
+                period_ms: 60000
+                key: header
+                header: X-Api-Key
+            "#,
+        )?;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("a"));
+        assert!(l.check(&keys("1.1.1.1", &headers, None)).is_ok());
+        assert!(l.check(&keys("1.1.1.1", &headers, None)).is_ok());
+        let err = l.check(&keys("1.1.1.1", &headers, None)).unwrap_err();
+        let AppError::TooManyRequests { retry_after } = err else {
+            panic!("expect too many requests, got {err:?}");
+        };
+        // one token is refilled every 30s
+        assert!(retry_after > Duration::from_secs(29) && retry_after <= Duration::from_secs(30));
+
+        // another ip has its own bucket, but the api key is shared
+        assert!(l.check(&keys("2.2.2.2", &headers, None)).is_ok());
+        assert!(l.check(&keys("2.2.2.2", &headers, None)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn limiter_should_refill_tokens() -> Result<()> {
+        let l = limiter("rate: [{ requests: 1, period_ms: 100, key: route }]")?;
+        let headers = HeaderMap::new();
+        assert!(l.check(&keys("", &headers, Some("hello"))).is_ok());
+        assert!(l.check(&keys("", &headers, Some("hello"))).is_err());
+        assert!(l.check(&keys("", &headers, Some("world"))).is_ok());
+        std::thread::sleep(Duration::from_millis(120));
+        assert!(l.check(&keys("", &headers, Some("hello"))).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn limiter_should_sweep_and_cap_buckets() -> Result<()> {
+        let config = serde_yml::from_str("{ requests: 1, period_ms: 100, key: ip }")?;
+        let mut rate = RateLimiter::try_new(&config)?;
+        rate.max_buckets = 2;
+        let headers = HeaderMap::new();
+        let now = Instant::now();
+        assert!(rate.acquire(&keys("1.1.1.1", &headers, None), now).is_ok());
+        assert!(rate.acquire(&keys("2.2.2.2", &headers, None), now).is_ok());
+        // a new key is rejected at the cap, a known one is still limited as usual
+        let ret = rate.acquire(&keys("3.3.3.3", &headers, None), now);
+        let Err(AppError::TooManyRequests { retry_after }) = ret else {
+            panic!("expect too many requests, got {ret:?}");
+        };
+        assert_eq!(retry_after, SWEEP_INTERVAL);
+        let later = now + Duration::from_millis(100);
+        assert!(rate
+            .acquire(&keys("1.1.1.1", &headers, None), later)
+            .is_ok());
+
+        // only the bucket of 2.2.2.2 is full again
+        rate.sweep(later);
+        assert_eq!(rate.buckets.len(), 1);
+        assert!(rate
+            .acquire(&keys("3.3.3.3", &headers, None), later)
+            .is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn limiter_should_cap_concurrency() -> Result<()> {
+        let l = limiter("max_concurrent: 1")?;
+        let headers = HeaderMap::new();
+        let permit = l.check(&keys("", &headers, None))?;
+        assert!(permit.is_some());
+        assert!(matches!(
+            l.check(&keys("", &headers, None)),
+            Err(AppError::TooManyRequests { .. })
+        ));
+        drop(permit);
+        assert!(l.check(&keys("", &headers, None)).is_ok());
+
+        assert!(limiter("max_concurrent: 0").is_err());
+        assert!(limiter("rate: [{ requests: 0 }]").is_err());
+        assert!(limiter("rate: [{ requests: 1, key: header }]").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use axum::{
+    extract::{Host, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use dashmap::DashMap;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::{AppError, AppState, SwappableAppRouter};
+
+// handler label of the requests that matched no route, or were answered before the handler,
+// e.g. a 429 of the limits or a cors preflight
+const NO_HANDLER: &str = "";
+
+/// the time a js worker spent on a request, attached to the response by the pool
+#[derive(Debug, Clone, Copy)]
+pub struct JsExecutionTime(pub Duration);
+
+/// the handler of the matched route, attached to the response by the tenant handler
+#[derive(Debug, Clone)]
+pub(crate) struct HandlerInfo {
+    pub handler: Option<String>,
+    pub exception: bool,
+}
+
+/// 以 prometheus 格式导出的指标，按 tenant 的 host（注册时使用的 host pattern）和 handler 区分。
+/// worker pool 的指标在每次抓取时从当前的 tenant 中读取，删除的 tenant 会随之消失
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    js_duration: HistogramVec,
+    js_exceptions: IntCounterVec,
+    pool_workers: IntGaugeVec,
+    pool_busy: IntGaugeVec,
+    pool_queued: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        // the definitions are static, they can only fail if they are wrong
+        Self::try_new().expect("invalid metric definitions")
+    }
+
+    fn try_new() -> Result<Self> {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            Opts::new("dino_http_requests_total", "Number of http requests"),
+            &["host", "handler", "status"],
+        )?;
+        let duration = HistogramVec::new(
+            HistogramOpts::new(
+                "dino_http_request_duration_seconds",
+                "Time to produce the response headers",
+            ),
+            &["host", "handler"],
+        )?;
+        let js_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "dino_js_execution_duration_seconds",
+                "Time spent running the handler in a js worker",
+            ),
+            &["host", "handler"],
+        )?;
+        let js_exceptions = IntCounterVec::new(
+            Opts::new(
+                "dino_js_exceptions_total",
+                "Uncaught exceptions of the handlers",
+            ),
+            &["host", "handler"],
+        )?;
+        let pool_workers = IntGaugeVec::new(
+            Opts::new("dino_pool_workers", "Number of js workers of the tenant"),
+            &["host"],
+        )?;
+        let pool_busy = IntGaugeVec::new(
+            Opts::new(
+                "dino_pool_busy_workers",
+                "Number of js workers running a task",
+            ),
+            &["host"],
+        )?;
+        let pool_queued = IntGaugeVec::new(
+            Opts::new(
+                "dino_pool_queued_tasks",
+                "Number of tasks waiting for a js worker",
+            ),
+            &["host"],
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(duration.clone()))?;
+        registry.register(Box::new(js_duration.clone()))?;
+        registry.register(Box::new(js_exceptions.clone()))?;
+        registry.register(Box::new(pool_workers.clone()))?;
+        registry.register(Box::new(pool_busy.clone()))?;
+        registry.register(Box::new(pool_queued.clone()))?;
+        Ok(Self {
+            registry,
+            requests,
+            duration,
+            js_duration,
+            js_exceptions,
+            pool_workers,
+            pool_busy,
+            pool_queued,
+        })
+    }
+
+    /// record a request served by the tenant registered under host
+    pub(crate) fn observe(
+        &self,
+        host: &str,
+        handler: Option<&str>,
+        res: &Response,
+        exception: bool,
+        elapsed: Duration,
+    ) {
+        let handler = handler.unwrap_or(NO_HANDLER);
+        let status = res.status().as_u16().to_string();
+        self.requests
+            .with_label_values(&[host, handler, &status])
+            .inc();
+        self.duration
+            .with_label_values(&[host, handler])
+            .observe(elapsed.as_secs_f64());
+        if let Some(JsExecutionTime(t)) = res.extensions().get() {
+            self.js_duration
+                .with_label_values(&[host, handler])
+                .observe(t.as_secs_f64());
+        }
+        if exception {
+            self.js_exceptions.with_label_values(&[host, handler]).inc();
+        }
+    }
+
+    /// the prometheus text format of all metrics
+    pub fn render(&self, routers: &DashMap<String, SwappableAppRouter>) -> Result<String> {
+        self.pool_workers.reset();
+        self.pool_busy.reset();
+        self.pool_queued.reset();
+        for entry in routers.iter() {
+            let pool = &entry.value().load().pool;
+            let host = entry.key().as_str();
+            self.pool_workers
+                .with_label_values(&[host])
+                .set(pool.size() as i64);
+            self.pool_busy
+                .with_label_values(&[host])
+                .set(pool.busy() as i64);
+            self.pool_queued
+                .with_label_values(&[host])
+                .set(pool.queued() as i64);
+        }
+
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 在 cors 和限流之外记录请求的指标，它们直接返回的 429 和预检响应也会被统计。
+/// 找不到 tenant 的请求不记录
+pub(crate) async fn track_metrics(
+    State(state): State<AppState>,
+    Host(host): Host,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Ok((pattern, _, _)) = state.lookup(&host, req.uri().path()) else {
+        return next.run(req).await;
+    };
+    let start = Instant::now();
+    let res = next.run(req).await;
+    let info = res.extensions().get::<HandlerInfo>();
+    let handler = info.and_then(|info| info.handler.as_deref());
+    let exception = info.is_some_and(|info| info.exception);
+    state
+        .metrics
+        .observe(&pattern, handler, &res, exception, start.elapsed());
+    res
+}
+
+/// `GET /metrics`, served on the admin api and the optional metrics port
+pub fn metrics_router(state: AppState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics_handler))
+        .with_state(state)
+}
+
+pub(crate) async fn metrics_handler(State(state): State<AppState>) -> Result<Response, AppError> {
+    let body = state.metrics.render(&state.routers)?;
+    let content_type = TextEncoder::new().format_type().to_string();
+    Ok((StatusCode::OK, [(header::CONTENT_TYPE, content_type)], body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_should_render_tenants_and_requests() -> Result<()> {
+        let config = include_str!("../fixtures/config.yml");
+        let router = SwappableAppRouter::try_new("", serde_yml::from_str(config)?)?;
+        let routers = DashMap::new();
+        routers.insert("example.com".to_string(), router);
+
+        let metrics = Metrics::new();
+        let mut res = StatusCode::OK.into_response();
+        res.extensions_mut()
+            .insert(JsExecutionTime(Duration::from_millis(5)));
+        metrics.observe(
+            "example.com",
+            Some("hello"),
+            &res,
+            false,
+            Duration::from_millis(8),
+        );
+        let res = StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        metrics.observe(
+            "example.com",
+            Some("hello"),
+            &res,
+            true,
+            Duration::from_millis(3),
+        );
+        let res = StatusCode::NOT_FOUND.into_response();
+        metrics.observe("example.com", None, &res, false, Duration::from_millis(1));
+
+        let text = metrics.render(&routers)?;
+        let expected = [
+            r#"dino_http_requests_total{handler="hello",host="example.com",status="200"} 1"#,
+            r#"dino_http_requests_total{handler="hello",host="example.com",status="500"} 1"#,
+            r#"dino_http_requests_total{handler="",host="example.com",status="404"} 1"#,
+            r#"dino_js_execution_duration_seconds_count{handler="hello",host="example.com"} 1"#,
+            r#"dino_js_exceptions_total{handler="hello",host="example.com"} 1"#,
+            r#"dino_pool_busy_workers{host="example.com"} 0"#,
+        ];
+        for line in expected {
+            assert!(text.contains(line), "{line} is not found in:\n{text}");
+        }
+
+        // pool metrics of removed tenants are gone on the next scrape
+        routers.clear();
+        assert!(!metrics.render(&routers)?.contains("dino_pool_workers{"));
+        Ok(())
+    }
+}
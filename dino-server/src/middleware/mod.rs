@@ -4,24 +4,45 @@ mod server_time;
 use request_id::set_request_id;
 use server_time::set_server_time;
 
-use axum::{middleware::from_fn, Router};
+use axum::{
+    http::{header, HeaderName, Request},
+    middleware::from_fn,
+    Router,
+};
 use tower::ServiceBuilder;
 use tower_http::{
     compression::CompressionLayer,
-    trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer},
+    sensitive_headers::SetSensitiveRequestHeadersLayer,
+    trace::{DefaultOnRequest, DefaultOnResponse, MakeSpan, TraceLayer},
     LatencyUnit,
 };
-use tracing::Level;
+use tracing::{field, info_span, Level, Span};
 
 pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+// the default header of the `api_key` auth, the configured ones are marked by the tenant
+const API_KEY_HEADER: &str = "x-api-key";
 pub(crate) const SERVER_TIME_HEADER: &str = "x-server-time";
+// the hash of the code serving the request, differs between the current version and a canary
+pub(crate) const DEPLOYMENT_HEADER: &str = "x-dino-deployment";
+
+/// the span of DefaultMakeSpan with headers, plus the deployment recorded by the handler
+#[derive(Debug, Clone, Copy)]
+struct MakeRequestSpan;
 
 pub fn set_layer(app: Router) -> Router {
+    let sensitive = [
+        header::AUTHORIZATION,
+        header::PROXY_AUTHORIZATION,
+        header::COOKIE,
+        HeaderName::from_static(API_KEY_HEADER),
+    ];
     app.layer(
         ServiceBuilder::new()
+            // credentials and secrets sent by clients are logged as `Sensitive` by TraceLayer
+            .layer(SetSensitiveRequestHeadersLayer::new(sensitive))
             .layer(
                 TraceLayer::new_for_http()
-                    .make_span_with(DefaultMakeSpan::new().include_headers(true))
+                    .make_span_with(MakeRequestSpan)
                     .on_request(DefaultOnRequest::new().level(Level::INFO))
                     .on_response(
                         DefaultOnResponse::new()
@@ -34,3 +55,17 @@ pub fn set_layer(app: Router) -> Router {
             .layer(from_fn(set_server_time)),
     )
 }
+
+impl<B> MakeSpan<B> for MakeRequestSpan {
+    fn make_span(&mut self, req: &Request<B>) -> Span {
+        info_span!(
+            "request",
+            method = %req.method(),
+            uri = %req.uri(),
+            version = ?req.version(),
+            headers = ?req.headers(),
+            deployment = field::Empty,
+            canary = field::Empty,
+        )
+    }
+}
@@ -0,0 +1,580 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc as std_mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use axum::{
+    body::{Body, Bytes},
+    extract::ws::{CloseFrame, Message, WebSocket},
+    response::Response,
+};
+use futures::{stream, SinkExt, StreamExt};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, warn};
+
+use crate::{
+    AppError, Bindings, ExecutionLimit, JsBody, JsExecutionTime, JsWorker, Req, WorkerConfig,
+};
+
+// 每个 worker 最多排队的任务数，超过后 handler 会在 send 时等待（背压）
+const QUEUE_SIZE_PER_WORKER: usize = 16;
+// 流式响应在 worker 和客户端之间最多缓冲的 chunk 数，客户端读得慢时 worker 会阻塞等待
+const STREAM_BUFFER_SIZE: usize = 8;
+// websocket 每个方向最多缓冲的消息数
+const SOCKET_BUFFER_SIZE: usize = 32;
+
+type Chunk = Result<Bytes, AppError>;
+
+type Task = Box<dyn FnOnce(&JsWorker) + Send>;
+
+/// 每个 tenant 一组专用线程，每个线程持有一个预热好的 JsWorker，
+/// 通过 mpsc channel 接收任务，通过 oneshot channel 返回结果。
+/// JsWorker (rquickjs Runtime) 不是 Send 的，所以只能在创建它的线程中使用。
+pub struct WorkerPool {
+    sender: mpsc::Sender<Task>,
+    size: usize,
+    timeout: Duration,
+    tenant: Arc<str>,
+    code: Arc<str>,
+    config: WorkerConfig,
+    bindings: Bindings,
+    sessions: Arc<Semaphore>,
+    // workers running a task, exported as a metric
+    busy: Arc<AtomicUsize>,
+}
+
+impl WorkerPool {
+    pub fn new(
+        tenant: impl Into<String>,
+        code: impl Into<String>,
+        config: &WorkerConfig,
+        bindings: Bindings,
+    ) -> Self {
+        let size = config.pool_size.max(1);
+        let (sender, receiver) = mpsc::channel::<Task>(size * QUEUE_SIZE_PER_WORKER);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let code: Arc<str> = Arc::from(code.into());
+        let tenant: Arc<str> = Arc::from(tenant.into());
+        let busy = Arc::new(AtomicUsize::new(0));
+        let (ready_tx, ready_rx) = std_mpsc::channel::<()>();
+
+        for i in 0..size {
+            let receiver = receiver.clone();
+            let busy = busy.clone();
+            let code = code.clone();
+            let tenant = tenant.clone();
+            let config = config.clone();
+            let bindings = bindings.clone();
+            let ready = ready_tx.clone();
+            let ret = thread::Builder::new()
+                .name(format!("dino-worker-{i}"))
+                .spawn(move || {
+                    let worker = new_worker(&tenant, &code, &config, &bindings);
+                    // the sender must not live as long as the thread
+                    drop(ready);
+                    worker_loop(worker, &tenant, &code, &config, &bindings, receiver, &busy)
+                });
+            if let Err(e) = ret {
+                warn!("spawn js worker thread failed: {}", e);
+            }
+        }
+        // 等所有 worker 加载完代码再返回，swap 之后的第一个请求不需要再等待代码的执行。
+        // every thread drops its sender once its worker is built, or when it fails to spawn
+        drop(ready_tx);
+        let _ = ready_rx.recv();
+
+        Self {
+            sender,
+            size,
+            timeout: config.timeout(),
+            tenant,
+            code,
+            config: config.clone(),
+            bindings,
+            sessions: Arc::new(Semaphore::new(config.max_websocket_sessions)),
+            busy,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// number of workers running a task, streaming responses included
+    pub fn busy(&self) -> usize {
+        self.busy.load(Ordering::Relaxed)
+    }
+
+    /// number of tasks waiting for a free worker
+    pub fn queued(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    pub fn tenant(&self) -> &str {
+        &self.tenant
+    }
+
+    /// 在池中任意一个空闲的 worker 上执行 handler，排队和执行的总时间受 timeout 限制。
+    /// 流式响应在返回响应头之后，worker 会一直绑定在这个请求上，直到流结束或客户端断开
+    pub async fn run(
+        &self,
+        handler: impl Into<String>,
+        req: Req<JsBody>,
+    ) -> Result<Response, AppError> {
+        self.run_with_middleware(handler, Arc::from([]), req).await
+    }
+
+    /// run the handler after the middleware of its route, see `JsWorker::run_with_middleware`
+    pub async fn run_with_middleware(
+        &self,
+        handler: impl Into<String>,
+        middleware: Arc<[String]>,
+        req: Req<JsBody>,
+    ) -> Result<Response, AppError> {
+        let handler = handler.into();
+        let (tx, rx) = oneshot::channel();
+        let fut = async {
+            self.execute(move |worker| {
+                let start = Instant::now();
+                let ret = worker.run_with_middleware(&middleware, &handler, req);
+                // for streaming responses, the time to produce the headers
+                let elapsed = JsExecutionTime(start.elapsed());
+                // a made up status or header must not panic the worker thread
+                let invalid = |e: axum::http::Error| AppError::InvalidResponse {
+                    handler: handler.clone(),
+                    message: e.to_string(),
+                };
+                let res = match ret {
+                    Ok(res) if worker.is_streaming() => res,
+                    ret => {
+                        let ret = ret.and_then(|res| res.try_into_response().map_err(invalid));
+                        let _ = tx.send(ret.map(|mut res| {
+                            res.extensions_mut().insert(elapsed);
+                            res
+                        }));
+                        return;
+                    }
+                };
+                let (sender, receiver) = mpsc::channel::<Chunk>(STREAM_BUFFER_SIZE);
+                let body = Body::from_stream(stream::unfold(receiver, |mut rx| async move {
+                    rx.recv().await.map(|chunk| (chunk, rx))
+                }));
+                let mut res = match res.try_into_response_with_body(body) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        worker.cancel_stream();
+                        let _ = tx.send(Err(invalid(e)));
+                        return;
+                    }
+                };
+                res.extensions_mut().insert(elapsed);
+                if tx.send(Ok(res)).is_err() {
+                    worker.cancel_stream();
+                    return;
+                }
+                pump_stream(worker, sender);
+            })
+            .await?;
+            let ret: Result<Response, AppError> = rx
+                .await
+                .map_err(|_| anyhow!("js worker dropped the request without a response"))?;
+            ret
+        };
+        tokio::time::timeout(self.timeout, fut)
+            .await
+            .map_err(|_| AppError::LimitExceeded(ExecutionLimit::WallClock))?
+    }
+
+    /// 在 websocket upgrade 之前占用一个 session 名额，超过上限时直接拒绝
+    pub fn try_acquire_session(&self) -> Result<OwnedSemaphorePermit, AppError> {
+        self.sessions
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| AppError::LimitExceeded(ExecutionLimit::WebSocketSessions))
+    }
+
+    /// websocket 连接会一直占用一个线程，所以每个连接使用独立的线程和 JsWorker，
+    /// 不会占用处理 http 请求的 worker
+    pub async fn websocket(
+        &self,
+        handler: String,
+        req: Req<JsBody>,
+        socket: WebSocket,
+        permit: OwnedSemaphorePermit,
+    ) {
+        let (inbound_tx, inbound_rx) = mpsc::channel::<Message>(SOCKET_BUFFER_SIZE);
+        let (outbound_tx, mut outbound_rx) = mpsc::channel::<Message>(SOCKET_BUFFER_SIZE);
+        let (tenant, code) = (self.tenant.clone(), self.code.clone());
+        let (config, bindings) = (self.config.clone(), self.bindings.clone());
+        let ret = thread::Builder::new()
+            .name(format!("dino-ws-{handler}"))
+            .spawn(move || {
+                let worker = JsWorker::try_new_with_bindings(&code, &tenant, &config, &bindings);
+                match worker {
+                    Ok(worker) => session_loop(&worker, &handler, req, inbound_rx, outbound_tx),
+                    Err(e) => warn!("create js worker for websocket failed: {:?}", e),
+                }
+                drop(permit);
+            });
+        if let Err(e) = ret {
+            warn!("spawn websocket session thread failed: {}", e);
+            return;
+        }
+
+        let (mut sink, mut stream) = socket.split();
+        let writer = async move {
+            while let Some(msg) = outbound_rx.recv().await {
+                if sink.send(msg).await.is_err() {
+                    break;
+                }
+            }
+            let _ = sink.close().await;
+        };
+        // dropping inbound_tx tells the session the client went away
+        let reader = async move {
+            while let Some(Ok(msg)) = stream.next().await {
+                if inbound_tx.send(msg).await.is_err() {
+                    break;
+                }
+            }
+        };
+        // the writer finishes when the session ends, the reader when the client goes away
+        tokio::select! {
+            _ = writer => {},
+            _ = reader => {},
+        }
+    }
+
+    /// 把一个任务发送到 worker 线程执行，任务在 worker 线程中同步运行
+    pub async fn execute<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&JsWorker) + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(f))
+            .await
+            .map_err(|_| anyhow!("js worker pool is closed"))
+    }
+}
+
+// 逐块读取流式响应写入 body channel，channel 满时阻塞（背压），客户端断开后 channel 关闭
+fn pump_stream(worker: &JsWorker, sender: mpsc::Sender<Chunk>) {
+    loop {
+        if sender.is_closed() {
+            debug!("client disconnected, cancel the streaming response");
+            worker.cancel_stream();
+            return;
+        }
+        match worker.next_chunk() {
+            Ok(Some(chunk)) => {
+                if sender.blocking_send(Ok(chunk.into_bytes().into())).is_err() {
+                    debug!("client disconnected, cancel the streaming response");
+                    worker.cancel_stream();
+                    return;
+                }
+            }
+            Ok(None) => return,
+            Err(e) => {
+                warn!("streaming response failed: {}", e);
+                let _ = sender.blocking_send(Err(e));
+                return;
+            }
+        }
+    }
+}
+
+// 一个 websocket 连接的整个生命周期，所有回调都在同一个 JsWorker 上执行
+fn session_loop(
+    worker: &JsWorker,
+    handler: &str,
+    req: Req<JsBody>,
+    mut inbound: mpsc::Receiver<Message>,
+    outbound: mpsc::Sender<Message>,
+) {
+    if let Err(e) = worker.open_socket(handler, req, outbound.clone()) {
+        warn!("websocket handler {} failed to open: {}", handler, e);
+        let _ = outbound.blocking_send(internal_error_close());
+        return;
+    }
+
+    let (code, reason) = loop {
+        let ret = match inbound.blocking_recv() {
+            Some(Message::Text(s)) => worker.socket_message(JsBody::Text(s)),
+            Some(Message::Binary(b)) => worker.socket_message(JsBody::Binary(b)),
+            Some(Message::Close(frame)) => {
+                break frame
+                    .map(|f| (f.code, f.reason.into_owned()))
+                    .unwrap_or((1005, String::new()));
+            }
+            // ping / pong are answered by axum
+            Some(_) => continue,
+            // the connection dropped without a close frame
+            None => break (1006, String::new()),
+        };
+        if let Err(e) = ret {
+            warn!("websocket handler {} failed: {}", handler, e);
+            if worker.is_poisoned() {
+                let _ = outbound.blocking_send(internal_error_close());
+                return;
+            }
+        }
+    };
+    if let Err(e) = worker.close_socket(code, reason) {
+        warn!("websocket handler {} failed to close: {}", handler, e);
+    }
+}
+
+fn internal_error_close() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: 1011,
+        reason: "internal error".into(),
+    }))
+}
+
+fn new_worker(
+    tenant: &str,
+    code: &str,
+    config: &WorkerConfig,
+    bindings: &Bindings,
+) -> Option<JsWorker> {
+    match JsWorker::try_new_with_bindings(code, tenant, config, bindings) {
+        Ok(worker) => Some(worker),
+        Err(e) => {
+            warn!("create js worker failed: {:?}", e);
+            None
+        }
+    }
+}
+
+// 当 pool 被 drop 时 sender 关闭，worker 处理完队列中剩余的任务后退出
+fn worker_loop(
+    mut worker: Option<JsWorker>,
+    tenant: &str,
+    code: &str,
+    config: &WorkerConfig,
+    bindings: &Bindings,
+    receiver: Arc<Mutex<mpsc::Receiver<Task>>>,
+    busy: &AtomicUsize,
+) {
+    loop {
+        let task = match receiver.lock() {
+            Ok(mut rx) => rx.blocking_recv(),
+            Err(_) => None,
+        };
+        let Some(task) = task else { break };
+
+        // the worker failed to load the code, try again for this task
+        if worker.is_none() {
+            worker = new_worker(tenant, code, config, bindings);
+        }
+        // without a worker the task is dropped, the caller gets an error from the closed oneshot
+        let Some(w) = worker.as_ref() else { continue };
+        busy.fetch_add(1, Ordering::Relaxed);
+        task(w);
+        busy.fetch_sub(1, Ordering::Relaxed);
+        if w.is_poisoned() {
+            // replaced right away, so that the next task gets a warm worker
+            warn!("js worker exceeded its limits, recreating it");
+            worker = new_worker(tenant, code, config, bindings);
+        }
+    }
+    debug!(
+        "js worker {:?} drained and exited",
+        thread::current().name()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    async fn body_text(res: Response) -> Result<String> {
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        Ok(String::from_utf8(body.to_vec())?)
+    }
+
+    #[tokio::test]
+    async fn worker_pool_should_work() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function hello(req){
+                return {
+                    status:200,
+                    headers:{
+                        "content-type":"application/json"
+                    },
+                    body: req.params.id
+                };
+            }
+            return{hello:hello};
+        })()"#;
+
+        let config = WorkerConfig {
+            pool_size: 2,
+            ..Default::default()
+        };
+        let pool = Arc::new(WorkerPool::new("test", code, &config, Bindings::default()));
+        let mut tasks = Vec::new();
+        for i in 0..8 {
+            let pool = pool.clone();
+            let req = Req::builder()
+                .method("GET")
+                .url("https://example.com")
+                .params(HashMap::from([("id".to_string(), i.to_string())]))
+                .build();
+            tasks.push(tokio::spawn(async move { pool.run("hello", req).await }));
+        }
+        for (i, task) in tasks.into_iter().enumerate() {
+            let ret = task.await??;
+            assert_eq!(ret.status(), 200);
+            assert_eq!(body_text(ret).await?, i.to_string());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn worker_pool_should_load_code_before_returning() -> Result<()> {
+        let code = r#"
+        (function(){
+            Dino.kv.put(`loaded:${Math.random()}`, "1");
+            async function hello(req){
+                return { status: 200, headers: {}, body: "ok" };
+            }
+            return{hello:hello};
+        })()"#;
+        let config = WorkerConfig {
+            pool_size: 2,
+            ..Default::default()
+        };
+        let bindings = Bindings::default();
+        let _pool = WorkerPool::new("test", code, &config, bindings.clone());
+        assert_eq!(bindings.kv.list("loaded:", 10)?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn worker_pool_should_reject_invalid_status_and_headers() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function status(req){
+                return { status: 1000, headers: {}, body: "x" };
+            }
+            async function header(req){
+                return { status: 200, headers: { "bad header": "x" }, body: "x" };
+            }
+            async function* chunks() { yield "x"; }
+            async function stream(req){
+                return new Response(chunks(), { headers: { "x-dino": "a\nb" } });
+            }
+            async function hello(req){
+                return { status: 200, headers: {}, body: "ok" };
+            }
+            return{status:status, header:header, stream:stream, hello:hello};
+        })()"#;
+        let config = WorkerConfig {
+            pool_size: 1,
+            ..Default::default()
+        };
+        let pool = WorkerPool::new("test", code, &config, Bindings::default());
+        let req = || Req::builder().method("GET").url("/").build();
+        for handler in ["status", "header", "stream"] {
+            let ret = pool.run(handler, req()).await;
+            assert!(
+                matches!(ret, Err(AppError::InvalidResponse { .. })),
+                "{handler}: {ret:?}"
+            );
+        }
+        // the worker thread is still alive
+        let ret = pool.run("hello", req()).await?;
+        assert_eq!(body_text(ret).await?, "ok");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn worker_pool_with_invalid_code_should_fail() {
+        let pool = WorkerPool::new("test", "", &WorkerConfig::default(), Bindings::default());
+        let req = Req::builder().method("GET").url("/").build();
+        assert!(pool.run("hello", req).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn worker_pool_should_recover_after_limit_exceeded() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function spin(req){
+                while(true){}
+            }
+            async function hello(req){
+                return { status: 200, headers: {}, body: "ok" };
+            }
+            return{spin:spin, hello:hello};
+        })()"#;
+        let config = WorkerConfig {
+            pool_size: 1,
+            cpu_time_ms: 50,
+            ..Default::default()
+        };
+        let pool = WorkerPool::new("test", code, &config, Bindings::default());
+        let req = || Req::builder().method("GET").url("/").build();
+        let ret = pool.run("spin", req()).await;
+        assert!(matches!(
+            ret,
+            Err(AppError::LimitExceeded(ExecutionLimit::CpuTime))
+        ));
+        let ret = pool.run("hello", req()).await?;
+        assert_eq!(body_text(ret).await?, "ok");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn worker_pool_should_stream_response() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function* numbers() {
+                for (let i = 0; i < 3; i++) yield `${i}
+`;
+            }
+            async function stream(req){
+                return new Response(numbers(), { headers: { "content-type": "text/plain" } });
+            }
+            async function events(req){
+                return Response.sse([{ event: "tick", data: { n: 1 } }, "bye"]);
+            }
+            return{stream:stream, events:events};
+        })()"#;
+        let config = WorkerConfig {
+            pool_size: 1,
+            ..Default::default()
+        };
+        let pool = WorkerPool::new("test", code, &config, Bindings::default());
+        let req = || Req::builder().method("GET").url("/").build();
+
+        let ret = pool.run("stream", req()).await?;
+        assert_eq!(ret.status(), 200);
+        assert_eq!(body_text(ret).await?, "0\n1\n2\n");
+
+        let ret = pool.run("events", req()).await?;
+        assert_eq!(
+            ret.headers().get("content-type").unwrap(),
+            "text/event-stream"
+        );
+        assert_eq!(
+            body_text(ret).await?,
+            "event: tick\ndata: {\"n\":1}\n\ndata: bye\n\n"
+        );
+
+        // the client goes away before reading the body, the worker must be released
+        let ret = pool.run("stream", req()).await?;
+        drop(ret);
+        let ret = pool.run("events", req()).await?;
+        assert_eq!(ret.status(), 200);
+        Ok(())
+    }
+}
@@ -1,21 +1,47 @@
-use anyhow::Result;
-use arc_swap::ArcSwap;
-use axum::http::Method;
+use anyhow::{bail, Result};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use axum::http::{HeaderName, Method};
 use matchit::{Match, Router};
 use std::{ops::Deref, sync::Arc};
+use tokio_util::sync::CancellationToken;
+use tower_http::cors::CorsLayer;
 
-use crate::{AppError, ProjectRoutes};
+use crate::{
+    cors::cors_layer, new_kv_store, AppError, AuthConfig, Authenticator, Bindings, Canary, KvStore,
+    Limiter, ProjectConfig, RouteInfo, RouteKind, Schedule, StaticFiles, WorkerPool,
+};
 
 // arcswap 类似于golang的atomic.Value，适用场景，数据的修改次数非常少，
 // 且每次修改都重建的代价不大，直接原子内存替换，如果经常修改，且重建数据代价特别大，请使用dashmap
 #[derive(Clone)]
 pub struct SwappableAppRouter {
     pub inner: Arc<ArcSwap<AppRouterInner>>,
+    // kv 数据属于 tenant 而不是某个版本的代码，swap 时保持不变
+    kv: Arc<dyn KvStore>,
+    // a second version receiving part of the traffic, see `select`
+    pub(crate) canary: Arc<ArcSwapOption<Canary>>,
+    // cancelled when the tenant is removed or the server shuts down
+    pub(crate) closed: CancellationToken,
 }
 
 pub struct AppRouterInner {
     pub code: String,
+    // blake3 of the code, identifies the deployed version
+    pub hash: String,
     pub router: Router<MethodRoute>,
+    // 每个版本的代码对应一个 worker pool，swap 后旧的 pool 在没有请求引用时被 drop 并退出
+    pub pool: WorkerPool,
+    pub schedules: Vec<Schedule>,
+    // rate limits and concurrency quota of the tenant, swapped together with the code
+    pub limiter: Limiter,
+    // none when the tenant has no `cors` config
+    pub cors: Option<CorsLayer>,
+    // files of the `static` dirs, matched before the routes
+    pub static_files: StaticFiles,
+    // the route table of config.yml, with the middleware of each route
+    pub routes: Vec<RouteInfo>,
+    // the headers of the `api_key` auths, never logged in plaintext
+    pub api_key_headers: Vec<HeaderName>,
 }
 
 #[derive(Clone)]
@@ -23,53 +49,102 @@ pub struct AppRouter(Arc<AppRouterInner>);
 
 #[derive(Debug, Default, Clone)]
 pub struct MethodRoute {
-    get: Option<String>,
-    post: Option<String>,
-    delete: Option<String>,
-    head: Option<String>,
-    options: Option<String>,
-    patch: Option<String>,
-    put: Option<String>,
-    trace: Option<String>,
-    connect: Option<String>,
+    get: Option<RouteHandler>,
+    post: Option<RouteHandler>,
+    delete: Option<RouteHandler>,
+    head: Option<RouteHandler>,
+    options: Option<RouteHandler>,
+    patch: Option<RouteHandler>,
+    put: Option<RouteHandler>,
+    trace: Option<RouteHandler>,
+    connect: Option<RouteHandler>,
+    websocket: Option<RouteHandler>,
+}
+
+/// the handler of a route with its auth and the middleware run before it
+#[derive(Debug, Clone)]
+pub struct RouteHandler {
+    pub handler: String,
+    pub middleware: Arc<[String]>,
+    // verified before the middleware run
+    pub auth: Option<Arc<Authenticator>>,
 }
 
 impl SwappableAppRouter {
-    pub fn try_new(code: impl Into<String>, routes: ProjectRoutes) -> Result<Self> {
-        let router = Self::get_router(routes)?;
-        let inner = AppRouterInner::new(code, router);
+    pub fn try_new(code: impl Into<String>, config: ProjectConfig) -> Result<Self> {
+        let router = Self::get_router(&config)?;
+        let kv = new_kv_store(&config.kv)?;
+        let inner = AppRouterInner::try_new(code, router, &config, kv.clone())?;
         Ok(Self {
             inner: Arc::new(ArcSwap::from_pointee(inner)),
+            kv,
+            canary: Arc::default(),
+            closed: CancellationToken::new(),
         })
     }
 
-    pub fn swap(&self, code: impl Into<String>, routes: ProjectRoutes) -> Result<()> {
-        let router = Self::get_router(routes)?;
-        let inner = AppRouterInner::new(code, router);
+    /// 停止 tenant 的后台任务（例如 schedules），已经开始的请求不受影响
+    pub fn close(&self) {
+        self.closed.cancel();
+    }
+
+    // 新代码会创建新的 worker pool，新的请求只会拿到新的 pool，
+    // 旧的 pool 在进行中的请求结束后被 drop，worker 处理完队列后退出
+    pub fn swap(&self, code: impl Into<String>, config: ProjectConfig) -> Result<()> {
+        let inner = self.build(code, config)?;
         self.inner.store(Arc::new(inner));
         Ok(())
     }
 
+    // a new version of the tenant, sharing the kv store with the running one
+    pub(crate) fn build(
+        &self,
+        code: impl Into<String>,
+        config: ProjectConfig,
+    ) -> Result<AppRouterInner> {
+        let router = Self::get_router(&config)?;
+        AppRouterInner::try_new(code, router, &config, self.kv.clone())
+    }
+
     pub fn load(&self) -> AppRouter {
         AppRouter(self.inner.load_full())
     }
 
-    fn get_router(routes: ProjectRoutes) -> Result<Router<MethodRoute>> {
+    fn get_router(config: &ProjectConfig) -> Result<Router<MethodRoute>> {
         let mut router = Router::new();
-        for (path, methods) in routes {
+        for (path, methods) in &config.routes {
             let mut method_route = MethodRoute::default();
             for method in methods {
+                let route = Some(RouteHandler {
+                    handler: method.handler.clone(),
+                    middleware: config.middleware_of(method).into(),
+                    auth: method
+                        .auth
+                        .as_ref()
+                        .map(|auth| Authenticator::try_new(auth).map(Arc::new))
+                        .transpose()?,
+                });
+                if method.kind == RouteKind::Websocket {
+                    if !method.middleware.is_empty() {
+                        bail!("websocket route {path} can't have middleware");
+                    }
+                    if method.auth.is_some() {
+                        bail!("websocket route {path} can't have auth");
+                    }
+                    method_route.websocket = route;
+                    continue;
+                }
                 match method.method {
-                    Method::GET => method_route.get = Some(method.handler),
-                    Method::HEAD => method_route.head = Some(method.handler),
-                    Method::DELETE => method_route.delete = Some(method.handler),
-                    Method::OPTIONS => method_route.options = Some(method.handler),
-                    Method::POST => method_route.post = Some(method.handler),
-                    Method::PATCH => method_route.patch = Some(method.handler),
-                    Method::PUT => method_route.put = Some(method.handler),
-                    Method::TRACE => method_route.trace = Some(method.handler),
-                    Method::CONNECT => method_route.connect = Some(method.handler),
-                    v => unreachable!("unsupported method {v}"),
+                    Method::GET => method_route.get = route,
+                    Method::HEAD => method_route.head = route,
+                    Method::DELETE => method_route.delete = route,
+                    Method::OPTIONS => method_route.options = route,
+                    Method::POST => method_route.post = route,
+                    Method::PATCH => method_route.patch = route,
+                    Method::PUT => method_route.put = route,
+                    Method::TRACE => method_route.trace = route,
+                    Method::CONNECT => method_route.connect = route,
+                    _ => unreachable!("unsupported method {}", method.method),
                 }
             }
 
@@ -84,7 +159,23 @@ impl AppRouter {
         &'this self,
         method: Method,
         path: &'path str,
-    ) -> Result<Match<&str>, AppError>
+    ) -> Result<Match<'this, 'path, &'this str>, AppError>
+    where
+        'path: 'this,
+    {
+        let ret = self.match_route(method, path)?;
+        Ok(Match {
+            value: ret.value.handler.as_str(),
+            params: ret.params,
+        })
+    }
+
+    /// like match_it, with the middleware of the route
+    pub fn match_route<'this, 'path>(
+        &'this self,
+        method: Method,
+        path: &'path str,
+    ) -> Result<Match<'this, 'path, &'this RouteHandler>, AppError>
     where
         'path: 'this,
     {
@@ -93,15 +184,15 @@ impl AppRouter {
         };
 
         let s = match method {
-            Method::GET => ret.value.get.as_deref(),
-            Method::HEAD => ret.value.head.as_deref(),
-            Method::PATCH => ret.value.patch.as_deref(),
-            Method::POST => ret.value.post.as_deref(),
-            Method::PUT => ret.value.put.as_deref(),
-            Method::DELETE => ret.value.delete.as_deref(),
-            Method::OPTIONS => ret.value.options.as_deref(),
-            Method::TRACE => ret.value.trace.as_deref(),
-            Method::CONNECT => ret.value.connect.as_deref(),
+            Method::GET => ret.value.get.as_ref(),
+            Method::HEAD => ret.value.head.as_ref(),
+            Method::PATCH => ret.value.patch.as_ref(),
+            Method::POST => ret.value.post.as_ref(),
+            Method::PUT => ret.value.put.as_ref(),
+            Method::DELETE => ret.value.delete.as_ref(),
+            Method::OPTIONS => ret.value.options.as_ref(),
+            Method::TRACE => ret.value.trace.as_ref(),
+            Method::CONNECT => ret.value.connect.as_ref(),
             _ => unreachable!(),
         };
         let s = s.ok_or_else(|| AppError::RouteMethodNotAllowed(method))?;
@@ -112,12 +203,90 @@ impl AppRouter {
     }
 }
 
+impl AppRouter {
+    /// websocket upgrade requests only match `kind: websocket` routes
+    pub fn match_websocket<'this, 'path>(
+        &'this self,
+        path: &'path str,
+    ) -> Result<Match<'this, 'path, &'this str>, AppError>
+    where
+        'path: 'this,
+    {
+        let Ok(ret) = self.router.at(path) else {
+            return Err(AppError::RoutePathNotFound(path.to_string()));
+        };
+        let s = ret
+            .value
+            .websocket
+            .as_ref()
+            .ok_or(AppError::RouteMethodNotAllowed(Method::GET))?;
+        Ok(Match {
+            value: s.handler.as_str(),
+            params: ret.params,
+        })
+    }
+}
+
 impl AppRouterInner {
-    pub fn new(code: impl Into<String>, router: Router<MethodRoute>) -> Self {
-        Self {
-            code: code.into(),
+    pub fn try_new(
+        code: impl Into<String>,
+        router: Router<MethodRoute>,
+        config: &ProjectConfig,
+        kv: Arc<dyn KvStore>,
+    ) -> Result<Self> {
+        // invalid cron expressions are rejected before the new code goes live
+        let schedules = config
+            .schedules
+            .iter()
+            .cloned()
+            .map(Schedule::try_new)
+            .collect::<Result<Vec<_>>>()?;
+        let limiter = Limiter::try_new(&config.limits)?;
+        let cors = config.cors.as_ref().map(cors_layer).transpose()?;
+        let static_files = StaticFiles::try_new(&config.static_files)?;
+        let routes = config.route_table();
+        let mut api_key_headers: Vec<HeaderName> = config
+            .routes
+            .values()
+            .flatten()
+            .filter_map(|route| match &route.auth {
+                Some(AuthConfig::ApiKey(auth)) => Some(auth.header.to_ascii_lowercase()),
+                _ => None,
+            })
+            .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+            .collect();
+        api_key_headers.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        api_key_headers.dedup();
+        let code = code.into();
+        let hash = code_hash(&code);
+        // secrets are resolved again on every swap, a missing one keeps the old code running
+        let env = Arc::new(config.resolve_env()?);
+        let bindings = Bindings { kv, env };
+        let pool = WorkerPool::new(&config.name, code.clone(), &config.worker, bindings);
+        Ok(Self {
+            code,
+            hash,
             router,
-        }
+            pool,
+            schedules,
+            limiter,
+            cors,
+            static_files,
+            routes,
+            api_key_headers,
+        })
+    }
+}
+
+pub fn code_hash(code: &str) -> String {
+    let mut hash = blake3::hash(code.as_bytes()).to_string();
+    hash.truncate(16);
+    hash
+}
+
+impl From<Arc<AppRouterInner>> for AppRouter {
+    fn from(inner: Arc<AppRouterInner>) -> Self {
+        Self(inner)
     }
 }
 
@@ -132,6 +301,7 @@ impl Deref for AppRouter {
 #[cfg(test)]
 mod tests {
     use crate::config::ProjectConfig;
+    use axum::body::to_bytes;
 
     use super::*;
 
@@ -139,7 +309,7 @@ mod tests {
     fn app_router_match_should_work() {
         let config = include_str!("../fixtures/config.yml");
         let config: ProjectConfig = serde_yml::from_str(config).unwrap();
-        let router = SwappableAppRouter::try_new("", config.routes).unwrap();
+        let router = SwappableAppRouter::try_new("", config).unwrap();
         let app_router = router.load();
 
         let m = app_router.match_it(Method::GET, "/api/hello/1").unwrap();
@@ -164,11 +334,60 @@ mod tests {
         assert_eq!(m.params.get("id"), Some("3"));
     }
 
+    #[test]
+    fn app_router_match_websocket_should_work() {
+        let config = include_str!("../fixtures/config.yml");
+        let config: ProjectConfig = serde_yml::from_str(config).unwrap();
+        let router = SwappableAppRouter::try_new("", config).unwrap();
+        let app_router = router.load();
+
+        let m = app_router.match_websocket("/ws/lobby").unwrap();
+        assert_eq!(m.value, "chat");
+        assert_eq!(m.params.get("room"), Some("lobby"));
+
+        // websocket routes are not reachable with plain http requests and vice versa
+        assert!(app_router.match_it(Method::GET, "/ws/lobby").is_err());
+        assert!(app_router.match_websocket("/api/hello/1").is_err());
+    }
+
+    #[test]
+    fn app_router_match_route_should_return_middleware() -> Result<()> {
+        let config: ProjectConfig = serde_yml::from_str(
+            r#"
+            name: test
+            middleware: [log]
+            routes:
+              /api/:id:
+                - method: GET
+                  handler: get
+                  middleware: [auth, tag]
+                - method: POST
+                  handler: post
+              /ws:
+                - kind: websocket
+                  handler: chat
+            "#,
+        )?;
+        let router = SwappableAppRouter::try_new("", config.clone())?.load();
+        let m = router.match_route(Method::GET, "/api/1")?;
+        assert_eq!(m.value.handler, "get");
+        assert_eq!(&*m.value.middleware, ["log", "auth", "tag"]);
+        let m = router.match_route(Method::POST, "/api/1")?;
+        assert_eq!(&*m.value.middleware, ["log"]);
+        assert_eq!(router.routes.len(), 3);
+        assert!(router.routes[2].middleware.is_empty());
+
+        let mut config = config;
+        config.routes["/ws"][0].middleware = vec!["auth".to_string()];
+        assert!(SwappableAppRouter::try_new("", config).is_err());
+        Ok(())
+    }
+
     #[test]
     fn app_router_swap_should_work() {
         let config = include_str!("../fixtures/config.yml");
         let config: ProjectConfig = serde_yml::from_str(config).unwrap();
-        let router = SwappableAppRouter::try_new("", config.routes).unwrap();
+        let router = SwappableAppRouter::try_new("", config).unwrap();
         let app_router = router.load();
         let m = app_router.match_it(Method::GET, "/api/world/3").unwrap();
         assert_eq!(m.value, "hello3");
@@ -185,7 +404,7 @@ mod tests {
 
         let new_config = include_str!("../fixtures/config-change.yml");
         let new_config: ProjectConfig = serde_yml::from_str(new_config).unwrap();
-        router.swap("", new_config.routes).unwrap();
+        router.swap("", new_config).unwrap();
         let app_router = router.load();
         let m = app_router.match_it(Method::GET, "/api/world/3").unwrap();
         assert_eq!(m.value, "handle1");
@@ -200,4 +419,40 @@ mod tests {
         assert_eq!(m.params.get("name"), Some("fake"));
         assert_eq!(m.params.get("id"), Some("3"));
     }
+
+    #[tokio::test]
+    async fn app_router_swap_should_rebuild_worker_pool() -> Result<()> {
+        let code = |body: &str| {
+            format!(
+                r#"(function(){{
+                    async function hello1(req){{
+                        return {{ status: 200, headers: {{}}, body: "{body}" }};
+                    }}
+                    return {{ hello1: hello1 }};
+                }})()"#
+            )
+        };
+        let config = include_str!("../fixtures/config.yml");
+        let config: ProjectConfig = serde_yml::from_str(config)?;
+        let router = SwappableAppRouter::try_new(code("v1"), config.clone())?;
+        let req = || {
+            crate::Req::builder()
+                .method("GET")
+                .url("/api/hello/1")
+                .build()
+        };
+
+        let app_router = router.load();
+        let res = app_router.pool.run("hello1", req()).await?;
+        assert_eq!(to_bytes(res.into_body(), usize::MAX).await?, "v1");
+
+        router.swap(code("v2"), config)?;
+        let res = router.load().pool.run("hello1", req()).await?;
+        assert_eq!(to_bytes(res.into_body(), usize::MAX).await?, "v2");
+
+        // request which loaded the old router still finishes on the old workers
+        let res = app_router.pool.run("hello1", req()).await?;
+        assert_eq!(to_bytes(res.into_body(), usize::MAX).await?, "v1");
+        Ok(())
+    }
 }
@@ -0,0 +1,247 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use axum::response::Response;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde_json::json;
+use tokio::{
+    sync::Mutex,
+    time::{Instant, MissedTickBehavior},
+};
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{
+    middleware::REQUEST_ID_HEADER, AppError, AppRouter, Executions, JsBody, OverlapPolicy, Req,
+    ScheduleConfig, SwappableAppRouter,
+};
+
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub config: ScheduleConfig,
+    cron: cron::Schedule,
+}
+
+impl Schedule {
+    pub fn try_new(config: ScheduleConfig) -> Result<Self> {
+        let expr = config.cron.trim();
+        // the cron crate wants a leading seconds field, `*/5 * * * *` means every 5 minutes
+        let expr = if expr.split_whitespace().count() == 5 {
+            format!("0 {expr}")
+        } else {
+            expr.to_string()
+        };
+        let cron = cron::Schedule::from_str(&expr)
+            .map_err(|e| anyhow!("invalid cron expression {:?}: {}", config.cron, e))?;
+        Ok(Self { config, cron })
+    }
+
+    /// the fire time of the schedule in (from, to], if any
+    pub fn due(&self, from: &DateTime<Utc>, to: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.cron.after(from).next().filter(|t| t <= to)
+    }
+
+    // runs of the same schedule share the overlap lock, also across swaps
+    fn key(&self) -> String {
+        format!("{} {}", self.config.cron, self.config.handler)
+    }
+}
+
+/// 每个 tenant 一个 ticker，每秒检查一次到期的 schedule。
+/// 每次都从 SwappableAppRouter 读取，swap 之后自动使用新的配置和代码。
+/// runs are counted in `executions`, so that a graceful shutdown waits for them
+pub async fn run_schedules(router: SwappableAppRouter, executions: Executions) {
+    // the ticker stops once the router is closed, or every clone of it is dropped
+    let closed = router.closed.clone();
+    let router = Arc::downgrade(&router.inner);
+    let mut locks: HashMap<String, Arc<Mutex<()>>> = HashMap::new();
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut last = Utc::now();
+    loop {
+        tokio::select! {
+            _ = closed.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+        let now = Utc::now();
+        let Some(inner) = router.upgrade() else {
+            break;
+        };
+        let app_router = AppRouter::from(inner.load_full());
+        for schedule in app_router.schedules.iter() {
+            let Some(time) = schedule.due(&last, &now) else {
+                continue;
+            };
+            let lock = locks.entry(schedule.key()).or_default().clone();
+            let fut = fire(app_router.clone(), schedule.clone(), time, lock);
+            let execution = executions.start();
+            tokio::spawn(async move {
+                fut.await;
+                drop(execution);
+            });
+        }
+        last = now;
+    }
+}
+
+async fn fire(router: AppRouter, schedule: Schedule, time: DateTime<Utc>, lock: Arc<Mutex<()>>) {
+    let config = &schedule.config;
+    if config.jitter_ms > 0 {
+        let delay = rand::thread_rng().gen_range(0..config.jitter_ms);
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+    }
+    let _guard = match config.overlap {
+        OverlapPolicy::Skip => match lock.try_lock_owned() {
+            Ok(guard) => Some(guard),
+            Err(_) => {
+                warn!(
+                    tenant = %router.pool.tenant(),
+                    handler = %config.handler,
+                    cron = %config.cron,
+                    "previous run is still in progress, skipped"
+                );
+                return;
+            }
+        },
+        OverlapPolicy::Queue => Some(lock.lock_owned().await),
+        OverlapPolicy::Allow => None,
+    };
+    let _ = trigger(&router, config, time).await;
+}
+
+/// 执行一次 schedule 的 handler：构造一个 json body 的 POST 请求，和 http 请求一样经过 pool，
+/// handler 中通过 `await req.json()` 拿到 `{type, cron, handler, scheduledTime}`
+pub async fn trigger(
+    router: &AppRouter,
+    config: &ScheduleConfig,
+    time: DateTime<Utc>,
+) -> Result<Response, AppError> {
+    let request_id = uuid::Uuid::now_v7().to_string();
+    let event = json!({
+        "type": "scheduled",
+        "cron": config.cron,
+        "handler": config.handler,
+        "scheduledTime": time.to_rfc3339(),
+    });
+    let headers = HashMap::from([
+        (REQUEST_ID_HEADER.to_string(), request_id.clone()),
+        ("content-type".to_string(), "application/json".to_string()),
+    ]);
+    let req = Req::builder()
+        .method("POST")
+        .url(format!("/__schedule/{}", config.handler))
+        .headers(headers)
+        .body(Some(JsBody::Text(event.to_string())))
+        .build();
+
+    let span = info_span!(
+        "schedule",
+        tenant = %router.pool.tenant(),
+        handler = %config.handler,
+        cron = %config.cron,
+        request_id = %request_id,
+    );
+    async move {
+        let start = Instant::now();
+        info!("schedule started");
+        let ret = router.pool.run(config.handler.as_str(), req).await;
+        let elapsed_ms = start.elapsed().as_millis() as u64;
+        match &ret {
+            Ok(res) if res.status().is_success() => {
+                info!(
+                    status = res.status().as_u16(),
+                    elapsed_ms, "schedule finished"
+                )
+            }
+            Ok(res) => warn!(
+                status = res.status().as_u16(),
+                elapsed_ms, "schedule finished"
+            ),
+            Err(e) => warn!(elapsed_ms, "schedule failed: {}", e),
+        }
+        ret
+    }
+    .instrument(span)
+    .await
+}
+
+/// run the schedule of `handler` right now, used by `dino run --trigger`
+pub async fn trigger_now(router: &AppRouter, handler: &str) -> Result<Response, AppError> {
+    let Some(schedule) = router
+        .schedules
+        .iter()
+        .find(|s| s.config.handler == handler)
+    else {
+        return Err(anyhow!("no schedule for handler {handler}").into());
+    };
+    trigger(router, &schedule.config, Utc::now()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(cron: &str) -> Result<Schedule> {
+        Schedule::try_new(ScheduleConfig {
+            cron: cron.to_string(),
+            handler: "tick".to_string(),
+            overlap: OverlapPolicy::Skip,
+            jitter_ms: 0,
+        })
+    }
+
+    #[test]
+    fn schedule_due_should_work() -> Result<()> {
+        let from: DateTime<Utc> = "2024-08-01T10:04:30Z".parse()?;
+        let to: DateTime<Utc> = "2024-08-01T10:05:00Z".parse()?;
+
+        let every_5_minutes = schedule("*/5 * * * *")?;
+        assert_eq!(every_5_minutes.due(&from, &to), Some(to));
+        assert_eq!(
+            every_5_minutes.due(&to, &(to + chrono::Duration::seconds(1))),
+            None
+        );
+
+        let every_10_seconds = schedule("*/10 * * * * *")?;
+        let t: DateTime<Utc> = "2024-08-01T10:04:40Z".parse()?;
+        assert_eq!(every_10_seconds.due(&from, &to), Some(t));
+
+        assert!(schedule("not a cron").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn trigger_should_call_handler_with_event() -> Result<()> {
+        let code = r#"
+        (function(){
+            async function tick(req){
+                const event = await req.json();
+                return { status: 200, headers: {}, body: `${event.type} ${event.handler}` };
+            }
+            return{tick:tick};
+        })()"#;
+        let config = include_str!("../fixtures/config.yml");
+        let config: crate::ProjectConfig = serde_yml::from_str(config)?;
+        let router = SwappableAppRouter::try_new(code, config)?;
+        let schedule = schedule("* * * * *")?;
+
+        let res = trigger(&router.load(), &schedule.config, Utc::now()).await?;
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        assert_eq!(body, "scheduled tick");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_schedules_should_stop_when_closed() -> Result<()> {
+        let config = include_str!("../fixtures/config.yml");
+        let config: crate::ProjectConfig = serde_yml::from_str(config)?;
+        let router = SwappableAppRouter::try_new("(function(){ return {}; })()", config)?;
+        let task = tokio::spawn(run_schedules(router.clone(), Executions::default()));
+        // the router is still referenced, only closing it stops the ticker
+        router.close();
+        tokio::time::timeout(Duration::from_secs(1), task).await??;
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tracing::{info, warn};
+
+// shutdown is rare, polling keeps the counter a plain atomic on the request path
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// the requests and schedule runs of a server that have not finished yet, including
+/// streaming responses and websocket sessions. every server has its own counter
+#[derive(Debug, Clone, Default)]
+pub struct Executions(Arc<AtomicUsize>);
+
+/// held while an execution is in flight, graceful shutdown waits for all of them
+#[derive(Debug)]
+pub(crate) struct ExecutionGuard(Arc<AtomicUsize>);
+
+impl Executions {
+    pub(crate) fn start(&self) -> ExecutionGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        ExecutionGuard(self.0.clone())
+    }
+
+    pub fn active(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    async fn wait_idle(&self) {
+        while self.active() > 0 {
+            tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+        }
+    }
+}
+
+impl Drop for ExecutionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// 优雅退出：shutdown 触发后 servers 停止接受新连接，等待进行中的请求和 js 执行结束，
+/// 超过 timeout 后不再等待，剩下的请求随进程退出被中断
+pub(crate) async fn drain<S, E>(
+    servers: S,
+    shutdown: impl Future<Output = ()>,
+    timeout: Duration,
+    executions: &Executions,
+) -> Result<(), E>
+where
+    S: Future<Output = Result<(), E>>,
+{
+    let finished = async {
+        servers.await?;
+        executions.wait_idle().await;
+        Ok(())
+    };
+    let deadline = async {
+        shutdown.await;
+        info!("shutting down, waiting up to {timeout:?} for in-flight requests");
+        tokio::time::sleep(timeout).await;
+    };
+    tokio::select! {
+        ret = finished => ret,
+        _ = deadline => {
+            warn!("shutdown timed out, {} executions are dropped", executions.active());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_should_wait_for_executions_until_deadline() {
+        // the server has stopped while an execution is still running
+        let (tx, rx) = oneshot::channel::<()>();
+        let executions = Executions::default();
+        let guard = executions.start();
+        let servers = async { Ok::<_, ()>(()) };
+        let shutdown = async {
+            let _ = rx.await;
+        };
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(guard);
+            let _ = tx.send(());
+        });
+        let start = Instant::now();
+        assert!(
+            drain(servers, shutdown, Duration::from_secs(10), &executions)
+                .await
+                .is_ok()
+        );
+        assert_eq!(executions.active(), 0);
+        // the server finished at once, but the execution is waited for
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // a request that never finishes is dropped at the deadline
+        let servers = std::future::pending::<Result<(), ()>>();
+        let start = Instant::now();
+        let _guard = executions.start();
+        let ret = drain(servers, async {}, Duration::from_millis(200), &executions).await;
+        assert!(ret.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use axum::{
+    body::Bytes,
+    http::{header, request::Parts, HeaderMap, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use percent_encoding::percent_decode_str;
+
+use crate::StaticDir;
+
+// the files are kept in memory with the code, large media belongs to a cdn
+const MAX_STATIC_SIZE: u64 = 64 * 1024 * 1024;
+const INDEX_FILE: &str = "index.html";
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+// without max_age_secs the files are cached but revalidated on every use
+const NO_CACHE: &str = "no-cache";
+
+/// config.yml 中 `static` 配置的静态文件，创建 router 时读入内存，和代码一起 swap。
+/// 以 `.` 开头的文件和目录不会被读取，避免把 .env 之类的文件暴露出去，
+/// 指向目录之外的链接和 fifo、设备之类的特殊文件也会被跳过
+#[derive(Debug, Default)]
+pub struct StaticFiles {
+    // the longest prefix is tried first
+    mounts: Vec<Mount>,
+}
+
+#[derive(Debug)]
+struct Mount {
+    // without the trailing slash, empty for `/`
+    prefix: String,
+    cache_control: HeaderValue,
+    // path relative to the dir, separated by `/`
+    files: HashMap<String, StaticFile>,
+}
+
+#[derive(Debug)]
+struct StaticFile {
+    body: Bytes,
+    content_type: HeaderValue,
+    etag: HeaderValue,
+}
+
+impl StaticFiles {
+    pub fn try_new(config: &BTreeMap<String, StaticDir>) -> Result<Self> {
+        let mut size = 0;
+        let mut mounts = Vec::with_capacity(config.len());
+        for (prefix, static_dir) in config {
+            if !prefix.starts_with('/') {
+                bail!("static prefix {prefix:?} must start with `/`");
+            }
+            let cache_control = match static_dir.max_age() {
+                Some(max_age) => format!("public, max-age={}", max_age.as_secs()),
+                None => NO_CACHE.to_string(),
+            };
+            let mut mount = Mount {
+                prefix: prefix.trim_end_matches('/').to_string(),
+                cache_control: HeaderValue::from_str(&cache_control)?,
+                files: HashMap::new(),
+            };
+            let dir = static_dir.dir();
+            let root = match fs::canonicalize(dir) {
+                Ok(root) if root.is_dir() => root,
+                _ => bail!("static dir {} is not found", dir.display()),
+            };
+            let mut loader = Loader {
+                root: root.clone(),
+                files: &mut mount.files,
+                size: &mut size,
+                visited: HashSet::new(),
+            };
+            loader.load_dir(&root, "")?;
+            mounts.push(mount);
+        }
+        mounts.sort_by_key(|m| Reverse(m.prefix.len()));
+        Ok(Self { mounts })
+    }
+
+    /// serve the file of a GET / HEAD request, none when no file matches the path
+    /// so that the request goes on to the js routes
+    pub fn serve(&self, parts: &Parts) -> Option<Response> {
+        if parts.method != Method::GET && parts.method != Method::HEAD {
+            return None;
+        }
+        let path = percent_decode_str(parts.uri.path()).decode_utf8().ok()?;
+        self.mounts.iter().find_map(|mount| {
+            let rest = path.strip_prefix(&mount.prefix)?;
+            if !rest.is_empty() && !rest.starts_with('/') {
+                return None;
+            }
+            let rest = rest.trim_start_matches('/');
+            let file = if rest.is_empty() || rest.ends_with('/') {
+                mount.files.get(&format!("{rest}{INDEX_FILE}"))
+            } else {
+                mount.files.get(rest)
+            }?;
+            Some(file.response(&mount.cache_control, &parts.headers))
+        })
+    }
+}
+
+impl StaticFile {
+    fn response(&self, cache_control: &HeaderValue, headers: &HeaderMap) -> Response {
+        let mut res = if self.is_not_modified(headers) {
+            StatusCode::NOT_MODIFIED.into_response()
+        } else {
+            self.ranged(headers)
+        };
+        let res_headers = res.headers_mut();
+        res_headers.insert(header::ETAG, self.etag.clone());
+        res_headers.insert(header::CACHE_CONTROL, cache_control.clone());
+        res_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        res
+    }
+
+    fn is_not_modified(&self, headers: &HeaderMap) -> bool {
+        let Some(value) = headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok())
+        else {
+            return false;
+        };
+        let etag = self.etag.to_str().unwrap_or_default();
+        value
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag)
+    }
+
+    // a single range is supported, other ranges get the whole file which is also valid
+    fn ranged(&self, headers: &HeaderMap) -> Response {
+        let len = self.body.len();
+        let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+        // the range is only for the version the client has
+        let if_range = headers
+            .get(header::IF_RANGE)
+            .filter(|v| **v != self.etag)
+            .is_none();
+        let range = match range.filter(|_| if_range).map(|r| parse_range(r, len)) {
+            Some(Ok(Some(range))) => range,
+            Some(Err(_)) => {
+                let content_range = format!("bytes */{len}");
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, content_range)],
+                )
+                    .into_response();
+            }
+            _ => {
+                let headers = [(header::CONTENT_TYPE, self.content_type.clone())];
+                return (headers, self.body.clone()).into_response();
+            }
+        };
+        let (start, end) = range;
+        let content_range = format!("bytes {start}-{end}/{len}");
+        let content_range = HeaderValue::from_str(&content_range).expect("valid header value");
+        let headers = [
+            (header::CONTENT_TYPE, self.content_type.clone()),
+            (header::CONTENT_RANGE, content_range),
+        ];
+        let body = self.body.slice(start..=end);
+        (StatusCode::PARTIAL_CONTENT, headers, body).into_response()
+    }
+}
+
+// `bytes=0-99`, `bytes=100-` or `bytes=-100`, the end is inclusive.
+// Ok(None) when the header can't be used, Err when the range is out of the file
+fn parse_range(value: &str, len: usize) -> Result<Option<(usize, usize)>> {
+    let Some(spec) = value.strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = match (start.parse::<usize>(), end.parse::<usize>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+    if start >= len {
+        bail!("range {value} is not satisfiable for {len} bytes");
+    }
+    Ok(Some((start, end)))
+}
+
+// reads the files of a static dir, links are followed as long as they stay in it
+struct Loader<'a> {
+    // the canonical path of the static dir
+    root: PathBuf,
+    files: &'a mut HashMap<String, StaticFile>,
+    // the total size of the files of all the static dirs
+    size: &'a mut u64,
+    // a dir linked from several places, or from itself, is only read once
+    visited: HashSet<PathBuf>,
+}
+
+impl Loader<'_> {
+    // dir is canonical, the keys are the paths as seen from the static dir
+    fn load_dir(&mut self, dir: &Path, prefix: &str) -> Result<()> {
+        if !self.visited.insert(dir.to_path_buf()) {
+            return Ok(());
+        }
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(|s| s.to_string()) else {
+                continue;
+            };
+            if name.starts_with('.') {
+                continue;
+            }
+            // a dangling link or a link out of the static dir is skipped
+            let Ok(path) = fs::canonicalize(entry.path()) else {
+                continue;
+            };
+            if !path.starts_with(&self.root) {
+                continue;
+            }
+            let metadata = fs::metadata(&path)?;
+            let key = format!("{prefix}{name}");
+            if metadata.is_dir() {
+                self.load_dir(&path, &format!("{key}/"))?;
+            } else if metadata.is_file() {
+                self.load_file(&path, metadata.len(), key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load_file(&mut self, path: &Path, len: u64, key: String) -> Result<()> {
+        *self.size += len;
+        if *self.size > MAX_STATIC_SIZE {
+            bail!("static files are larger than {MAX_STATIC_SIZE} bytes");
+        }
+        // the file may grow after the size check, never read more than it said
+        let mut body = Vec::with_capacity(len as usize);
+        File::open(path)
+            .and_then(|file| file.take(len).read_to_end(&mut body))
+            .map_err(|e| anyhow!("read static file {} failed: {e}", path.display()))?;
+        // by the name the file is served as, the target of a link may be named otherwise
+        let content_type = mime_guess::from_path(&key)
+            .first_raw()
+            .unwrap_or(DEFAULT_CONTENT_TYPE);
+        let mut etag = blake3::hash(&body).to_string();
+        etag.truncate(16);
+        let file = StaticFile {
+            body: body.into(),
+            content_type: HeaderValue::from_static(content_type),
+            etag: HeaderValue::from_str(&format!("\"{etag}\""))?,
+        };
+        self.files.insert(key, file);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, http::Request};
+
+    use super::*;
+
+    fn static_files(dir: &Path, max_age: Option<u64>) -> Result<StaticFiles> {
+        fs::create_dir_all(dir.join("js"))?;
+        fs::write(dir.join("index.html"), "<h1>hello</h1>")?;
+        fs::write(dir.join("js/app.js"), "console.log('0123456789')")?;
+        fs::write(dir.join(".env"), "SECRET=1")?;
+        let assets = match max_age {
+            Some(secs) => format!("{{ dir: {}, max_age_secs: {secs} }}", dir.display()),
+            None => dir.display().to_string(),
+        };
+        let yaml = format!("{{ /: {}, /assets/: {assets} }}", dir.display());
+        StaticFiles::try_new(&serde_yml::from_str(&yaml)?)
+    }
+
+    fn get(uri: &str, headers: &[(header::HeaderName, &str)]) -> Parts {
+        let mut req = Request::get(uri);
+        for (name, value) in headers {
+            req = req.header(name.clone(), *value);
+        }
+        req.body(()).unwrap().into_parts().0
+    }
+
+    async fn body(res: Response) -> Result<String> {
+        let body = to_bytes(res.into_body(), usize::MAX).await?;
+        Ok(String::from_utf8(body.to_vec())?)
+    }
+
+    #[tokio::test]
+    async fn static_files_should_serve_by_prefix() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let files = static_files(dir.path(), Some(3600))?;
+
+        let res = files.serve(&get("/", &[])).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "text/html");
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "no-cache");
+        assert_eq!(body(res).await?, "<h1>hello</h1>");
+
+        let res = files.serve(&get("/assets/js/app.js", &[])).unwrap();
+        let content_type = res.headers()[header::CONTENT_TYPE].to_str()?;
+        assert!(content_type.ends_with("javascript"));
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "public, max-age=3600");
+
+        // left to the js routes
+        assert!(files.serve(&get("/api/hello", &[])).is_none());
+        assert!(files.serve(&get("/assetsjs/app.js", &[])).is_none());
+        assert!(files.serve(&get("/.env", &[])).is_none());
+        let post = Request::post("/").body(()).unwrap().into_parts().0;
+        assert!(files.serve(&post).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn static_files_should_support_etag_and_range() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let files = static_files(dir.path(), None)?;
+        let res = files.serve(&get("/js/app.js", &[])).unwrap();
+        let etag = res.headers()[header::ETAG].to_str()?.to_string();
+
+        let res = files.serve(&get(
+            "/js/app.js",
+            &[(header::IF_NONE_MATCH, etag.as_str())],
+        ));
+        assert_eq!(res.unwrap().status(), StatusCode::NOT_MODIFIED);
+
+        let res = files.serve(&get("/js/app.js", &[(header::RANGE, "bytes=13-22")]));
+        let res = res.unwrap();
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes 13-22/25");
+        assert_eq!(body(res).await?, "0123456789");
+
+        let res = files.serve(&get("/js/app.js", &[(header::RANGE, "bytes=-2")]));
+        assert_eq!(body(res.unwrap()).await?, "')");
+        let res = files.serve(&get("/js/app.js", &[(header::RANGE, "bytes=100-")]));
+        assert_eq!(res.unwrap().status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        // the client has another version, send the whole file
+        let headers = [(header::RANGE, "bytes=0-1"), (header::IF_RANGE, "\"old\"")];
+        let res = files.serve(&get("/js/app.js", &headers)).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        assert!(StaticFiles::try_new(&serde_yml::from_str("{ assets: public }")?).is_err());
+        assert!(StaticFiles::try_new(&serde_yml::from_str("{ /: not-found }")?).is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn static_files_should_skip_links_out_of_dir_and_special_files() -> Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let outside = tempfile::tempdir()?;
+        fs::write(outside.path().join("secret.txt"), "secret")?;
+        let dir = tempfile::tempdir()?;
+        let files = dir.path().join("public");
+        fs::create_dir_all(files.join("js"))?;
+        fs::write(files.join("js/app.abc123.js"), "app")?;
+        symlink("js/app.abc123.js", files.join("app.js"))?;
+        symlink(outside.path().join("secret.txt"), files.join("secret.txt"))?;
+        symlink(outside.path(), files.join("outside"))?;
+        symlink("..", files.join("js/loop"))?;
+        symlink("missing.txt", files.join("dangling.txt"))?;
+        let fifo = std::process::Command::new("mkfifo")
+            .arg(files.join("fifo.txt"))
+            .status()?;
+        assert!(fifo.success());
+
+        let yaml = format!("{{ /: {} }}", files.display());
+        let files = StaticFiles::try_new(&serde_yml::from_str(&yaml)?)?;
+        let res = files.serve(&get("/app.js", &[])).unwrap();
+        let content_type = res.headers()[header::CONTENT_TYPE].to_str()?;
+        assert!(content_type.ends_with("javascript"));
+        assert_eq!(body(res).await?, "app");
+        assert!(files.serve(&get("/js/app.abc123.js", &[])).is_some());
+        for path in [
+            "/secret.txt",
+            "/outside/secret.txt",
+            "/dangling.txt",
+            "/fifo.txt",
+        ] {
+            assert!(files.serve(&get(path, &[])).is_none(), "{path} is served");
+        }
+        Ok(())
+    }
+}
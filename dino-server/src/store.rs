@@ -0,0 +1,304 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{code_hash, ProjectConfig};
+
+const BUNDLE_FILE: &str = "bundle.mjs";
+const CONFIG_FILE: &str = "config.yml";
+const META_FILE: &str = "meta.json";
+// the version currently served for the host
+const CURRENT_FILE: &str = "current";
+
+/// 本地目录保存每个 host 的部署历史，重启后从这里恢复所有 tenant：
+///
+/// ```text
+/// {dir}/{host}/current
+/// {dir}/{host}/{version}/bundle.mjs
+/// {dir}/{host}/{version}/config.yml
+/// {dir}/{host}/{version}/meta.json
+/// ```
+///
+/// 每个版本先写到临时目录再 rename，进程在写入过程中退出不会留下不完整的版本。
+/// 同一个 host 的写操作互斥，并发的 save 不会拿到同一个版本号
+#[derive(Debug)]
+pub struct DeploymentStore {
+    dir: PathBuf,
+    // number of versions kept per host, the current one is never pruned
+    keep: usize,
+    locks: DashMap<String, Arc<Mutex<()>>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeploymentRecord {
+    pub version: u64,
+    pub hash: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// a stored version, ready to be passed to `SwappableAppRouter`
+#[derive(Debug, Clone)]
+pub struct StoredDeployment {
+    pub record: DeploymentRecord,
+    pub code: String,
+    pub config: ProjectConfig,
+}
+
+impl DeploymentStore {
+    pub fn open(dir: impl Into<PathBuf>, keep: usize) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            keep: keep.max(1),
+            locks: DashMap::new(),
+        })
+    }
+
+    /// hosts that have a current version
+    pub fn hosts(&self) -> Result<Vec<String>> {
+        let mut hosts = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let host = entry.file_name().to_string_lossy().to_string();
+            if is_valid_host(&host) && entry.path().join(CURRENT_FILE).exists() {
+                hosts.push(host);
+            }
+        }
+        hosts.sort();
+        Ok(hosts)
+    }
+
+    /// save a new version of the host and make it the current one
+    pub fn save(&self, host: &str, code: &str, config: &str) -> Result<DeploymentRecord> {
+        let host_dir = self.host_dir(host)?;
+        let lock = self.lock(host);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        fs::create_dir_all(&host_dir)?;
+        let version = self.versions(host)?.last().map_or(1, |v| v + 1);
+        let record = DeploymentRecord {
+            version,
+            hash: code_hash(code),
+            created_at: Utc::now(),
+        };
+
+        let tmp = host_dir.join(format!(".tmp-{version}"));
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        fs::create_dir_all(&tmp)?;
+        fs::write(tmp.join(BUNDLE_FILE), code)?;
+        fs::write(tmp.join(CONFIG_FILE), config)?;
+        fs::write(tmp.join(META_FILE), serde_json::to_vec_pretty(&record)?)?;
+        fs::rename(&tmp, host_dir.join(version_dir(version)))?;
+
+        write_current(&host_dir, version)?;
+        self.prune(host, version)?;
+        Ok(record)
+    }
+
+    pub fn load(&self, host: &str, version: u64) -> Result<StoredDeployment> {
+        let dir = self.host_dir(host)?.join(version_dir(version));
+        if !dir.exists() {
+            bail!("version {version} of {host} is not found");
+        }
+        let record = serde_json::from_slice(&fs::read(dir.join(META_FILE))?)?;
+        let code = fs::read_to_string(dir.join(BUNDLE_FILE))?;
+        let config = ProjectConfig::load(dir.join(CONFIG_FILE))?;
+        Ok(StoredDeployment {
+            record,
+            code,
+            config,
+        })
+    }
+
+    pub fn load_current(&self, host: &str) -> Result<StoredDeployment> {
+        let version = self
+            .current(host)?
+            .ok_or_else(|| anyhow!("{host} has no deployment"))?;
+        self.load(host, version)
+    }
+
+    pub fn current(&self, host: &str) -> Result<Option<u64>> {
+        let path = self.host_dir(host)?.join(CURRENT_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let version = fs::read_to_string(path)?.trim().parse()?;
+        Ok(Some(version))
+    }
+
+    pub fn set_current(&self, host: &str, version: u64) -> Result<()> {
+        let host_dir = self.host_dir(host)?;
+        let lock = self.lock(host);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if !host_dir.join(version_dir(version)).exists() {
+            bail!("version {version} of {host} is not found");
+        }
+        write_current(&host_dir, version)
+    }
+
+    /// all kept versions of the host, the newest first
+    pub fn history(&self, host: &str) -> Result<Vec<DeploymentRecord>> {
+        let host_dir = self.host_dir(host)?;
+        let mut records = Vec::new();
+        for version in self.versions(host)?.into_iter().rev() {
+            let meta = host_dir.join(version_dir(version)).join(META_FILE);
+            records.push(serde_json::from_slice(&fs::read(meta)?)?);
+        }
+        Ok(records)
+    }
+
+    /// the newest version older than the current one
+    pub fn previous(&self, host: &str) -> Result<Option<u64>> {
+        let Some(current) = self.current(host)? else {
+            return Ok(None);
+        };
+        let versions = self.versions(host)?;
+        Ok(versions.into_iter().rev().find(|v| *v < current))
+    }
+
+    pub fn remove(&self, host: &str) -> Result<()> {
+        let host_dir = self.host_dir(host)?;
+        let lock = self.lock(host);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if host_dir.exists() {
+            fs::remove_dir_all(host_dir)?;
+        }
+        Ok(())
+    }
+
+    // the lock of a host lives as long as the store, there are only a few hosts
+    fn lock(&self, host: &str) -> Arc<Mutex<()>> {
+        self.locks.entry(host.to_string()).or_default().clone()
+    }
+
+    fn versions(&self, host: &str) -> Result<Vec<u64>> {
+        let host_dir = self.host_dir(host)?;
+        if !host_dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut versions: Vec<u64> = fs::read_dir(host_dir)?
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect();
+        versions.sort();
+        Ok(versions)
+    }
+
+    fn prune(&self, host: &str, current: u64) -> Result<()> {
+        let host_dir = self.host_dir(host)?;
+        let versions = self.versions(host)?;
+        let stale = versions.len().saturating_sub(self.keep);
+        for version in versions.into_iter().take(stale) {
+            if version != current {
+                fs::remove_dir_all(host_dir.join(version_dir(version)))?;
+            }
+        }
+        Ok(())
+    }
+
+    // the host comes from the admin api, it must not escape the store directory
+    fn host_dir(&self, host: &str) -> Result<PathBuf> {
+        if !is_valid_host(host) {
+            bail!("invalid host: {host:?}");
+        }
+        Ok(self.dir.join(host))
+    }
+}
+
+fn version_dir(version: u64) -> String {
+    // zero padded so that the directories are listed in order
+    format!("{version:08}")
+}
+
+// write then rename, the pointer is always either the old or the new version
+fn write_current(host_dir: &Path, version: u64) -> Result<()> {
+    let tmp = host_dir.join(format!(".{CURRENT_FILE}.tmp"));
+    fs::write(&tmp, version.to_string())?;
+    fs::rename(tmp, host_dir.join(CURRENT_FILE))?;
+    Ok(())
+}
+
+fn is_valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && !host.starts_with('.')
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '*'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = include_str!("../fixtures/config.yml");
+
+    #[test]
+    fn deployment_store_should_keep_history() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let store = DeploymentStore::open(dir.path(), 2)?;
+        assert!(store.hosts()?.is_empty());
+
+        let v1 = store.save("example.com", "v1", CONFIG)?;
+        let v2 = store.save("example.com", "v2", CONFIG)?;
+        let v3 = store.save("example.com", "v3", CONFIG)?;
+        assert_eq!((v1.version, v2.version, v3.version), (1, 2, 3));
+        assert_eq!(store.hosts()?, vec!["example.com"]);
+        assert_eq!(store.current("example.com")?, Some(3));
+
+        // only the last 2 versions are kept
+        let history = store.history("example.com")?;
+        assert_eq!(history, vec![v3.clone(), v2.clone()]);
+        assert!(store.load("example.com", 1).is_err());
+
+        let current = store.load_current("example.com")?;
+        assert_eq!(current.code, "v3");
+        assert_eq!(current.record.hash, v3.hash);
+        assert_eq!(current.config.name, "dino-test");
+
+        assert_eq!(store.previous("example.com")?, Some(2));
+        store.set_current("example.com", 2)?;
+        assert_eq!(store.load_current("example.com")?.code, "v2");
+        assert_eq!(store.previous("example.com")?, None);
+
+        store.remove("example.com")?;
+        assert!(store.hosts()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn deployment_store_should_save_concurrently() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let store = &DeploymentStore::open(dir.path(), 10)?;
+        let mut versions = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..8)
+                .map(|i| s.spawn(move || store.save("example.com", &format!("v{i}"), CONFIG)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| Ok(h.join().unwrap()?.version))
+                .collect::<Result<Vec<_>>>()
+        })?;
+        versions.sort();
+        assert_eq!(versions, (1..=8).collect::<Vec<_>>());
+        assert_eq!(store.history("example.com")?.len(), 8);
+        Ok(())
+    }
+
+    #[test]
+    fn deployment_store_should_reject_invalid_host() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let store = DeploymentStore::open(dir.path(), 2)?;
+        assert!(store.save("../etc", "v1", CONFIG).is_err());
+        assert!(store.save("a/b", "v1", CONFIG).is_err());
+        assert!(store.save("", "v1", CONFIG).is_err());
+        Ok(())
+    }
+}
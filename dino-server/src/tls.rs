@@ -0,0 +1,239 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use arc_swap::ArcSwap;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{
+    crypto::ring,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use tracing::{info, warn};
+
+use crate::host::{find_host, normalize_host};
+
+// certificates are usually renewed days before they expire, polling is good enough
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 在 dino 内终止 TLS：按 SNI 为每个 host 选择证书，证书文件更新后自动重新加载，
+/// 已经建立的连接不受影响，新的握手使用新的证书
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub certs: Vec<TlsCert>,
+}
+
+/// a certificate chain and its private key, both in PEM
+#[derive(Debug, Clone)]
+pub struct TlsCert {
+    // matched like the tenant hosts: `example.com`, `*.example.com` or `*` for the rest,
+    // `*` is also used when the client sends no SNI
+    pub host: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// picks the certificate by SNI, the certificates can be swapped at any time
+#[derive(Debug)]
+pub(crate) struct CertResolver {
+    certs: ArcSwap<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl TlsOptions {
+    pub fn new(certs: Vec<TlsCert>) -> Self {
+        Self { certs }
+    }
+
+    // the modification time of every file, a change of any of them triggers a reload
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.certs
+            .iter()
+            .flat_map(|c| [&c.cert, &c.key])
+            .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+impl TlsCert {
+    pub fn new(host: impl Into<String>, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        Self {
+            host: host.into(),
+            cert: cert.into(),
+            key: key.into(),
+        }
+    }
+
+    fn load(&self) -> Result<CertifiedKey> {
+        let certs = rustls_pemfile::certs(&mut open(&self.cert)?)
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("invalid certificate {}", self.cert.display()))?;
+        if certs.is_empty() {
+            bail!("no certificate found in {}", self.cert.display());
+        }
+        let key = rustls_pemfile::private_key(&mut open(&self.key)?)
+            .with_context(|| format!("invalid private key {}", self.key.display()))?
+            .ok_or_else(|| anyhow!("no private key found in {}", self.key.display()))?;
+        let key = ring::sign::any_supported_type(&key)
+            .with_context(|| format!("unsupported private key {}", self.key.display()))?;
+        Ok(CertifiedKey::new(certs, key))
+    }
+}
+
+impl CertResolver {
+    pub(crate) fn try_new(options: &TlsOptions) -> Result<Self> {
+        Ok(Self {
+            certs: ArcSwap::from_pointee(load_certs(options)?),
+        })
+    }
+
+    // all the certificates are replaced together, a broken file keeps the old ones
+    pub(crate) fn reload(&self, options: &TlsOptions) -> Result<()> {
+        self.certs.store(Arc::new(load_certs(options)?));
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.load();
+        let host = normalize_host(server_name.unwrap_or(crate::DEFAULT_HOST));
+        find_host(&host, |pattern| certs.get(pattern).cloned())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.get(client_hello.server_name())
+    }
+}
+
+/// build the rustls config of the listener, and keep its certificates in sync with the files
+pub(crate) fn tls_config(options: TlsOptions) -> Result<RustlsConfig> {
+    let resolver = Arc::new(CertResolver::try_new(&options)?);
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    tokio::spawn(watch_certs(options, resolver));
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+async fn watch_certs(options: TlsOptions, resolver: Arc<CertResolver>) {
+    let mut modified = options.modified();
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let current = options.modified();
+        if current == modified {
+            continue;
+        }
+        // a renewal may replace the cert and the key one after the other, a failed reload
+        // is retried on the next tick
+        match resolver.reload(&options) {
+            Ok(()) => {
+                info!("tls certificates reloaded");
+                modified = current;
+            }
+            Err(e) => warn!("reload tls certificates failed: {:#}", e),
+        }
+    }
+}
+
+fn load_certs(options: &TlsOptions) -> Result<HashMap<String, Arc<CertifiedKey>>> {
+    let mut certs = HashMap::new();
+    for cert in &options.certs {
+        let host = normalize_host(&cert.host);
+        if certs.insert(host, Arc::new(cert.load()?)).is_some() {
+            bail!("duplicate tls certificate for {}", cert.host);
+        }
+    }
+    if certs.is_empty() {
+        bail!("at least one tls certificate is required");
+    }
+    Ok(certs)
+}
+
+fn open(path: &Path) -> Result<BufReader<fs::File>> {
+    let file = fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // generate a self-signed certificate of the host into dir
+    fn self_signed(dir: &Path, host: &str, name: &str) -> Result<TlsCert> {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec![name.to_string()])?;
+        let cert_path = dir.join(format!("{name}.crt"));
+        let key_path = dir.join(format!("{name}.key"));
+        fs::write(&cert_path, cert.pem())?;
+        fs::write(&key_path, key_pair.serialize_pem())?;
+        Ok(TlsCert::new(host, cert_path, key_path))
+    }
+
+    fn cert_of(resolver: &CertResolver, name: Option<&str>) -> Option<Vec<u8>> {
+        resolver.get(name).map(|key| key.cert[0].to_vec())
+    }
+
+    #[test]
+    fn cert_resolver_should_select_by_sni() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = TlsOptions::new(vec![
+            self_signed(dir.path(), "example.com", "example.com")?,
+            self_signed(dir.path(), "*.example.com", "wildcard.example.com")?,
+            self_signed(dir.path(), "*", "localhost")?,
+        ]);
+        let resolver = CertResolver::try_new(&options)?;
+        let exact = cert_of(&resolver, Some("example.com"));
+        let wildcard = cert_of(&resolver, Some("api.example.com"));
+        let default = cert_of(&resolver, None);
+        assert!(exact.is_some() && wildcard.is_some() && default.is_some());
+        assert_ne!(exact, wildcard);
+        assert_eq!(cert_of(&resolver, Some("API.Example.com")), wildcard);
+        assert_eq!(cert_of(&resolver, Some("other.org")), default);
+
+        // without a `*` certificate, unknown names abort the handshake
+        let options = TlsOptions::new(options.certs[..2].to_vec());
+        let resolver = CertResolver::try_new(&options)?;
+        assert!(cert_of(&resolver, Some("other.org")).is_none());
+        assert!(cert_of(&resolver, None).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn cert_resolver_should_reload() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = TlsOptions::new(vec![self_signed(dir.path(), "*", "localhost")?]);
+        let resolver = CertResolver::try_new(&options)?;
+        let old = cert_of(&resolver, None);
+
+        // renewed in place
+        self_signed(dir.path(), "*", "localhost")?;
+        resolver.reload(&options)?;
+        let new = cert_of(&resolver, None);
+        assert_ne!(old, new);
+
+        // a broken file is rejected and the old certificate is kept
+        fs::write(&options.certs[0].key, "not a key")?;
+        assert!(resolver.reload(&options).is_err());
+        assert_eq!(cert_of(&resolver, None), new);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_config_should_reject_missing_files() {
+        let options = TlsOptions::new(vec![TlsCert::new("*", "/no/such.crt", "/no/such.key")]);
+        assert!(tls_config(options).is_err());
+        assert!(tls_config(TlsOptions::new(vec![])).is_err());
+    }
+}
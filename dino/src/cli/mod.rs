@@ -1,13 +1,17 @@
 mod build;
 mod init;
+mod routes;
 mod run;
+mod serve;
 
 use clap::Parser;
 use enum_dispatch::enum_dispatch;
 
 pub use build::BuildOpts;
 pub use init::InitOpts;
-pub use run::RunOpts;
+pub use routes::RoutesOpts;
+pub use run::{RunOpts, ServerArgs};
+pub use serve::ServeOpts;
 
 #[derive(Debug, Parser)]
 #[command(name = "dino", version, author, about, long_about = None)]
@@ -27,4 +31,13 @@ pub enum SubCommand {
 
     #[command(name = "run", about = "Run user's dino project")]
     Run(RunOpts),
+
+    #[command(name = "serve", about = "Serve all dino projects in a directory")]
+    Serve(ServeOpts),
+
+    #[command(
+        name = "routes",
+        about = "Show the routes of dino project with their middleware"
+    )]
+    Routes(RoutesOpts),
 }
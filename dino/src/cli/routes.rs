@@ -0,0 +1,78 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Parser;
+use dino_server::{ProjectConfig, RouteInfo};
+
+use crate::CmdExecutor;
+
+#[derive(Debug, Parser)]
+pub struct RoutesOpts {
+    // the project directory, defaults to the current one
+    #[arg(long, default_value = ".")]
+    pub dir: PathBuf,
+}
+
+impl CmdExecutor for RoutesOpts {
+    async fn execute(self) -> Result<()> {
+        let config = ProjectConfig::load(self.dir.join("config.yml"))?;
+        print!("{}", format_routes(&config.route_table()));
+        Ok(())
+    }
+}
+
+// one route per line, the columns are aligned
+fn format_routes(routes: &[RouteInfo]) -> String {
+    let mut rows = vec![[
+        "METHOD".to_string(),
+        "PATH".to_string(),
+        "HANDLER".to_string(),
+        "AUTH".to_string(),
+        "MIDDLEWARE".to_string(),
+    ]];
+    rows.extend(routes.iter().map(|r| {
+        let middleware = if r.middleware.is_empty() {
+            "-".to_string()
+        } else {
+            r.middleware.join(" -> ")
+        };
+        [
+            r.method.clone(),
+            r.path.clone(),
+            r.handler.clone(),
+            r.auth.clone().unwrap_or_else(|| "-".to_string()),
+            middleware,
+        ]
+    }));
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut out = String::new();
+    for [method, path, handler, auth, middleware] in rows {
+        let [w0, w1, w2, w3] = widths;
+        out.push_str(&format!(
+            "{method:w0$}  {path:w1$}  {handler:w2$}  {auth:w3$}  {middleware}\n"
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_routes_should_align_columns() -> Result<()> {
+        let config = ProjectConfig::load("fixtures/projects/api/config.yml")?;
+        assert_eq!(
+            format_routes(&config.route_table()),
+            "METHOD     PATH    HANDLER  AUTH     MIDDLEWARE\n\
+             GET        /hello  hello    api_key  log -> auth\n\
+             WEBSOCKET  /ws     chat     -        -\n"
+        );
+        Ok(())
+    }
+}
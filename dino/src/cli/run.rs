@@ -1,48 +1,206 @@
-use std::{fs, path::Path, time::Duration};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::Result;
-use clap::Parser;
-use dino_server::{start_server, ProjectConfig, SwappableAppRouter, TennetRouter};
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, Parser};
+use dino_server::{
+    set_dev_mode, start_server_with_shutdown, trigger_now, AdminOptions, DeploymentStore,
+    ProjectConfig, ServerOptions, SwappableAppRouter, TennetRouter, TlsCert, TlsOptions,
+    DEFAULT_HOST,
+};
 use notify::RecursiveMode;
 use notify_debouncer_mini::{new_debouncer, DebounceEventResult};
 use tokio::sync::mpsc::channel;
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tracing::{info, warn};
 
-use crate::{build_project, CmdExecutor};
+use crate::{build_project, CmdExecutor, BUILD_DIR, STATIC_DIR};
+
+const ADMIN_TOKEN_ENV: &str = "DINO_ADMIN_TOKEN";
+// versions kept per host in the deployment store
+const KEEP_DEPLOYMENTS: usize = 10;
 
 #[derive(Debug, Parser)]
 pub struct RunOpts {
+    #[command(flatten)]
+    pub server: ServerArgs,
+    // run the schedule of the given handler once and exit, instead of starting the server
+    #[arg(long)]
+    pub trigger: Option<String>,
+}
+
+/// options of the server shared by `dino run` and `dino serve`
+#[derive(Debug, Args)]
+pub struct ServerArgs {
     // port to listen
     #[arg(short, long, default_value = "3000")]
     pub port: u16,
+    // serve the admin api on this port, the token is read from DINO_ADMIN_TOKEN
+    #[arg(long)]
+    pub admin_port: Option<u16>,
+    // persist the deployments of the admin api, they are restored on restart
+    #[arg(long, requires = "admin_port")]
+    pub deploy_dir: Option<PathBuf>,
+    // also route `/t/{tenant}/...` to the tenant, when the host can't be used
+    #[arg(long)]
+    pub path_prefix: bool,
+    // serve https with this certificate (PEM), used for the hosts without their own one
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    // the certificate of a host picked by SNI, `<host>=<cert>,<key>`, can be repeated
+    #[arg(long, value_parser = parse_sni_cert)]
+    pub tls_sni: Vec<TlsCert>,
+    // seconds to wait for the in-flight requests on ctrl-c or SIGTERM
+    #[arg(long, default_value = "30")]
+    pub shutdown_timeout: u64,
+    // serve prometheus metrics at /metrics on this port, also on the admin api
+    #[arg(long)]
+    pub metrics_port: Option<u16>,
 }
 
 impl CmdExecutor for RunOpts {
     async fn execute(self) -> Result<()> {
-        let (code, config) = get_code_and_config()?;
-        let router = SwappableAppRouter::try_new(&code, config.routes)?;
+        // running locally, return the stack of js exceptions to the client
+        set_dev_mode(true);
+        let (code, config) = get_code_and_config(Path::new("."))?;
+        let router = SwappableAppRouter::try_new(&code, config)?;
+        if let Some(handler) = self.trigger {
+            return trigger_schedule(&router, &handler).await;
+        }
         let routers = vec![TennetRouter::new("localhost".to_string(), router.clone())];
+        let options = self.server.into_options()?;
 
-        tokio::spawn(async_watch(".", router));
+        tokio::spawn(async_watch(PathBuf::from("."), router));
 
-        start_server(self.port, routers).await?;
+        start_server_with_shutdown(options, routers, shutdown_signal()).await?;
 
         Ok(())
     }
 }
 
-fn get_code_and_config() -> Result<(String, ProjectConfig)> {
-    let filename = build_project(".")?;
+impl ServerArgs {
+    pub fn into_options(self) -> Result<ServerOptions> {
+        let admin = match self.admin_port {
+            Some(port) => {
+                let token = std::env::var(ADMIN_TOKEN_ENV)
+                    .map_err(|_| anyhow!("{ADMIN_TOKEN_ENV} is required by --admin-port"))?;
+                Some(AdminOptions { port, token })
+            }
+            None => None,
+        };
+        let store = match self.deploy_dir {
+            Some(dir) => Some(Arc::new(DeploymentStore::open(dir, KEEP_DEPLOYMENTS)?)),
+            None => None,
+        };
+        let mut certs = self.tls_sni;
+        if let (Some(cert), Some(key)) = (self.tls_cert, self.tls_key) {
+            certs.push(TlsCert::new(DEFAULT_HOST, cert, key));
+        }
+        let tls = (!certs.is_empty()).then(|| TlsOptions::new(certs));
+        let options = ServerOptions::builder()
+            .port(self.port)
+            .admin(admin)
+            .store(store)
+            .path_prefix(self.path_prefix)
+            .tls(tls)
+            .shutdown_timeout(Duration::from_secs(self.shutdown_timeout))
+            .metrics_port(self.metrics_port)
+            .build();
+        Ok(options)
+    }
+}
+
+// resolves on ctrl-c, or SIGTERM sent by docker / kubernetes / systemd
+pub(crate) async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("listen for ctrl-c failed: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(e) => {
+                warn!("listen for SIGTERM failed: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("received ctrl-c, shutting down"),
+        _ = terminate => info!("received SIGTERM, shutting down"),
+    }
+}
+
+fn parse_sni_cert(s: &str) -> Result<TlsCert> {
+    let (host, files) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expect <host>=<cert>,<key>, got {s}"))?;
+    let (cert, key) = files
+        .split_once(',')
+        .ok_or_else(|| anyhow!("expect <host>=<cert>,<key>, got {s}"))?;
+    Ok(TlsCert::new(host, cert, key))
+}
+
+async fn trigger_schedule(router: &SwappableAppRouter, handler: &str) -> Result<()> {
+    let res = trigger_now(&router.load(), handler).await?;
+    let status = res.status();
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+    println!("{status}\n{}", String::from_utf8_lossy(&body));
+    if !status.is_success() {
+        bail!("schedule {handler} failed with status {status}");
+    }
+    Ok(())
+}
+
+// build the project in dir and load the artifacts
+pub(crate) fn get_code_and_config(dir: &Path) -> Result<(String, ProjectConfig)> {
+    let filename = build_project(&dir.display().to_string())?;
     let config = filename.replace(".mjs", ".yml");
     let code = fs::read_to_string(filename)?;
-    let config = ProjectConfig::load(config)?;
+    // the static files are served from the build, not the sources being edited
+    let config = ProjectConfig::load(config)?
+        .with_static_root(Path::new(BUILD_DIR).join(STATIC_DIR))
+        .with_base_dir(dir);
     Ok((code, config))
 }
 
+// a file in the static dirs of the project, the dirs are read from config.yml again
+// as they may have been changed together
+fn is_static_file(dir: &Path, path: &Path) -> bool {
+    let Ok(config) = ProjectConfig::load(dir.join("config.yml")) else {
+        return false;
+    };
+    // a removed file has no canonical path, its dir usually still has one
+    let Some(parent) = path.parent().and_then(|p| p.canonicalize().ok()) else {
+        return false;
+    };
+    config
+        .with_base_dir(dir)
+        .static_files
+        .values()
+        .filter_map(|s| s.dir().canonicalize().ok())
+        .any(|static_dir| parent.starts_with(static_dir))
+}
+
 const MONITOR_FS_INTERVAL: Duration = Duration::from_secs(2);
 
-async fn async_watch(p: impl AsRef<Path>, router: SwappableAppRouter) -> Result<()> {
+// rebuild the project in dir on changes and swap the router
+pub(crate) async fn async_watch(dir: PathBuf, router: SwappableAppRouter) -> Result<()> {
     let (tx, rx) = channel(1);
 
     // Select recommended watcher for debouncer.
@@ -55,27 +213,34 @@ async fn async_watch(p: impl AsRef<Path>, router: SwappableAppRouter) -> Result<
 
     // Add a path to be watched. All files and directories at that path and
     // below will be monitored for changes.
-    debouncer
-        .watcher()
-        .watch(p.as_ref(), RecursiveMode::Recursive)?;
+    debouncer.watcher().watch(&dir, RecursiveMode::Recursive)?;
     let mut stream = ReceiverStream::new(rx);
     while let Some(ret) = stream.next().await {
         match ret {
             Ok(events) => {
                 let mut need_swap = false;
-                // config.yml change, or any ".ts" / ".js" file change
+                // config.yml or secrets change, any ".ts" / ".js" file or static file change
                 for event in events {
                     let path = event.path;
+                    // written by the build itself
+                    if path.components().any(|c| c.as_os_str() == BUILD_DIR) {
+                        continue;
+                    }
                     let ext = path.extension().unwrap_or_default();
-                    if path.ends_with("config.yml") || ext == "ts" || ext == "js" {
+                    let is_config = path.ends_with("config.yml") || path.ends_with(".secrets.yml");
+                    if is_config || ext == "ts" || ext == "js" || is_static_file(&dir, &path) {
                         info!("File changed: {}", path.display());
                         need_swap = true;
                         break;
                     }
                 }
+                // a broken change keeps the running version, fix it and save again
                 if need_swap {
-                    let (code, config) = get_code_and_config()?;
-                    router.swap(code, config.routes)?;
+                    let ret = get_code_and_config(&dir)
+                        .and_then(|(code, config)| router.swap(code, config));
+                    if let Err(e) = ret {
+                        warn!("Reload {} failed: {:#}", dir.display(), e);
+                    }
                 }
             }
             Err(e) => {
@@ -0,0 +1,86 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use dino_server::{start_server_with_shutdown, SwappableAppRouter, TennetRouter};
+use tracing::info;
+
+use super::run::{async_watch, get_code_and_config, shutdown_signal, ServerArgs};
+use crate::CmdExecutor;
+
+#[derive(Debug, Parser)]
+pub struct ServeOpts {
+    // every sub-directory with a config.yml is a project
+    #[arg(long)]
+    pub projects: PathBuf,
+    #[command(flatten)]
+    pub server: ServerArgs,
+}
+
+impl CmdExecutor for ServeOpts {
+    async fn execute(self) -> Result<()> {
+        let mut routers = Vec::new();
+        let mut hosts = HashSet::new();
+        for dir in discover_projects(&self.projects)? {
+            let (code, config) = get_code_and_config(&dir)?;
+            // without a host in config.yml the project is served at `{name}.localhost`
+            let host = config
+                .host
+                .clone()
+                .unwrap_or_else(|| format!("{}.localhost", config.name));
+            if !hosts.insert(host.clone()) {
+                bail!(
+                    "host {host} of {} is used by another project",
+                    dir.display()
+                );
+            }
+            info!("Serving {} at {}", dir.display(), host);
+            let router = SwappableAppRouter::try_new(code, config)?;
+            routers.push(TennetRouter::new(host, router.clone()));
+            tokio::spawn(async_watch(dir, router));
+        }
+        if routers.is_empty() {
+            bail!("no project found in {}", self.projects.display());
+        }
+
+        let options = self.server.into_options()?;
+        start_server_with_shutdown(options, routers, shutdown_signal()).await?;
+
+        Ok(())
+    }
+}
+
+// sub-directories containing a config.yml, in name order
+fn discover_projects(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut projects = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && path.join("config.yml").is_file() {
+            projects.push(path);
+        }
+    }
+    projects.sort();
+    Ok(projects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discover_projects_should_work() -> Result<()> {
+        let projects = discover_projects(Path::new("fixtures/projects"))?;
+        assert_eq!(
+            projects,
+            [
+                PathBuf::from("fixtures/projects/api"),
+                PathBuf::from("fixtures/projects/blog"),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -6,6 +6,8 @@ use enum_dispatch::enum_dispatch;
 pub(crate) use utils::*;
 
 pub const BUILD_DIR: &str = ".build";
+// the static dirs of the project are copied here in the build dir
+pub const STATIC_DIR: &str = "static";
 
 #[allow(async_fn_in_trait)]
 #[enum_dispatch]
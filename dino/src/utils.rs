@@ -2,70 +2,135 @@ use std::{
     collections::BTreeSet,
     fs::{self, File},
     io::{self},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use bundler::run_bundle;
-use glob::{glob, GlobError};
+use dino_server::ProjectConfig;
+use glob::{glob, glob_with, GlobError, MatchOptions};
 
-use crate::BUILD_DIR;
+use crate::{BUILD_DIR, STATIC_DIR};
 
-// get all files with certain extension in a directory
+// get all files with certain extension in a directory, the build output is not included
 pub(crate) fn get_files_with_exts(dir: &str, exts: &[&str]) -> Result<BTreeSet<PathBuf>> {
     let mut files = BTreeSet::new();
     for ext in exts {
         let rule = format!("{}/**/*.{}", dir, ext);
         let paths = glob(&rule)?.collect::<Result<BTreeSet<PathBuf>, GlobError>>()?;
-        files.extend(paths);
+        files.extend(paths.into_iter().filter(|p| !is_build_output(dir, p)));
     }
     Ok(files)
 }
 
 pub(crate) fn calc_project_hash(dir: &str) -> Result<String> {
-    let hash = calc_hash_for_files(dir, &["ts", "js", "json", "yml"], 16)?;
-    Ok(hash)
+    let files = get_files_with_exts(dir, &["ts", "js", "json", "yml"])?;
+    let mut hasher = blake3::Hasher::new();
+    hash_files(&mut hasher, files)?;
+    // static files are served by their path, a renamed file is a change too
+    for (file, path) in get_static_files(dir)? {
+        hasher.update(path.to_string_lossy().as_bytes());
+        hasher.update_reader(File::open(file)?)?;
+    }
+    let mut ret = hasher.finalize().to_string();
+    ret.truncate(16);
+    Ok(ret)
 }
 
 pub(crate) fn calc_hash_for_files(dir: &str, exts: &[&str], expect_len: usize) -> Result<String> {
     let files = get_files_with_exts(dir, exts)?;
     let mut hasher = blake3::Hasher::new();
-    for file in files {
-        hasher.update_reader(File::open(file)?)?;
-    }
+    hash_files(&mut hasher, files)?;
     let mut ret = hasher.finalize().to_string();
     ret.truncate(expect_len);
     Ok(ret)
 }
 
+fn hash_files(hasher: &mut blake3::Hasher, files: BTreeSet<PathBuf>) -> Result<()> {
+    for file in files {
+        hasher.update_reader(File::open(file)?)?;
+    }
+    Ok(())
+}
+
+// files of the `static` dirs in config.yml, with their path relative to the project.
+// dot files are skipped, the server doesn't serve them either
+pub(crate) fn get_static_files(dir: &str) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let config = ProjectConfig::load(Path::new(dir).join("config.yml"))?;
+    let options = MatchOptions {
+        require_literal_leading_dot: true,
+        ..Default::default()
+    };
+    let mut files = Vec::new();
+    for static_dir in config.static_files.values() {
+        let static_dir = static_dir.dir();
+        // absolute dirs are read in place by the server
+        if static_dir.is_absolute() {
+            continue;
+        }
+        if static_dir.components().any(|c| c == Component::ParentDir) {
+            bail!("static dir {} is outside the project", static_dir.display());
+        }
+        let rule = format!("{}/{}/**/*", dir, static_dir.display());
+        for file in glob_with(&rule, options)? {
+            let file = file?;
+            if file.is_file() {
+                let path = file.strip_prefix(dir)?.to_path_buf();
+                files.push((file, path));
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn is_build_output(dir: &str, path: &Path) -> bool {
+    path.strip_prefix(dir)
+        .is_ok_and(|p| p.starts_with(BUILD_DIR))
+}
+
+// build the project in `dir`, the artifacts are written into `{dir}/.build`
 pub(crate) fn build_project(dir: &str) -> Result<String> {
-    fs::create_dir_all(BUILD_DIR)?;
+    let build_dir = Path::new(dir).join(BUILD_DIR);
+    fs::create_dir_all(&build_dir)?;
     let hash = calc_project_hash(dir)?;
     // 注意生成的文件使用.mjs 目的是为了避免与.js文件 会被拿去build，导致生成的文件也会被拿去build
-    let filename = format!("{}/{}.mjs", BUILD_DIR, hash);
-    let config = format!("{}/{}.yml", BUILD_DIR, hash);
+    let filename = build_dir.join(format!("{}.mjs", hash));
+    let config = build_dir.join(format!("{}.yml", hash));
 
     // if the file already exists, skip building
-    let dst = Path::new(&filename);
-    if dst.exists() {
-        return Ok(filename);
+    if filename.exists() {
+        return Ok(filename.display().to_string());
     }
 
-    remove_dir_contents(BUILD_DIR)?;
+    remove_dir_contents(&build_dir)?;
 
     // build the project
-    let content = run_bundle("main.ts", &Default::default())?;
-    fs::write(dst, content)?;
+    let entry = Path::new(dir).join("main.ts");
+    let content = run_bundle(&entry.display().to_string(), &Default::default())?;
+    fs::write(&filename, content)?;
     let mut dst = File::create(&config)?;
-    let mut src = File::open("config.yml")?;
+    let mut src = File::open(Path::new(dir).join("config.yml"))?;
     io::copy(&mut src, &mut dst)?;
-    Ok(filename)
+    // the static dirs keep their path relative to the project
+    for (file, path) in get_static_files(dir)? {
+        let target = build_dir.join(STATIC_DIR).join(path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(file, target)?;
+    }
+    Ok(filename.display().to_string())
 }
 
 // https://stackoverflow.com/questions/65573245/
 fn remove_dir_contents<P: AsRef<Path>>(path: P) -> io::Result<()> {
     for entry in fs::read_dir(path)? {
-        fs::remove_file(entry?.path())?;
+        let path = entry?.path();
+        if path.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
     }
     Ok(())
 }
@@ -96,4 +161,24 @@ mod tests {
         assert_eq!(hash, "af1349b9");
         Ok(())
     }
+
+    #[test]
+    fn get_static_files_should_skip_dot_files() -> Result<()> {
+        let mut files = get_static_files("fixtures/projects/blog")?;
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                (
+                    PathBuf::from("fixtures/projects/blog/public/css/site.css"),
+                    PathBuf::from("public/css/site.css")
+                ),
+                (
+                    PathBuf::from("fixtures/projects/blog/public/index.html"),
+                    PathBuf::from("public/index.html")
+                ),
+            ]
+        );
+        Ok(())
+    }
 }